@@ -0,0 +1,143 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use super::KrunBackend;
+
+use std::{
+    ffi::{c_char, c_int, CStr},
+    ptr,
+};
+
+// Test builds use the mock backend and must not require libkrun to be installed.
+#[cfg_attr(not(test), link(name = "krun-efi"))]
+extern "C" {
+    fn krun_create_ctx() -> i32;
+    fn krun_set_vm_config(ctx_id: u32, num_vcpus: u8, ram_mib: u32) -> i32;
+    fn krun_set_kernel(
+        ctx_id: u32,
+        c_kernel_path: *const c_char,
+        kernel_format: u32,
+        c_initramfs: *const c_char,
+        c_cmdline: *const c_char,
+    ) -> i32;
+    fn krun_add_disk3(
+        ctx_id: u32,
+        c_block_id: *const c_char,
+        c_disk_path: *const c_char,
+        disk_format: u32,
+        read_only: bool,
+        direct_io: bool,
+        sync_mode: u32,
+    ) -> i32;
+    fn krun_add_vsock_port2(ctx_id: u32, port: u32, c_filepath: *const c_char, listen: bool)
+        -> i32;
+    fn krun_set_console_output(ctx_id: u32, c_filepath: *const c_char) -> i32;
+    fn krun_disable_implicit_console(ctx_id: u32) -> i32;
+    fn krun_add_virtio_console_default(
+        ctx_id: u32,
+        input_fd: c_int,
+        output_fd: c_int,
+        err_fd: c_int,
+    ) -> i32;
+    fn krun_add_virtiofs(ctx_id: u32, c_tag: *const c_char, c_path: *const c_char) -> i32;
+    fn krun_set_gvproxy_path(ctx_id: u32, c_path: *const c_char) -> i32;
+    fn krun_set_net_mac(ctx_id: u32, c_mac: *const u8) -> i32;
+    fn krun_get_shutdown_eventfd(ctx_id: u32) -> i32;
+    fn krun_start_enter(ctx_id: u32) -> i32;
+}
+
+/// The libkrun library linked into krunkit.
+pub struct Libkrun;
+
+impl KrunBackend for Libkrun {
+    fn create_ctx(&self) -> i32 {
+        unsafe { krun_create_ctx() }
+    }
+
+    fn set_vm_config(&self, ctx_id: u32, num_vcpus: u8, ram_mib: u32) -> i32 {
+        unsafe { krun_set_vm_config(ctx_id, num_vcpus, ram_mib) }
+    }
+
+    fn set_kernel(
+        &self,
+        ctx_id: u32,
+        kernel_path: &CStr,
+        kernel_format: u32,
+        initramfs: Option<&CStr>,
+        cmdline: Option<&CStr>,
+    ) -> i32 {
+        unsafe {
+            krun_set_kernel(
+                ctx_id,
+                kernel_path.as_ptr(),
+                kernel_format,
+                initramfs.map_or(ptr::null(), CStr::as_ptr),
+                cmdline.map_or(ptr::null(), CStr::as_ptr),
+            )
+        }
+    }
+
+    fn add_disk3(
+        &self,
+        ctx_id: u32,
+        block_id: &CStr,
+        disk_path: &CStr,
+        disk_format: u32,
+        read_only: bool,
+        direct_io: bool,
+        sync_mode: u32,
+    ) -> i32 {
+        unsafe {
+            krun_add_disk3(
+                ctx_id,
+                block_id.as_ptr(),
+                disk_path.as_ptr(),
+                disk_format,
+                read_only,
+                direct_io,
+                sync_mode,
+            )
+        }
+    }
+
+    fn add_vsock_port2(&self, ctx_id: u32, port: u32, filepath: &CStr, listen: bool) -> i32 {
+        unsafe { krun_add_vsock_port2(ctx_id, port, filepath.as_ptr(), listen) }
+    }
+
+    fn set_console_output(&self, ctx_id: u32, filepath: &CStr) -> i32 {
+        unsafe { krun_set_console_output(ctx_id, filepath.as_ptr()) }
+    }
+
+    fn disable_implicit_console(&self, ctx_id: u32) -> i32 {
+        unsafe { krun_disable_implicit_console(ctx_id) }
+    }
+
+    fn add_virtio_console_default(
+        &self,
+        ctx_id: u32,
+        input_fd: i32,
+        output_fd: i32,
+        err_fd: i32,
+    ) -> i32 {
+        unsafe { krun_add_virtio_console_default(ctx_id, input_fd, output_fd, err_fd) }
+    }
+
+    fn add_virtiofs(&self, ctx_id: u32, tag: &CStr, path: &CStr) -> i32 {
+        unsafe { krun_add_virtiofs(ctx_id, tag.as_ptr(), path.as_ptr()) }
+    }
+
+    fn set_gvproxy_path(&self, ctx_id: u32, path: &CStr) -> i32 {
+        unsafe { krun_set_gvproxy_path(ctx_id, path.as_ptr()) }
+    }
+
+    fn set_net_mac(&self, ctx_id: u32, mac: &[u8; 6]) -> i32 {
+        unsafe { krun_set_net_mac(ctx_id, mac.as_ptr()) }
+    }
+
+    fn get_shutdown_eventfd(&self, ctx_id: u32) -> i32 {
+        unsafe { krun_get_shutdown_eventfd(ctx_id) }
+    }
+
+    fn start_enter(&self, ctx_id: u32) -> i32 {
+        unsafe { krun_start_enter(ctx_id) }
+    }
+}
@@ -0,0 +1,158 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use super::KrunBackend;
+
+use std::{ffi::CStr, sync::Mutex};
+
+/// A libkrun call recorded by the mock backend.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Call {
+    CreateCtx,
+    SetVmConfig(u32, u8, u32),
+    SetKernel(u32, String, u32, Option<String>, Option<String>),
+    AddDisk3(u32, String, String, u32, bool, bool, u32),
+    AddVsockPort2(u32, u32, String, bool),
+    SetConsoleOutput(u32, String),
+    DisableImplicitConsole(u32),
+    AddVirtioConsoleDefault(u32, i32, i32, i32),
+    AddVirtiofs(u32, String, String),
+    SetGvproxyPath(u32, String),
+    SetNetMac(u32, [u8; 6]),
+    GetShutdownEventfd(u32),
+    StartEnter(u32),
+}
+
+/// A backend recording every libkrun call made, in order, without configuring a VM.
+#[derive(Debug, Default)]
+pub struct MockBackend {
+    calls: Mutex<Vec<Call>>,
+
+    /// Name of a call (as in Call's Debug output, e.g. "AddDisk3") to report as failed.
+    fail: Option<&'static str>,
+}
+
+impl MockBackend {
+    /// Create a mock backend that fails the calls of the given name.
+    pub fn failing(name: &'static str) -> Self {
+        Self {
+            calls: Mutex::new(Vec::new()),
+            fail: Some(name),
+        }
+    }
+
+    /// All calls recorded so far.
+    pub fn calls(&self) -> Vec<Call> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    fn record(&self, call: Call) -> i32 {
+        let failed = self
+            .fail
+            .map(|name| format!("{:?}", call).starts_with(name))
+            .unwrap_or(false);
+
+        self.calls.lock().unwrap().push(call);
+
+        if failed {
+            -1
+        } else {
+            0
+        }
+    }
+}
+
+fn string(s: &CStr) -> String {
+    s.to_string_lossy().into_owned()
+}
+
+impl KrunBackend for MockBackend {
+    fn create_ctx(&self) -> i32 {
+        self.record(Call::CreateCtx)
+    }
+
+    fn set_vm_config(&self, ctx_id: u32, num_vcpus: u8, ram_mib: u32) -> i32 {
+        self.record(Call::SetVmConfig(ctx_id, num_vcpus, ram_mib))
+    }
+
+    fn set_kernel(
+        &self,
+        ctx_id: u32,
+        kernel_path: &CStr,
+        kernel_format: u32,
+        initramfs: Option<&CStr>,
+        cmdline: Option<&CStr>,
+    ) -> i32 {
+        self.record(Call::SetKernel(
+            ctx_id,
+            string(kernel_path),
+            kernel_format,
+            initramfs.map(string),
+            cmdline.map(string),
+        ))
+    }
+
+    fn add_disk3(
+        &self,
+        ctx_id: u32,
+        block_id: &CStr,
+        disk_path: &CStr,
+        disk_format: u32,
+        read_only: bool,
+        direct_io: bool,
+        sync_mode: u32,
+    ) -> i32 {
+        self.record(Call::AddDisk3(
+            ctx_id,
+            string(block_id),
+            string(disk_path),
+            disk_format,
+            read_only,
+            direct_io,
+            sync_mode,
+        ))
+    }
+
+    fn add_vsock_port2(&self, ctx_id: u32, port: u32, filepath: &CStr, listen: bool) -> i32 {
+        self.record(Call::AddVsockPort2(ctx_id, port, string(filepath), listen))
+    }
+
+    fn set_console_output(&self, ctx_id: u32, filepath: &CStr) -> i32 {
+        self.record(Call::SetConsoleOutput(ctx_id, string(filepath)))
+    }
+
+    fn disable_implicit_console(&self, ctx_id: u32) -> i32 {
+        self.record(Call::DisableImplicitConsole(ctx_id))
+    }
+
+    fn add_virtio_console_default(
+        &self,
+        ctx_id: u32,
+        input_fd: i32,
+        output_fd: i32,
+        err_fd: i32,
+    ) -> i32 {
+        self.record(Call::AddVirtioConsoleDefault(
+            ctx_id, input_fd, output_fd, err_fd,
+        ))
+    }
+
+    fn add_virtiofs(&self, ctx_id: u32, tag: &CStr, path: &CStr) -> i32 {
+        self.record(Call::AddVirtiofs(ctx_id, string(tag), string(path)))
+    }
+
+    fn set_gvproxy_path(&self, ctx_id: u32, path: &CStr) -> i32 {
+        self.record(Call::SetGvproxyPath(ctx_id, string(path)))
+    }
+
+    fn set_net_mac(&self, ctx_id: u32, mac: &[u8; 6]) -> i32 {
+        self.record(Call::SetNetMac(ctx_id, *mac))
+    }
+
+    fn get_shutdown_eventfd(&self, ctx_id: u32) -> i32 {
+        self.record(Call::GetShutdownEventfd(ctx_id))
+    }
+
+    fn start_enter(&self, ctx_id: u32) -> i32 {
+        self.record(Call::StartEnter(ctx_id))
+    }
+}
@@ -0,0 +1,50 @@
+// SPDX-License-Identifier: Apache-2.0
+
+mod libkrun;
+#[cfg(test)]
+pub mod mock;
+
+pub use libkrun::Libkrun;
+
+use std::ffi::CStr;
+
+/// Interface to the libkrun API. Each method maps to the libkrun function of the same name (with
+/// the "krun_" prefix removed) and returns its raw result, a negative value indicating an error.
+pub trait KrunBackend: Send + Sync {
+    fn create_ctx(&self) -> i32;
+    fn set_vm_config(&self, ctx_id: u32, num_vcpus: u8, ram_mib: u32) -> i32;
+    fn set_kernel(
+        &self,
+        ctx_id: u32,
+        kernel_path: &CStr,
+        kernel_format: u32,
+        initramfs: Option<&CStr>,
+        cmdline: Option<&CStr>,
+    ) -> i32;
+    #[allow(clippy::too_many_arguments)]
+    fn add_disk3(
+        &self,
+        ctx_id: u32,
+        block_id: &CStr,
+        disk_path: &CStr,
+        disk_format: u32,
+        read_only: bool,
+        direct_io: bool,
+        sync_mode: u32,
+    ) -> i32;
+    fn add_vsock_port2(&self, ctx_id: u32, port: u32, filepath: &CStr, listen: bool) -> i32;
+    fn set_console_output(&self, ctx_id: u32, filepath: &CStr) -> i32;
+    fn disable_implicit_console(&self, ctx_id: u32) -> i32;
+    fn add_virtio_console_default(
+        &self,
+        ctx_id: u32,
+        input_fd: i32,
+        output_fd: i32,
+        err_fd: i32,
+    ) -> i32;
+    fn add_virtiofs(&self, ctx_id: u32, tag: &CStr, path: &CStr) -> i32;
+    fn set_gvproxy_path(&self, ctx_id: u32, path: &CStr) -> i32;
+    fn set_net_mac(&self, ctx_id: u32, mac: &[u8; 6]) -> i32;
+    fn get_shutdown_eventfd(&self, ctx_id: u32) -> i32;
+    fn start_enter(&self, ctx_id: u32) -> i32;
+}
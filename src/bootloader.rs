@@ -0,0 +1,699 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    backend::KrunBackend,
+    cmdline::{args_parse, val_parse},
+    fat::FatFs,
+    image::Image,
+    lock::{FileLock, LockMode},
+    partition::PartitionTable,
+    secureboot,
+    varstore::VariableStore,
+    virtio::{path_to_cstring, KrunContextSet},
+};
+
+use std::{
+    ffi::CString,
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Bootloader configuration, identified by its firmware.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "fw", rename_all = "lowercase")]
+pub enum Config {
+    Efi(EfiConfig),
+    Linux(LinuxConfig),
+}
+
+impl FromStr for Config {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let args = args_parse(s.to_string(), "bootloader", None)?;
+
+        // The first argument is the bootloader firmware. Subsequent arguments are
+        // firmware-specific.
+        let rest = args[1..].join(",");
+
+        match BootloaderFw::from_str(&args[0])? {
+            BootloaderFw::Efi => Ok(Self::Efi(EfiConfig::from_str(&rest)?)),
+            BootloaderFw::Linux => Ok(Self::Linux(LinuxConfig::from_str(&rest)?)),
+        }
+    }
+}
+
+impl Config {
+    /// Reject Secure Boot keys: libkrun-efi's firmware never reads the variable store, so the VM
+    /// would boot without Secure Boot being enforced.
+    pub fn check_secure_boot(&self) -> Result<()> {
+        match self {
+            Self::Efi(EfiConfig {
+                secure_boot: Some(keys_dir),
+                ..
+            }) => Err(anyhow!(
+                "Secure Boot keys from {} cannot be enforced: libkrun-efi's firmware does not read the variable store, so the VM would boot without Secure Boot",
+                keys_dir.display()
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Verify that the firmware will find something to boot on the root disk, so that an
+    /// unbootable disk is reported instead of leaving the VM stuck in the firmware.
+    pub fn check_root_disk(&self, disk: Option<&Image>) -> Result<()> {
+        match (self, disk) {
+            (Self::Efi(efi), Some(disk)) => efi.check_root_disk(disk),
+            _ => Ok(()),
+        }
+    }
+
+    /// Lock the EFI variable store, which the firmware writes to, if it exists.
+    pub fn lock(&self) -> Result<Option<FileLock>> {
+        match self {
+            Self::Efi(efi) if efi.vstore.exists() => Ok(Some(FileLock::new(
+                &efi.vstore,
+                LockMode::Exclusive,
+                "variable store",
+            )?)),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Configure the bootloader in the krun context.
+impl KrunContextSet for Config {
+    fn krun_ctx_set(&self, backend: &dyn KrunBackend, id: u32) -> Result<(), anyhow::Error> {
+        match self {
+            Self::Linux(linux) => linux.krun_ctx_set(backend, id),
+
+            // The EFI firmware is built into libkrun-efi, which has no API to hand it a variable
+            // store: the store is prepared on the host, but the firmware never reads it.
+            Self::Efi(efi) => efi.prepare_vstore(),
+        }
+    }
+}
+
+/// Bootloader firmware identifier.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BootloaderFw {
+    Efi,
+    Linux,
+}
+
+impl FromStr for BootloaderFw {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let string = s.to_string().to_lowercase();
+
+        match string.as_str() {
+            "efi" => Ok(Self::Efi),
+            "linux" => Ok(Self::Linux),
+            _ => Err(anyhow!("invalid bootloader firmware option: {}", string)),
+        }
+    }
+}
+
+/// Configuration of the EFI bootloader.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct EfiConfig {
+    /// Path of the EFI variable store.
+    #[serde(rename = "variable-store")]
+    vstore: PathBuf,
+
+    /// Action to take on the variable store.
+    action: Action,
+
+    /// Variable store to copy when creating or resetting the variable store. An empty variable
+    /// store is used if not given.
+    template: Option<PathBuf>,
+
+    /// Directory of the Secure Boot keys (PK, KEK, db and dbx certificates) to enroll when
+    /// creating or resetting the variable store.
+    #[serde(rename = "secure-boot")]
+    secure_boot: Option<PathBuf>,
+}
+
+impl FromStr for EfiConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let args = args_parse(s.to_string(), "bootloader", None)?;
+
+        if args.len() < 2 || args.len() > 4 {
+            return Err(anyhow!(
+                "expected --bootloader efi argument to have 2 to 4 comma-separated sub-arguments, found {}",
+                args.len()
+            ));
+        }
+
+        let v = Vstore::from_str(&args[0])?;
+        let action = Action::from_str(&args[1])?;
+
+        let mut template = None;
+        let mut secure_boot = None;
+        for arg in &args[2..] {
+            let (label, value) = arg
+                .split_once('=')
+                .ok_or_else(|| anyhow!("invalid argument format: {}", arg))?;
+
+            match label {
+                "template" => {
+                    template = Some(
+                        PathBuf::from_str(value).context("template argument not a valid path")?,
+                    )
+                }
+                "secure-boot" => {
+                    secure_boot = Some(
+                        PathBuf::from_str(value)
+                            .context("secure-boot argument not a valid path")?,
+                    )
+                }
+                _ => return Err(anyhow!("invalid efi bootloader argument: {}", label)),
+            }
+        }
+
+        Ok(Self {
+            vstore: v.0,
+            action,
+            template,
+            secure_boot,
+        })
+    }
+}
+
+impl EfiConfig {
+    /// Create, open or reset the variable store as requested by the bootloader action.
+    fn prepare_vstore(&self) -> Result<()> {
+        match self.action {
+            Action::Create => {
+                if !self.vstore.exists() {
+                    return self.initial_vstore()?.save(&self.vstore);
+                }
+
+                // Booting without the requested keys would silently disable Secure Boot.
+                if self.secure_boot.is_some() {
+                    return Err(anyhow!(
+                        "variable store {} already exists, Secure Boot keys are only enrolled when creating or resetting the variable store",
+                        self.vstore.display()
+                    ));
+                }
+
+                // Never clobber an existing file, only reuse it if it is a valid variable store.
+                VariableStore::load(&self.vstore).context(format!(
+                    "refusing to overwrite {}, use the reset action to recreate it",
+                    self.vstore.display()
+                ))?;
+            }
+            Action::Open => {
+                if self.secure_boot.is_some() {
+                    return Err(anyhow!(
+                        "Secure Boot keys are only enrolled when creating or resetting the variable store"
+                    ));
+                }
+
+                if !self.vstore.exists() {
+                    return Err(anyhow!(
+                        "variable store {} does not exist",
+                        self.vstore.display()
+                    ));
+                }
+
+                VariableStore::load(&self.vstore)?;
+            }
+            Action::Reset => self.initial_vstore()?.save(&self.vstore)?,
+        }
+
+        Ok(())
+    }
+
+    /// Contents of a newly created variable store, with the Secure Boot keys enrolled if
+    /// requested.
+    fn initial_vstore(&self) -> Result<VariableStore> {
+        let mut store = match &self.template {
+            Some(template) => VariableStore::load(template)?,
+            None => VariableStore::default(),
+        };
+
+        if let Some(keys_dir) = &self.secure_boot {
+            secureboot::enroll(&mut store, keys_dir).context(format!(
+                "unable to enroll Secure Boot keys from {}",
+                keys_dir.display()
+            ))?;
+        }
+
+        Ok(store)
+    }
+
+    /// Verify that the root disk has an EFI system partition holding the default bootloader of
+    /// the host architecture. The variable store is not seen by the firmware, so its boot
+    /// entries cannot point at another bootloader. A disk that cannot be read is not a finding:
+    /// the check is skipped with a warning.
+    fn check_root_disk(&self, disk: &Image) -> Result<()> {
+        match self.root_disk_problem(disk) {
+            Ok(None) => Ok(()),
+            Ok(Some(problem)) => Err(anyhow!(problem)),
+            Err(err) => {
+                eprintln!(
+                    "Warning: unable to check root disk {}, skipping the boot check: {:#}",
+                    disk.path.display(),
+                    err
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// Why the root disk cannot boot, if it can be read.
+    fn root_disk_problem(&self, disk: &Image) -> Result<Option<String>> {
+        let path = disk.path.display();
+        let reader = disk.reader()?;
+
+        let Some(table) =
+            PartitionTable::read(&reader).context("unable to read partition table")?
+        else {
+            return Ok(Some(format!("root disk {} has no partition table", path)));
+        };
+        let Some(esp) = table.esp() else {
+            return Ok(Some(format!(
+                "root disk {} has no EFI system partition",
+                path
+            )));
+        };
+
+        let fat = FatFs::open(&reader, esp.start).context(format!(
+            "unable to read EFI system partition {}",
+            esp.number
+        ))?;
+
+        let Some(loader) = default_loader() else {
+            return Ok(None);
+        };
+
+        Ok(match fat.file_size(loader)? {
+            Some(size) if size > 0 => None,
+            Some(_) => Some(format!(
+                "bootloader {} of root disk {} is empty",
+                loader, path
+            )),
+            None => Some(format!(
+                "root disk {} has no {} bootloader in its EFI system partition",
+                path, loader
+            )),
+        })
+    }
+}
+
+/// Path of the bootloader the firmware falls back to on the host architecture, if known.
+pub fn default_loader() -> Option<&'static str> {
+    if cfg!(target_arch = "aarch64") {
+        Some("\\EFI\\BOOT\\BOOTAA64.EFI")
+    } else if cfg!(target_arch = "x86_64") {
+        Some("\\EFI\\BOOT\\BOOTX64.EFI")
+    } else {
+        None
+    }
+}
+
+/// Variable store.
+#[derive(Clone, Debug)]
+pub struct Vstore(PathBuf);
+
+impl Vstore {
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl FromStr for Vstore {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let value = val_parse(s.to_string(), "variable-store")?;
+
+        Ok(Self(
+            PathBuf::from_str(&value).context("variable-store argument not a valid path")?,
+        ))
+    }
+}
+
+/// Bootloader action.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Create the variable store if it does not exist.
+    Create,
+
+    /// Use an existing variable store.
+    Open,
+
+    /// Recreate the variable store, discarding its variables.
+    Reset,
+}
+
+impl FromStr for Action {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let string = s.to_string().to_lowercase();
+
+        match string.as_str() {
+            "create" => Ok(Self::Create),
+            "open" => Ok(Self::Open),
+            "reset" => Ok(Self::Reset),
+            _ => Err(anyhow!("invalid bootloader action: {}", string)),
+        }
+    }
+}
+
+/// Configuration of a direct Linux kernel boot.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct LinuxConfig {
+    /// Path of the kernel image.
+    kernel: PathBuf,
+
+    /// Path of the initial ramdisk.
+    initrd: Option<PathBuf>,
+
+    /// Kernel command line.
+    cmdline: Option<String>,
+}
+
+impl FromStr for LinuxConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut kernel = None;
+        let mut initrd = None;
+        let mut cmdline = None;
+
+        let mut rest = s;
+        while !rest.is_empty() {
+            let (label, value) = rest
+                .split_once('=')
+                .ok_or_else(|| anyhow!("invalid argument format: {}", rest))?;
+
+            // The kernel command line may itself contain commas, so it extends to the end of
+            // the argument.
+            if label == "cmdline" {
+                cmdline = Some(value.trim_matches('"').to_string());
+                break;
+            }
+
+            let (value, next) = value.split_once(',').unwrap_or((value, ""));
+            match label {
+                "kernel" => {
+                    kernel =
+                        Some(PathBuf::from_str(value).context("kernel argument not a valid path")?)
+                }
+                "initrd" => {
+                    initrd =
+                        Some(PathBuf::from_str(value).context("initrd argument not a valid path")?)
+                }
+                _ => return Err(anyhow!("invalid linux bootloader argument: {}", label)),
+            }
+
+            rest = next;
+        }
+
+        Ok(Self {
+            kernel: kernel.ok_or_else(|| anyhow!("linux bootloader requires a kernel argument"))?,
+            initrd,
+            cmdline,
+        })
+    }
+}
+
+/// Set the kernel, initrd and command line to boot directly.
+impl KrunContextSet for LinuxConfig {
+    fn krun_ctx_set(&self, backend: &dyn KrunBackend, id: u32) -> Result<(), anyhow::Error> {
+        let format = KernelFormat::detect(&self.kernel)?;
+
+        if let Some(initrd) = &self.initrd {
+            if !initrd.is_file() {
+                return Err(anyhow!("initrd {} not found", initrd.display()));
+            }
+        }
+
+        let kernel_cstr = path_to_cstring(&self.kernel)?;
+        let initrd_cstr = self.initrd.as_deref().map(path_to_cstring).transpose()?;
+        let cmdline_cstr = self
+            .cmdline
+            .as_deref()
+            .map(CString::new)
+            .transpose()
+            .context("kernel command line contains a NULL byte")?;
+
+        if backend.set_kernel(
+            id,
+            &kernel_cstr,
+            format as u32,
+            initrd_cstr.as_deref(),
+            cmdline_cstr.as_deref(),
+        ) < 0
+        {
+            return Err(anyhow!("unable to set kernel {}", &self.kernel.display()));
+        }
+
+        Ok(())
+    }
+}
+
+/// Kernel image formats, with the values libkrun uses to identify them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KernelFormat {
+    /// Uncompressed image (arm64 Image).
+    Raw = 0,
+    Elf = 1,
+
+    /// PE (EFI zboot) image containing a gzip-compressed kernel.
+    PeGz = 2,
+    ImageBz2 = 3,
+    ImageGz = 4,
+    ImageZstd = 5,
+}
+
+impl KernelFormat {
+    /// Identify the format of a kernel image from its header.
+    pub fn detect(path: &Path) -> Result<Self> {
+        let mut header = Vec::new();
+        File::open(path)
+            .context(format!("unable to open kernel {}", path.display()))?
+            .take(0x240)
+            .read_to_end(&mut header)
+            .context(format!("unable to read kernel {}", path.display()))?;
+
+        Self::from_header(&header).ok_or_else(|| {
+            if header.get(0x202..0x206) == Some(b"HdrS") {
+                anyhow!(
+                    "kernel {} is a bzImage, which libkrun cannot load; use the uncompressed vmlinux ELF instead",
+                    path.display()
+                )
+            } else {
+                anyhow!("unrecognized format of kernel {}", path.display())
+            }
+        })
+    }
+
+    fn from_header(header: &[u8]) -> Option<Self> {
+        // arm64 Image, possibly with an EFI stub (starting with "MZ").
+        if header.get(0x38..0x3c) == Some(b"ARM\x64") {
+            return Some(Self::Raw);
+        }
+
+        // EFI zboot image, recording its compression type at offset 24.
+        if header.starts_with(b"MZ") && header.get(4..8) == Some(b"zimg") {
+            return match header.get(24..28) {
+                Some(b"gzip") => Some(Self::PeGz),
+                _ => None,
+            };
+        }
+
+        match header {
+            [0x7f, b'E', b'L', b'F', ..] => Some(Self::Elf),
+            [0x1f, 0x8b, ..] => Some(Self::ImageGz),
+            [b'B', b'Z', b'h', ..] => Some(Self::ImageBz2),
+            [0x28, 0xb5, 0x2f, 0xfd, ..] => Some(Self::ImageZstd),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        image::Format,
+        test_util::{esp_disk, gpt_disk, temp_path},
+        varstore::Guid,
+    };
+
+    #[test]
+    fn parse_linux() {
+        let config = Config::from_str(
+            "linux,kernel=/boot/Image,initrd=/boot/initrd,cmdline=\"console=hvc0 opts=a,b\"",
+        )
+        .unwrap();
+
+        let Config::Linux(linux) = config else {
+            panic!("expected linux bootloader");
+        };
+        assert_eq!(linux.kernel, PathBuf::from("/boot/Image"));
+        assert_eq!(linux.initrd, Some(PathBuf::from("/boot/initrd")));
+        assert_eq!(linux.cmdline.as_deref(), Some("console=hvc0 opts=a,b"));
+
+        assert!(Config::from_str("linux,initrd=/boot/initrd").is_err());
+        assert!(Config::from_str("linux,kernel=/boot/Image,foo=bar").is_err());
+    }
+
+    #[test]
+    fn parse_efi() {
+        assert!(matches!(
+            Config::from_str("efi,variable-store=/tmp/vstore,create").unwrap(),
+            Config::Efi(_)
+        ));
+        assert!(Config::from_str("efi,variable-store=/tmp/vstore").is_err());
+        assert!(Config::from_str("efi,variable-store=/tmp/vstore,open,template=/tmp/t").is_ok());
+        assert!(Config::from_str(
+            "efi,variable-store=/tmp/vstore,create,secure-boot=/tmp/keys,template=/tmp/t"
+        )
+        .is_ok());
+        assert!(Config::from_str("efi,variable-store=/tmp/vstore,create,keys=/tmp/k").is_err());
+    }
+
+    #[test]
+    fn vstore_actions() {
+        let vstore = temp_path("vstore");
+        let efi = |action| EfiConfig {
+            vstore: vstore.clone(),
+            action,
+            template: None,
+            secure_boot: None,
+        };
+
+        // The store must exist to be opened.
+        assert!(efi(Action::Open).prepare_vstore().is_err());
+
+        efi(Action::Create).prepare_vstore().unwrap();
+        efi(Action::Open).prepare_vstore().unwrap();
+        assert_eq!(
+            VariableStore::load(&vstore).unwrap(),
+            VariableStore::default()
+        );
+
+        // Secure Boot keys are never silently left out of an existing store.
+        let err = EfiConfig {
+            secure_boot: Some(PathBuf::from("/nonexistent/keys")),
+            ..efi(Action::Create)
+        }
+        .prepare_vstore()
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "variable store {} already exists, Secure Boot keys are only enrolled when creating or resetting the variable store",
+                vstore.display()
+            )
+        );
+
+        // Files that are not variable stores are only overwritten by a reset.
+        std::fs::write(&vstore, b"not a variable store").unwrap();
+        assert!(efi(Action::Create).prepare_vstore().is_err());
+        assert_eq!(std::fs::read(&vstore).unwrap(), b"not a variable store");
+        efi(Action::Reset).prepare_vstore().unwrap();
+        assert!(VariableStore::load(&vstore).is_ok());
+
+        std::fs::remove_file(&vstore).unwrap();
+    }
+
+    #[test]
+    fn kernel_formats() {
+        let mut image = vec![b'M', b'Z'];
+        image.resize(0x38, 0);
+        image.extend_from_slice(b"ARM\x64");
+        assert_eq!(KernelFormat::from_header(&image), Some(KernelFormat::Raw));
+
+        let mut zboot = b"MZ\0\0zimg".to_vec();
+        zboot.resize(24, 0);
+        zboot.extend_from_slice(b"gzip");
+        assert_eq!(KernelFormat::from_header(&zboot), Some(KernelFormat::PeGz));
+
+        assert_eq!(
+            KernelFormat::from_header(b"\x7fELF\x02\x01"),
+            Some(KernelFormat::Elf)
+        );
+        assert_eq!(
+            KernelFormat::from_header(&[0x1f, 0x8b, 0x08]),
+            Some(KernelFormat::ImageGz)
+        );
+        assert_eq!(KernelFormat::from_header(b"not a kernel"), None);
+    }
+
+    #[test]
+    fn root_disk_check() {
+        let vstore = temp_path("vstore");
+        let disk = temp_path("disk.img");
+        let efi = EfiConfig {
+            vstore: vstore.clone(),
+            action: Action::Create,
+            template: None,
+            secure_boot: None,
+        };
+        efi.prepare_vstore().unwrap();
+
+        let check = |contents: Vec<u8>| {
+            std::fs::write(&disk, contents).unwrap();
+            efi.check_root_disk(&Image::open(&disk, Format::Raw).unwrap())
+        };
+
+        let bootable = check(esp_disk(true));
+        let no_loader = check(esp_disk(false));
+        let unpartitioned = check(vec![0u8; 1 << 20]);
+        let no_esp = check(gpt_disk(
+            1 << 20,
+            &[(Guid::from_bytes(&[1; 16]), 34, 2047, "x")],
+        ));
+
+        // A qcow2 image with 64K clusters whose first cluster is compressed: the disk cannot be
+        // read, so the check is skipped.
+        let mut qcow2 = vec![0u8; 3 << 16];
+        qcow2[0..4].copy_from_slice(b"QFI\xfb");
+        qcow2[4..8].copy_from_slice(&3u32.to_be_bytes());
+        qcow2[20..24].copy_from_slice(&16u32.to_be_bytes());
+        qcow2[24..32].copy_from_slice(&(1u64 << 20).to_be_bytes());
+        qcow2[36..40].copy_from_slice(&1u32.to_be_bytes());
+        qcow2[40..48].copy_from_slice(&0x10000u64.to_be_bytes());
+        qcow2[100..104].copy_from_slice(&104u32.to_be_bytes());
+        qcow2[0x10000..0x10008].copy_from_slice(&0x20000u64.to_be_bytes());
+        qcow2[0x20000..0x20008].copy_from_slice(&(1u64 << 62 | 0x30000).to_be_bytes());
+        std::fs::write(&disk, qcow2).unwrap();
+        let compressed = efi.check_root_disk(&Image::open(&disk, Format::Qcow2).unwrap());
+
+        std::fs::remove_file(&vstore).unwrap();
+        std::fs::remove_file(&disk).unwrap();
+
+        bootable.unwrap();
+        compressed.unwrap();
+        assert_eq!(
+            unpartitioned.err().unwrap().to_string(),
+            format!("root disk {} has no partition table", disk.display())
+        );
+        assert_eq!(
+            no_esp.err().unwrap().to_string(),
+            format!("root disk {} has no EFI system partition", disk.display())
+        );
+        if let Some(loader) = default_loader() {
+            assert!(no_loader.err().unwrap().to_string().contains(loader));
+        }
+    }
+}
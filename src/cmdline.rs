@@ -1,35 +1,106 @@
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::virtio::VirtioDeviceConfig;
+use crate::{
+    bootloader, config,
+    disk::DiskArgs,
+    efivars::EfivarsArgs,
+    forward::Forward,
+    status::{RestfulUri, DEFAULT_RESTFUL_URI},
+    virtio::VirtioDeviceConfig,
+};
 
-use std::{path::PathBuf, str::FromStr};
+use std::path::PathBuf;
 
-use anyhow::{anyhow, Context, Result};
-use clap::Parser;
+use anyhow::{anyhow, Result};
+use clap::{ArgMatches, FromArgMatches, Parser, Subcommand};
 
 /// Command line arguments to configure a krun VM.
 #[derive(Clone, Debug, Parser)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    subcommand_negates_reqs = true,
+    args_conflicts_with_subcommands = true
+)]
 pub struct Args {
-    /// Number of vCPUs for the VM.
+    /// Subcommand to run instead of a VM.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// VM configuration file (TOML, JSON or YAML). Command line arguments override its values.
     #[arg(long)]
+    pub config: Option<PathBuf>,
+
+    /// Number of vCPUs for the VM.
+    #[arg(long, required = false, required_unless_present = "config")]
     pub cpus: u8,
 
     /// Amount of RAM available to VM.
-    #[arg(long)]
+    #[arg(long, required = false, required_unless_present = "config")]
     pub memory: u32,
 
     /// Bootloader configuration.
-    #[arg(long)]
+    #[arg(long, required = false, required_unless_present = "config")]
     pub bootloader: bootloader::Config,
 
     /// virtio devices to configure in the VM.
     #[arg(long = "device")]
     pub devices: Vec<VirtioDeviceConfig>,
 
-    /// URI of the status/shutdown listener.
-    #[arg(long = "restful-uri")]
-    pub restful_uri: String,
+    /// Forward a host TCP port to a guest vsock port (tcp:HOST:PORT=vsock:PORT).
+    #[arg(long = "forward")]
+    pub forwards: Vec<Forward>,
+
+    /// Run the VM from copy-on-write overlays of its writable disks, discarded on exit.
+    #[arg(long)]
+    pub ephemeral: bool,
+
+    /// Skip checking that the root disk holds the default EFI bootloader before starting the VM.
+    #[arg(long)]
+    pub no_boot_check: bool,
+
+    /// URI of the status/shutdown listener (tcp://host:port, unix:///path or none).
+    #[arg(long = "restful-uri", default_value = DEFAULT_RESTFUL_URI)]
+    pub restful_uri: RestfulUri,
+}
+
+impl Args {
+    /// Gather the VM arguments from the parsed command line, merging them with the configuration
+    /// file if one is given.
+    pub fn from_matches(matches: &ArgMatches) -> Result<Self> {
+        match matches.get_one::<PathBuf>("config") {
+            Some(path) => config::merge(matches, path),
+            None => Ok(Self::from_arg_matches(matches)?),
+        }
+    }
+}
+
+/// Subcommands operating on VM files without running a VM.
+#[derive(Clone, Debug, Subcommand)]
+pub enum Command {
+    /// Inspect and edit the variables of an EFI variable store.
+    Efivars(EfivarsArgs),
+
+    /// Create, resize and inspect disk images.
+    Disk(DiskArgs),
+}
+
+impl Command {
+    /// Get the subcommand from the parsed command line, if one was given.
+    pub fn from_matches(matches: &ArgMatches) -> Result<Option<Self>> {
+        match matches.subcommand() {
+            Some(_) => Ok(Some(Self::from_arg_matches(matches)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn run(&self) -> Result<()> {
+        match self {
+            Self::Efivars(efivars) => efivars.run(),
+            Self::Disk(disk) => disk.run(),
+        }
+    }
 }
 
 /// Parse a string into a vector of substrings, all of which are separated by commas.
@@ -75,86 +146,26 @@ pub fn val_parse(s: String, label: &str) -> Result<String> {
     }
 }
 
-/// A wrapper of all data associated with the bootloader argument.
-mod bootloader {
-    use super::*;
-
-    #[derive(Clone, Debug)]
-    pub struct Config {
-        fw: BootloaderFw,
-        vstore: PathBuf,
-        action: Action,
-    }
-
-    impl FromStr for Config {
-        type Err = anyhow::Error;
-
-        fn from_str(s: &str) -> Result<Self, Self::Err> {
-            let args = args_parse(s.to_string(), "bootloader", Some(3))?;
-
-            let fw = BootloaderFw::from_str(&args[0])?;
-            let v = Vstore::from_str(&args[1])?;
-            let action = Action::from_str(&args[2])?;
-
-            Ok(Self {
-                fw,
-                vstore: v.0,
-                action,
-            })
-        }
-    }
-
-    /// Bootloader firmware identifier.
-    #[derive(Clone, Debug)]
-    pub enum BootloaderFw {
-        Efi,
-    }
-
-    impl FromStr for BootloaderFw {
-        type Err = anyhow::Error;
-
-        fn from_str(s: &str) -> Result<Self, Self::Err> {
-            let string = s.to_string().to_lowercase();
-
-            match string.as_str() {
-                "efi" => Ok(Self::Efi),
-                _ => Err(anyhow!("invalid bootloader firmware option: {}", string)),
-            }
-        }
-    }
-
-    /// Variable store.
-    #[derive(Clone, Debug)]
-    pub struct Vstore(PathBuf);
-
-    impl FromStr for Vstore {
-        type Err = anyhow::Error;
-
-        fn from_str(s: &str) -> Result<Self, Self::Err> {
-            let value = val_parse(s.to_string(), "variable-store")?;
-
-            Ok(Self(
-                PathBuf::from_str(&value).context("variable-store argument not a valid path")?,
-            ))
-        }
-    }
-
-    /// Bootloader action.
-    #[derive(Clone, Debug)]
-    pub enum Action {
-        Create,
-    }
-
-    impl FromStr for Action {
-        type Err = anyhow::Error;
-
-        fn from_str(s: &str) -> Result<Self, Self::Err> {
-            let string = s.to_string().to_lowercase();
-
-            match string.as_str() {
-                "create" => Ok(Self::Create),
-                _ => Err(anyhow!("invalid bootloader action: {}", string)),
-            }
-        }
-    }
+/// Parse a size in bytes, with an optional unit suffix (K, M, G or T, and their KB/KiB forms).
+pub fn size_parse(s: &str) -> Result<u64> {
+    let invalid = || anyhow!("invalid size: {}", s);
+
+    let lower = s.trim().to_lowercase();
+    let number = lower.trim_end_matches(char::is_alphabetic);
+    let unit = &lower[number.len()..];
+
+    let shift = match unit {
+        "" | "b" => 0,
+        "k" | "kb" | "kib" => 10,
+        "m" | "mb" | "mib" => 20,
+        "g" | "gb" | "gib" => 30,
+        "t" | "tb" | "tib" => 40,
+        _ => return Err(invalid()),
+    };
+
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(invalid)
 }
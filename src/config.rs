@@ -0,0 +1,260 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    bootloader, cmdline::Args, forward::Forward, status::RestfulUri, virtio::VirtioDeviceConfig,
+};
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use clap::{parser::ValueSource, ArgMatches};
+use serde::Deserialize;
+
+/// VM configuration read from a file given with --config. Each key corresponds to the command
+/// line argument of the same name.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ConfigFile {
+    cpus: Option<u8>,
+    memory: Option<u32>,
+    bootloader: Option<bootloader::Config>,
+    #[serde(default)]
+    devices: Vec<VirtioDeviceConfig>,
+    #[serde(default)]
+    forward: Vec<Forward>,
+    ephemeral: Option<bool>,
+    no_boot_check: Option<bool>,
+    restful_uri: Option<RestfulUri>,
+}
+
+/// Configuration file formats, identified by the file extension.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Toml,
+    Json,
+    Yaml,
+}
+
+impl Format {
+    fn from_path(path: &Path) -> Result<Self> {
+        let ext = path
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+
+        match &ext[..] {
+            "toml" => Ok(Self::Toml),
+            "json" => Ok(Self::Json),
+            "yaml" | "yml" => Ok(Self::Yaml),
+            _ => Err(anyhow!(
+                "unable to determine format of configuration file {} (expected a .toml, .json, .yaml or .yml extension)",
+                path.display()
+            )),
+        }
+    }
+}
+
+impl ConfigFile {
+    /// Read and parse a configuration file.
+    pub fn load(path: &Path) -> Result<Self> {
+        let format = Format::from_path(path)?;
+        let contents = fs::read_to_string(path).context(format!(
+            "unable to read configuration file {}",
+            path.display()
+        ))?;
+
+        Self::parse(&contents, format)
+            .context(format!("invalid configuration file {}", path.display()))
+    }
+
+    /// Parse the contents of a configuration file. Errors name the offending key and its
+    /// location in the file.
+    pub fn parse(contents: &str, format: Format) -> Result<Self> {
+        let config = match format {
+            Format::Toml => toml::from_str(contents)?,
+            Format::Json => {
+                serde_path_to_error::deserialize(&mut serde_json::Deserializer::from_str(contents))?
+            }
+            Format::Yaml => {
+                serde_path_to_error::deserialize(serde_yaml::Deserializer::from_str(contents))?
+            }
+        };
+
+        Ok(config)
+    }
+
+    /// Build the VM arguments from the configuration file, with the values given on the command
+    /// line taking precedence. Devices given on the command line are added to the ones of the
+    /// file.
+    pub fn merge(self, matches: &ArgMatches, path: &Path) -> Result<Args> {
+        let missing = |key: &str| {
+            anyhow!(
+                "{} not set: specify it in {} or with --{}",
+                key,
+                path.display(),
+                key
+            )
+        };
+
+        let cpus = matches
+            .get_one::<u8>("cpus")
+            .copied()
+            .or(self.cpus)
+            .ok_or_else(|| missing("cpus"))?;
+        let memory = matches
+            .get_one::<u32>("memory")
+            .copied()
+            .or(self.memory)
+            .ok_or_else(|| missing("memory"))?;
+        let bootloader = matches
+            .get_one::<bootloader::Config>("bootloader")
+            .cloned()
+            .or(self.bootloader)
+            .ok_or_else(|| missing("bootloader"))?;
+
+        let mut devices = self.devices;
+        if let Some(cmdline_devices) = matches.get_many::<VirtioDeviceConfig>("devices") {
+            devices.extend(cmdline_devices.cloned());
+        }
+
+        let mut forwards = self.forward;
+        if let Some(cmdline_forwards) = matches.get_many::<Forward>("forwards") {
+            forwards.extend(cmdline_forwards.cloned());
+        }
+
+        // Ephemeral mode is enabled by either the file or the command line flag.
+        let ephemeral = matches.get_flag("ephemeral") || self.ephemeral.unwrap_or(false);
+        let no_boot_check =
+            matches.get_flag("no_boot_check") || self.no_boot_check.unwrap_or(false);
+
+        // The restful URI always has a value on the command line, as it has a default.
+        let cmdline_uri = matches.get_one::<RestfulUri>("restful_uri").cloned();
+        let restful_uri = match (matches.value_source("restful_uri"), self.restful_uri) {
+            (Some(ValueSource::CommandLine), _) | (_, None) => cmdline_uri.unwrap_or_default(),
+            (_, Some(uri)) => uri,
+        };
+
+        Ok(Args {
+            command: None,
+            config: Some(PathBuf::from(path)),
+            cpus,
+            memory,
+            bootloader,
+            devices,
+            forwards,
+            ephemeral,
+            no_boot_check,
+            restful_uri,
+        })
+    }
+}
+
+/// Load the configuration file and merge it with the command line arguments.
+pub fn merge(matches: &ArgMatches, path: &Path) -> Result<Args> {
+    ConfigFile::load(path)?.merge(matches, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use clap::CommandFactory;
+
+    const TOML: &str = r#"
+cpus = 2
+memory = 2048
+restful-uri = "none"
+forward = ["tcp:127.0.0.1:2222=vsock:22"]
+
+[bootloader]
+fw = "efi"
+variable-store = "/tmp/vstore"
+action = "create"
+
+[[devices]]
+kind = "virtio-blk"
+path = "/tmp/disk.img"
+
+[[devices]]
+kind = "virtio-rng"
+"#;
+
+    fn matches(cmdline: &[&str]) -> ArgMatches {
+        Args::command()
+            .try_get_matches_from(["krunkit", "--config", "vm.toml"].iter().chain(cmdline))
+            .unwrap()
+    }
+
+    #[test]
+    fn parse_formats() {
+        let toml = ConfigFile::parse(TOML, Format::Toml).unwrap();
+        assert_eq!(toml.cpus, Some(2));
+        assert_eq!(toml.devices.len(), 2);
+
+        let json = r#"{
+            "cpus": 2,
+            "devices": [{ "kind": "virtio-vsock", "port": 1024, "socketURL": "/tmp/s", "action": "listen" }]
+        }"#;
+        let json = ConfigFile::parse(json, Format::Json).unwrap();
+        assert!(matches!(json.devices[0], VirtioDeviceConfig::Vsock(_)));
+
+        let yaml =
+            "memory: 512\ndevices:\n  - kind: virtio-fs\n    sharedDir: /tmp\n    mountTag: tmp\n";
+        let yaml = ConfigFile::parse(yaml, Format::Yaml).unwrap();
+        assert_eq!(yaml.memory, Some(512));
+    }
+
+    #[test]
+    fn errors_point_at_key() {
+        let err = ConfigFile::parse("cpus = 2\ncpu = 2\n", Format::Toml).unwrap_err();
+        let msg = err.to_string();
+        assert!(msg.contains("line 2") && msg.contains("cpu"), "{msg}");
+
+        let json = "{\n  \"devices\": [\n    { \"kind\": \"virtio-net\", \"unixSocketPath\": \"/tmp/n\", \"mac\": \"zz\" }\n  ]\n}";
+        let msg = ConfigFile::parse(json, Format::Json)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            msg.starts_with("devices[0]") && msg.contains("line"),
+            "{msg}"
+        );
+    }
+
+    #[test]
+    fn cmdline_overrides_file() {
+        let file = ConfigFile::parse(TOML, Format::Toml).unwrap();
+        let args = file
+            .merge(
+                &matches(&[
+                    "--cpus",
+                    "4",
+                    "--device",
+                    "virtio-fs,sharedDir=/a,mountTag=a",
+                    "--forward",
+                    "tcp:127.0.0.1:8080=vsock:80",
+                ]),
+                Path::new("vm.toml"),
+            )
+            .unwrap();
+
+        assert_eq!(args.cpus, 4);
+        assert_eq!(args.memory, 2048);
+        assert_eq!(args.restful_uri, RestfulUri::None);
+        assert_eq!(args.devices.len(), 3);
+        assert_eq!(args.forwards.len(), 2);
+    }
+
+    #[test]
+    fn missing_required_value() {
+        let file = ConfigFile::parse("cpus = 1\nmemory = 512\n", Format::Toml).unwrap();
+        let err = file.merge(&matches(&[]), Path::new("vm.toml")).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "bootloader not set: specify it in vm.toml or with --bootloader"
+        );
+    }
+}
@@ -0,0 +1,475 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    backend::KrunBackend,
+    logfile::{LogOptions, RotatingLog},
+    virtio::KrunContextSet,
+};
+
+use std::{
+    ffi::CStr,
+    fs::File,
+    io::{self, Read, Write},
+    os::unix::{
+        fs::OpenOptionsExt,
+        io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    },
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use anyhow::{anyhow, Context, Result};
+
+/// Terminal attributes of stdin before it was switched to raw mode, restored on exit.
+static SAVED_TERMIOS: Mutex<Option<libc::termios>> = Mutex::new(None);
+
+/// Default escape character of stdio consoles, Ctrl-].
+pub const DEFAULT_ESCAPE: u8 = 0x1d;
+
+/// The guest console, attached to krunkit's stdio, a pseudo-terminal or a log file.
+pub struct Console {
+    input: RawFd,
+    output: RawFd,
+    _pty: Option<Pty>,
+    stdio: Option<Stdio>,
+
+    /// Pipe ends used by libkrun, kept open as long as the console.
+    _pipes: Vec<OwnedFd>,
+}
+
+/// Pipes between krunkit's stdio and the guest console. krunkit forwards the bytes, so that it
+/// can handle escape sequences and stop forwarding when detached.
+struct Stdio {
+    /// krunkit's ends of the pipes: the guest's input and output.
+    to_guest: File,
+    from_guest: File,
+
+    /// The guest's ends of the pipes, used by libkrun.
+    _guest: (OwnedFd, OwnedFd),
+
+    /// Escape character starting commands, None if escape sequences are disabled.
+    escape: Option<u8>,
+}
+
+impl Console {
+    /// Attach the console to stdin and stdout. If stdin is a terminal, it is switched to raw
+    /// mode, so that the guest receives every key press, until krunkit exits.
+    pub fn stdio(escape: Option<u8>) -> Result<Self> {
+        let (guest_input, to_guest) = pipe()?;
+        let (from_guest, guest_output) = pipe()?;
+
+        if unsafe { libc::isatty(libc::STDIN_FILENO) } == 1 {
+            raw_mode()?;
+        }
+
+        Ok(Self {
+            input: guest_input.as_raw_fd(),
+            output: guest_output.as_raw_fd(),
+            _pty: None,
+            stdio: Some(Stdio {
+                to_guest: File::from(to_guest),
+                from_guest: File::from(from_guest),
+                _guest: (guest_input, guest_output),
+                escape,
+            }),
+            _pipes: Vec::new(),
+        })
+    }
+
+    /// Write the console's output to a log file, rotating and timestamping it as krunkit copies
+    /// the output. The guest receives no input.
+    pub fn log_file(path: &Path, options: LogOptions) -> Result<Self> {
+        let mut log = RotatingLog::open(path, options)?;
+        let (guest_input, no_input) = pipe()?;
+        let (from_guest, guest_output) = pipe()?;
+
+        let mut from_guest = File::from(from_guest);
+        thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            let mut failed = false;
+            while let Ok(len) = from_guest.read(&mut buf) {
+                if len == 0 {
+                    break;
+                }
+
+                // Keep reading the guest's output, so that the guest never blocks on it.
+                if let Err(err) = log.write(&buf[..len]) {
+                    if !failed {
+                        eprintln!("{:#}", err);
+                        failed = true;
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            input: guest_input.as_raw_fd(),
+            output: guest_output.as_raw_fd(),
+            _pty: None,
+            stdio: None,
+            _pipes: vec![guest_input, no_input, guest_output],
+        })
+    }
+
+    /// Attach the console to a new pseudo-terminal, returning its path for users to connect to.
+    pub fn pty() -> Result<(Self, PathBuf)> {
+        let pty = Pty::open()?;
+        let path = pty.path.clone();
+
+        Ok((
+            Self {
+                input: pty.master.as_raw_fd(),
+                output: pty.master.as_raw_fd(),
+                _pty: Some(pty),
+                stdio: None,
+                _pipes: Vec::new(),
+            },
+            path,
+        ))
+    }
+
+    /// Whether the console is attached to krunkit's stdio.
+    pub fn is_stdio(&self) -> bool {
+        self.stdio.is_some()
+    }
+
+    /// Start forwarding krunkit's stdio to and from the guest console, once the VM runs. The
+    /// quit command of the escape sequence calls the given function to stop the VM.
+    pub fn start(&self, mut quit: impl FnMut() + Send + 'static) -> Result<()> {
+        let Some(stdio) = &self.stdio else {
+            return Ok(());
+        };
+
+        let detached = Arc::new(AtomicBool::new(false));
+
+        // Keep reading the guest's output once detached, so that the guest never blocks on it.
+        let mut from_guest = stdio.from_guest.try_clone()?;
+        let output_detached = detached.clone();
+        thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            let mut stdout = io::stdout();
+            while let Ok(len) = from_guest.read(&mut buf) {
+                if len == 0 {
+                    break;
+                }
+
+                if !output_detached.load(Ordering::Relaxed) {
+                    let _ = stdout.write_all(&buf[..len]);
+                    let _ = stdout.flush();
+                }
+            }
+        });
+
+        let mut to_guest = stdio.to_guest.try_clone()?;
+        let mut filter = EscapeFilter::new(stdio.escape);
+        thread::spawn(move || {
+            let mut stdin = io::stdin();
+            let mut buf = [0u8; 4096];
+            let mut forward = Vec::new();
+            while let Ok(len) = stdin.read(&mut buf) {
+                if len == 0 {
+                    break;
+                }
+
+                forward.clear();
+                let command = filter.filter(&buf[..len], &mut forward);
+                if to_guest.write_all(&forward).is_err() {
+                    break;
+                }
+
+                match command {
+                    Some(Command::Quit) => quit(),
+                    Some(Command::Detach) => {
+                        detached.store(true, Ordering::Relaxed);
+                        detach();
+                        break;
+                    }
+                    None => (),
+                }
+            }
+        });
+
+        Ok(())
+    }
+}
+
+/// Stop using the terminal, leaving the VM running. When krunkit is the foreground job of a
+/// shell with job control, it is suspended so that the shell regains control, and resumes in
+/// the background with `bg`. Otherwise nothing would resume it, so it keeps running.
+fn detach() {
+    restore_terminal();
+    eprintln!("\r\nDetached from the VM console");
+
+    if foreground_job() {
+        unsafe { libc::raise(libc::SIGTSTP) };
+    }
+}
+
+/// Whether krunkit is the foreground process group of its terminal, as set by a shell with job
+/// control.
+fn foreground_job() -> bool {
+    unsafe {
+        libc::isatty(libc::STDIN_FILENO) == 1
+            && libc::tcgetpgrp(libc::STDIN_FILENO) == libc::getpgrp()
+    }
+}
+
+/// Commands given with escape sequences.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Command {
+    /// Stop the VM gracefully.
+    Quit,
+
+    /// Stop using the terminal, leaving the VM running.
+    Detach,
+}
+
+/// Filter of the escape sequences of the input: the escape character followed by a command
+/// (q or d). The escape character is sent to the guest by typing it twice, and escape
+/// sequences with unknown commands are sent as is.
+struct EscapeFilter {
+    escape: Option<u8>,
+    pending: bool,
+}
+
+impl EscapeFilter {
+    fn new(escape: Option<u8>) -> Self {
+        Self {
+            escape,
+            pending: false,
+        }
+    }
+
+    /// Append the bytes of the input to forward to the guest, up to the first command.
+    fn filter(&mut self, input: &[u8], forward: &mut Vec<u8>) -> Option<Command> {
+        let Some(escape) = self.escape else {
+            forward.extend_from_slice(input);
+            return None;
+        };
+
+        for &b in input {
+            if !self.pending {
+                match b == escape {
+                    true => self.pending = true,
+                    false => forward.push(b),
+                }
+                continue;
+            }
+
+            self.pending = false;
+            match b {
+                b'q' | b'Q' => return Some(Command::Quit),
+                b'd' | b'D' => return Some(Command::Detach),
+                _ if b == escape => forward.push(b),
+                _ => forward.extend_from_slice(&[escape, b]),
+            }
+        }
+
+        None
+    }
+}
+
+/// Parse an escape character given as a caret notation control character (e.g. ^]) or "none".
+pub fn parse_escape(s: &str) -> Result<Option<u8>> {
+    let invalid = || anyhow!("invalid escape character {} (expected ^X or none)", s);
+
+    if s == "none" {
+        return Ok(None);
+    }
+
+    match s.as_bytes() {
+        [b'^', c] if (b'@'..=b'_').contains(&c.to_ascii_uppercase()) => {
+            Ok(Some(c.to_ascii_uppercase() & 0x1f))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/// Create a pipe, returning its read and write ends.
+fn pipe() -> Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error()).context("unable to create console pipe");
+    }
+
+    Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) })
+}
+
+/// Replace libkrun's implicit console with a console reading from and writing to the
+/// console's file descriptors.
+impl KrunContextSet for Console {
+    fn krun_ctx_set(&self, backend: &dyn KrunBackend, id: u32) -> Result<(), anyhow::Error> {
+        if backend.disable_implicit_console(id) < 0 {
+            return Err(anyhow!("unable to disable libkrun's implicit console"));
+        }
+
+        if backend.add_virtio_console_default(id, self.input, self.output, self.output) < 0 {
+            return Err(anyhow!("unable to add virtio-serial console"));
+        }
+
+        Ok(())
+    }
+}
+
+/// A pseudo-terminal. The VM uses the master side, and users connect to the slave side, which
+/// krunkit keeps open so that the master is usable while no user is connected.
+struct Pty {
+    master: OwnedFd,
+    _slave: File,
+    path: PathBuf,
+}
+
+impl Pty {
+    fn open() -> Result<Self> {
+        let fd = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error()).context("unable to allocate a pseudo-terminal");
+        }
+        let master = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        if unsafe { libc::grantpt(fd) } < 0 || unsafe { libc::unlockpt(fd) } < 0 {
+            return Err(io::Error::last_os_error()).context("unable to unlock the pseudo-terminal");
+        }
+
+        // ptsname() returns a static buffer, only use it while holding a lock.
+        static PTSNAME: Mutex<()> = Mutex::new(());
+        let path = {
+            let _guard = PTSNAME.lock().unwrap();
+            let name = unsafe { libc::ptsname(fd) };
+            if name.is_null() {
+                return Err(io::Error::last_os_error())
+                    .context("unable to get the pseudo-terminal path");
+            }
+
+            PathBuf::from(
+                unsafe { CStr::from_ptr(name) }
+                    .to_string_lossy()
+                    .into_owned(),
+            )
+        };
+
+        let slave = File::options()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(&path)
+            .context(format!("unable to open pseudo-terminal {}", path.display()))?;
+
+        // The guest handles line editing and echoing, so pass the bytes through unchanged.
+        let mut termios = get_termios(slave.as_raw_fd())?;
+        unsafe { libc::cfmakeraw(&mut termios) };
+        set_termios(slave.as_raw_fd(), &termios)?;
+
+        Ok(Self {
+            master,
+            _slave: slave,
+            path,
+        })
+    }
+}
+
+/// Switch stdin to raw mode, restoring its attributes when krunkit exits.
+fn raw_mode() -> Result<()> {
+    // libkrun exits the process when the guest shuts down, so restore in an exit handler.
+    extern "C" fn restore() {
+        restore_terminal();
+    }
+
+    let mut saved = SAVED_TERMIOS.lock().unwrap();
+    if saved.is_some() {
+        return Ok(());
+    }
+
+    let original = get_termios(libc::STDIN_FILENO)?;
+    let mut raw = original;
+    unsafe { libc::cfmakeraw(&mut raw) };
+
+    // Keep translating "\n" to "\r\n" on output, as krunkit and the guest print plain lines.
+    raw.c_oflag |= libc::OPOST | libc::ONLCR;
+    set_termios(libc::STDIN_FILENO, &raw)?;
+
+    *saved = Some(original);
+    unsafe {
+        libc::atexit(restore);
+    }
+
+    Ok(())
+}
+
+/// Restore the attributes of stdin if it was switched to raw mode.
+pub fn restore_terminal() {
+    if let Some(termios) = SAVED_TERMIOS.lock().ok().and_then(|mut t| t.take()) {
+        let _ = set_termios(libc::STDIN_FILENO, &termios);
+    }
+}
+
+fn get_termios(fd: RawFd) -> Result<libc::termios> {
+    let mut termios = unsafe { std::mem::zeroed() };
+    if unsafe { libc::tcgetattr(fd, &mut termios) } < 0 {
+        return Err(io::Error::last_os_error()).context("unable to get terminal attributes");
+    }
+
+    Ok(termios)
+}
+
+fn set_termios(fd: RawFd, termios: &libc::termios) -> Result<()> {
+    if unsafe { libc::tcsetattr(fd, libc::TCSANOW, termios) } < 0 {
+        return Err(io::Error::last_os_error()).context("unable to set terminal attributes");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pty() {
+        let (console, path) = Console::pty().unwrap();
+
+        // Bytes written by the guest reach the user connected to the pseudo-terminal unchanged.
+        let mut master = unsafe { File::from_raw_fd(libc::dup(console.output)) };
+        let mut user = File::options().read(true).write(true).open(&path).unwrap();
+        master.write_all(b"login: \n").unwrap();
+
+        let mut buf = [0u8; 8];
+        user.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"login: \n");
+
+        user.write_all(b"root\r").unwrap();
+        let mut buf = [0u8; 5];
+        master.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"root\r");
+    }
+
+    #[test]
+    fn escape_sequences() {
+        let mut filter = EscapeFilter::new(Some(DEFAULT_ESCAPE));
+        let mut forward = Vec::new();
+
+        // Escape sequences may be split across reads.
+        assert_eq!(filter.filter(b"ls\x1d", &mut forward), None);
+        assert_eq!(filter.filter(b"\x1d\x1dx", &mut forward), None);
+        assert_eq!(forward, b"ls\x1d\x1dx");
+
+        forward.clear();
+        assert_eq!(filter.filter(b"a\x1dqb", &mut forward), Some(Command::Quit));
+        assert_eq!(forward, b"a");
+        assert_eq!(filter.filter(b"\x1dd", &mut forward), Some(Command::Detach));
+
+        let mut disabled = EscapeFilter::new(None);
+        forward.clear();
+        assert_eq!(disabled.filter(b"\x1dq", &mut forward), None);
+        assert_eq!(forward, b"\x1dq");
+
+        assert_eq!(parse_escape("^]").unwrap(), Some(0x1d));
+        assert_eq!(parse_escape("^a").unwrap(), Some(0x01));
+        assert_eq!(parse_escape("none").unwrap(), None);
+        assert!(parse_escape("q").is_err());
+    }
+}
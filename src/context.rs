@@ -3,25 +3,37 @@
 use super::*;
 
 use crate::{
-    status::{get_shutdown_eventfd, status_listener},
-    virtio::KrunContextSet,
+    backend::{KrunBackend, Libkrun},
+    console::Console,
+    lock::FileLock,
+    state::{VmState, VmStatus},
+    status::status_listener,
+    virtio::{self, KrunContextSet},
 };
 
-use std::{convert::TryFrom, thread};
+use std::{
+    convert::TryFrom,
+    fs::File,
+    io::Write,
+    os::unix::io::{FromRawFd, RawFd},
+    sync::Arc,
+    thread,
+};
 
 use anyhow::anyhow;
 
-#[link(name = "krun-efi")]
-extern "C" {
-    fn krun_create_ctx() -> i32;
-    fn krun_set_vm_config(ctx_id: u32, num_vcpus: u8, ram_mib: u32) -> i32;
-    fn krun_start_enter(ctx_id: u32) -> i32;
-}
-
 /// A wrapper of all data used to configure the krun VM.
 pub struct KrunContext {
     id: u32,
+    backend: Arc<dyn KrunBackend>,
     args: Args,
+    state: VmState,
+
+    /// Locks of the variable store and disk images, held while the VM runs.
+    locks: Vec<FileLock>,
+
+    /// Console of the VM, if attached to stdio or a pseudo-terminal.
+    console: Option<Console>,
 }
 
 /// Create a krun context from the command line arguments.
@@ -29,9 +41,32 @@ impl TryFrom<Args> for KrunContext {
     type Error = anyhow::Error;
 
     fn try_from(args: Args) -> Result<Self, Self::Error> {
+        Self::new(args, Arc::new(Libkrun))
+    }
+}
+
+/// Check the consistency of the whole configuration, including the devices and forwards of a
+/// configuration file, before anything is created for the VM.
+fn validate(args: &Args) -> Result<(), anyhow::Error> {
+    args.bootloader.check_secure_boot()?;
+    virtio::check_vsock(&args.devices)?;
+    virtio::check_serial(&args.devices)?;
+    for forward in &args.forwards {
+        forward.check(&args.devices)?;
+    }
+
+    Ok(())
+}
+
+impl KrunContext {
+    /// Create a krun context from the command line arguments, configuring the VM through the
+    /// given backend.
+    pub fn new(mut args: Args, backend: Arc<dyn KrunBackend>) -> Result<Self, anyhow::Error> {
+        validate(&args)?;
+
         // Create a new context in libkrun. Store identifier to later use to configure VM
         // resources and devices.
-        let id = unsafe { krun_create_ctx() };
+        let id = backend.create_ctx();
         if id < 0 {
             return Err(anyhow!("unable to create libkrun context"));
         }
@@ -48,33 +83,852 @@ impl TryFrom<Args> for KrunContext {
             return Err(anyhow!("zero MiB RAM inputted (invalid)"));
         }
 
-        if unsafe { krun_set_vm_config(id, args.cpus, args.memory) } < 0 {
+        if backend.set_vm_config(id, args.cpus, args.memory) < 0 {
             return Err(anyhow!("unable to set krun vCPU/RAM configuration"));
         }
 
-        // Configure each virtio device to include in the VM.
+        // Configure the bootloader. An existing variable store is locked before it is prepared,
+        // as resetting it would corrupt the one of a running VM, and a new one once created.
+        let mut locks: Vec<FileLock> = args.bootloader.lock()?.into_iter().collect();
+        args.bootloader.krun_ctx_set(backend.as_ref(), id)?;
+        if locks.is_empty() {
+            locks.extend(args.bootloader.lock()?);
+        }
+
+        // Configure each virtio device to include in the VM, starting with the root disk.
+        virtio::prepare_disks(&mut args.devices)?;
+        virtio::prepare_compressed(&mut args.devices)?;
+        virtio::prepare_overlays(&mut args.devices, args.ephemeral)?;
+        locks.extend(virtio::lock_disks(&args.devices)?);
+        let console = virtio::prepare_serial(&mut args.devices)?;
+        if let Some(console) = &console {
+            console.krun_ctx_set(backend.as_ref(), id)?;
+        }
+
         for device in &args.devices {
-            unsafe { device.krun_ctx_set(id)? }
+            device.krun_ctx_set(backend.as_ref(), id)?;
+        }
+
+        // Fail fast if the firmware would find nothing to boot on the root disk.
+        if !args.no_boot_check {
+            args.bootloader
+                .check_root_disk(virtio::root_disk(&args.devices)?.as_ref())?;
         }
 
-        Ok(Self { id, args })
+        Ok(Self {
+            id,
+            backend,
+            args,
+            state: VmState::default(),
+            locks,
+            console,
+        })
     }
-}
 
-impl KrunContext {
     /// Spawn a thread to listen for shutdown requests and run the workload. If behaving properly,
     /// the main thread will never return from this function.
     pub fn run(&self) -> Result<(), anyhow::Error> {
-        // Get the krun shutdown file descriptor and listen to shutdown requests on a new thread.
-        let shutdown_eventfd = unsafe { get_shutdown_eventfd(self.id) };
+        // Bind the status listener before starting the workload, so that an unusable URI is
+        // reported before the VM boots.
+        if let Some(listener) = self.args.restful_uri.bind()? {
+            // Get the krun shutdown file descriptor and listen to shutdown requests on a new
+            // thread.
+            let shutdown_eventfd = self.shutdown_eventfd()?;
+
+            let args = self.args.clone();
+            let state = self.state.clone();
+
+            thread::spawn(move || {
+                status_listener(listener, shutdown_eventfd, args, state).unwrap()
+            });
+        }
 
-        thread::spawn(move || status_listener(shutdown_eventfd).unwrap());
+        // Bind the forwarded TCP ports before starting the workload, so that unusable addresses
+        // are reported before the VM boots.
+        let forwarders = self
+            .args
+            .forwards
+            .iter()
+            .map(|forward| forward.bind(&self.args.devices))
+            .collect::<Result<Vec<_>, anyhow::Error>>()?;
+        for forwarder in forwarders {
+            forwarder.start();
+        }
+
+        // Forward the console to and from stdio, stopping the VM on the quit escape sequence.
+        let console = match self.console.as_ref().filter(|c| c.is_stdio()) {
+            Some(console) => {
+                // The status listener owns the shutdown file descriptor, use a duplicate.
+                let fd = unsafe { libc::dup(self.shutdown_eventfd()?) };
+                if fd < 0 {
+                    return Err(anyhow!("unable to duplicate krun shutdown file descriptor"));
+                }
+
+                Some((console, unsafe { File::from_raw_fd(fd) }))
+            }
+            None => None,
+        };
+
+        // libkrun does not report when the VM has started, and start_enter only returns on
+        // failure, so the VM is reported as running once it is about to be handed to libkrun.
+        // The console starts afterwards, so that a quit escape sequence typed right away is not
+        // dropped.
+        self.state.transition(VmStatus::Running)?;
+
+        if let Some((console, mut shutdown)) = console {
+            let state = self.state.clone();
+
+            console
+                .start(move || {
+                    if state.transition(VmStatus::Stopping).is_err() {
+                        return;
+                    }
+
+                    if let Err(e) = shutdown.write_all(&1u64.to_le_bytes()) {
+                        state.fail(&anyhow!(e).context("unable to request VM shutdown"));
+                    }
+                })
+                .inspect_err(|err| self.state.fail(err))?;
+        }
 
         // Run the workload.
-        if unsafe { krun_start_enter(self.id) } < 0 {
-            return Err(anyhow!("unable to begin running krun workload"));
+        if self.backend.start_enter(self.id) < 0 {
+            let err = anyhow!("unable to begin running krun workload");
+            self.state.fail(&err);
+
+            return Err(err);
         }
 
+        // libkrun normally exits the process when the guest shuts down. Record the stop in case
+        // it returns instead.
+        self.state.transition(VmStatus::Stopped)?;
+
         Ok(())
     }
+
+    /// File descriptor of the eventfd shutting down the VM when written to.
+    fn shutdown_eventfd(&self) -> Result<RawFd, anyhow::Error> {
+        let fd = self.backend.get_shutdown_eventfd(self.id);
+        if fd < 0 {
+            return Err(anyhow!("unable to retrieve krun shutdown file descriptor"));
+        }
+
+        Ok(fd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        backend::mock::{Call, MockBackend},
+        test_util::{esp_disk, temp_path},
+    };
+
+    use clap::Parser;
+
+    fn context(cmdline: &[&str], backend: &Arc<MockBackend>) -> Result<KrunContext, anyhow::Error> {
+        let vstore = temp_path("vstore");
+        let bootloader = format!("efi,variable-store={},create", vstore.display());
+        let args = Args::try_parse_from(
+            ["krunkit", "--bootloader", &bootloader]
+                .iter()
+                .chain(cmdline),
+        )?;
+
+        let ctx = KrunContext::new(args, backend.clone());
+        let _ = std::fs::remove_file(&vstore);
+
+        ctx
+    }
+
+    /// A raw disk image in the temporary directory, bootable by the EFI firmware.
+    fn raw_disk(name: &str) -> String {
+        let path = temp_path(name);
+        std::fs::write(&path, esp_disk(true)).unwrap();
+
+        path.display().to_string()
+    }
+
+    #[test]
+    fn cmdline_to_libkrun_calls() {
+        let disk = raw_disk("disk.img");
+        let backend = Arc::new(MockBackend::default());
+        let ctx = context(
+            &[
+                "--cpus",
+                "2",
+                "--memory",
+                "2048",
+                "--device",
+                &format!("virtio-blk,path={}", disk),
+                "--device",
+                "virtio-vsock,port=1024,socketURL=/tmp/vsock.sock,connect",
+                "--device",
+                "virtio-net,unixSocketPath=/tmp/net.sock,mac=00:11:22:33:44:55",
+                "--device",
+                "virtio-fs,sharedDir=/tmp/shared,mountTag=shared",
+                "--device",
+                "virtio-rng",
+            ],
+            &backend,
+        );
+        std::fs::remove_file(&disk).unwrap();
+        ctx.unwrap();
+
+        assert_eq!(
+            backend.calls(),
+            vec![
+                Call::CreateCtx,
+                Call::SetVmConfig(0, 2, 2048),
+                Call::AddDisk3(0, "root".into(), disk, 0, false, false, 1),
+                Call::AddVsockPort2(0, 1024, "/tmp/vsock.sock".into(), true),
+                Call::SetGvproxyPath(0, "/tmp/net.sock".into()),
+                Call::SetNetMac(0, [0x00, 0x11, 0x22, 0x33, 0x44, 0x55]),
+                Call::AddVirtiofs(0, "shared".into(), "/tmp/shared".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn linux_kernel_boot() {
+        let kernel = temp_path("kernel.elf");
+        std::fs::write(&kernel, b"\x7fELF\x02\x01\x01").unwrap();
+
+        let backend = Arc::new(MockBackend::default());
+        let args = Args::try_parse_from([
+            "krunkit",
+            "--cpus",
+            "1",
+            "--memory",
+            "512",
+            "--bootloader",
+            &format!("linux,kernel={},cmdline=console=hvc0", kernel.display()),
+        ])
+        .unwrap();
+        let ctx = KrunContext::new(args, backend.clone());
+        std::fs::remove_file(&kernel).unwrap();
+        ctx.unwrap();
+
+        assert_eq!(
+            backend.calls(),
+            vec![
+                Call::CreateCtx,
+                Call::SetVmConfig(0, 1, 512),
+                Call::SetKernel(
+                    0,
+                    kernel.display().to_string(),
+                    1,
+                    None,
+                    Some("console=hvc0".into())
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn multiple_disks() {
+        let (data, root, scratch) = (
+            raw_disk("data.img"),
+            raw_disk("root.img"),
+            raw_disk("scratch.img"),
+        );
+        let backend = Arc::new(MockBackend::default());
+        let ctx = context(
+            &[
+                "--cpus",
+                "1",
+                "--memory",
+                "512",
+                "--device",
+                &format!("virtio-blk,path={},readonly", data),
+                "--device",
+                "virtio-fs,sharedDir=/tmp/shared,mountTag=shared",
+                "--device",
+                &format!("virtio-blk,path={},root,format=raw", root),
+                "--device",
+                &format!("virtio-blk,path={},deviceId=scratch", scratch),
+            ],
+            &backend,
+        );
+        for disk in [&data, &root, &scratch] {
+            std::fs::remove_file(disk).unwrap();
+        }
+        ctx.unwrap();
+
+        assert_eq!(
+            backend.calls()[2..],
+            [
+                Call::AddDisk3(0, "root".into(), root, 0, false, false, 1),
+                Call::AddDisk3(0, "data1".into(), data, 0, true, false, 1),
+                Call::AddVirtiofs(0, "shared".into(), "/tmp/shared".into()),
+                Call::AddDisk3(0, "scratch".into(), scratch, 0, false, false, 1),
+            ]
+        );
+
+        // Only one disk can be the root disk.
+        let err = context(
+            &[
+                "--cpus",
+                "1",
+                "--memory",
+                "512",
+                "--device",
+                "virtio-blk,path=/tmp/a.img,root",
+                "--device",
+                "virtio-blk,path=/tmp/b.img,root",
+            ],
+            &Arc::new(MockBackend::default()),
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "only one virtio-blk disk can be the root disk, found /tmp/a.img, /tmp/b.img"
+        );
+
+        // Disk IDs must be unique.
+        assert!(context(
+            &[
+                "--cpus",
+                "1",
+                "--memory",
+                "512",
+                "--device",
+                "virtio-blk,path=/tmp/a.img,deviceId=x",
+                "--device",
+                "virtio-blk,path=/tmp/b.img,deviceId=x",
+            ],
+            &Arc::new(MockBackend::default()),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn disk_io_options() {
+        let (a, b, c) = (raw_disk("a.img"), raw_disk("b.img"), raw_disk("c.img"));
+        let backend = Arc::new(MockBackend::default());
+        let ctx = context(
+            &[
+                "--cpus",
+                "1",
+                "--memory",
+                "512",
+                "--device",
+                &format!("virtio-blk,path={},cache=none,sync=full", a),
+                "--device",
+                &format!("virtio-blk,path={},cache=unsafe", b),
+                "--device",
+                &format!("virtio-blk,path={},cache=writeback,sync=none", c),
+            ],
+            &backend,
+        );
+        drop(ctx);
+        let conflicting = context(
+            &[
+                "--cpus",
+                "1",
+                "--memory",
+                "512",
+                "--device",
+                &format!("virtio-blk,path={},cache=unsafe,sync=full", a),
+            ],
+            &Arc::new(MockBackend::default()),
+        );
+        for disk in [&a, &b, &c] {
+            std::fs::remove_file(disk).unwrap();
+        }
+
+        assert_eq!(
+            backend.calls()[2..],
+            [
+                Call::AddDisk3(0, "root".into(), a.clone(), 0, false, true, 2),
+                Call::AddDisk3(0, "data1".into(), b, 0, false, false, 0),
+                Call::AddDisk3(0, "data2".into(), c, 0, false, false, 0),
+            ]
+        );
+        assert_eq!(
+            conflicting.err().unwrap().to_string(),
+            format!(
+                "virtio-blk disk {} cannot use both cache=unsafe and sync=full",
+                a
+            )
+        );
+        assert!("virtio-blk,path=/a.img,cache=always"
+            .parse::<virtio::VirtioDeviceConfig>()
+            .is_err());
+    }
+
+    #[test]
+    fn ephemeral_overlays() {
+        let (root, data) = (raw_disk("root.img"), raw_disk("data.img"));
+        let backend = Arc::new(MockBackend::default());
+        let ctx = context(
+            &[
+                "--cpus",
+                "1",
+                "--memory",
+                "512",
+                "--ephemeral",
+                "--device",
+                &format!("virtio-blk,path={}", root),
+                "--device",
+                &format!("virtio-blk,path={},readonly", data),
+            ],
+            &backend,
+        );
+        let base = std::fs::read(&root).unwrap();
+        for disk in [&root, &data] {
+            std::fs::remove_file(disk).unwrap();
+        }
+        ctx.unwrap();
+
+        // The writable disk is replaced by an overlay, the read-only one is attached as is.
+        let calls = backend.calls();
+        let Call::AddDisk3(_, id, overlay, format, false, _, _) = &calls[2] else {
+            panic!("expected root disk, found {:?}", calls[2]);
+        };
+        assert_eq!((id.as_str(), *format), ("root", 1));
+        assert_ne!(overlay, &root);
+        assert!(std::path::Path::new(overlay).is_file());
+        assert_eq!(
+            calls[3],
+            Call::AddDisk3(0, "data1".into(), data, 0, true, false, 1)
+        );
+        assert_eq!(base, esp_disk(true));
+
+        std::fs::remove_file(overlay).unwrap();
+    }
+
+    #[test]
+    fn disks_locked() {
+        let (disk, shared) = (raw_disk("disk.img"), raw_disk("shared.img"));
+        let cmdline = |disk_args: &str| {
+            context(
+                &[
+                    "--cpus",
+                    "1",
+                    "--memory",
+                    "512",
+                    "--device",
+                    &format!("virtio-blk,path={}", disk_args),
+                    "--device",
+                    &format!("virtio-blk,path={},readonly", shared),
+                ],
+                &Arc::new(MockBackend::default()),
+            )
+        };
+
+        let first = cmdline(&disk);
+        let second = cmdline(&disk);
+        drop(first);
+        let after_exit = cmdline(&disk);
+        let writing_shared = cmdline(&shared);
+
+        for disk in [&disk, &shared] {
+            std::fs::remove_file(disk).unwrap();
+        }
+
+        // Read-only disks are shared, writable ones are exclusive to one VM.
+        assert_eq!(
+            second.err().unwrap().to_string(),
+            format!(
+                "disk image {} is in use by process {}",
+                disk,
+                std::process::id()
+            )
+        );
+        assert!(after_exit.is_ok());
+        assert!(writing_shared.is_err());
+    }
+
+    #[test]
+    fn serial_log() {
+        let log = temp_path("serial.log");
+        let backend = Arc::new(MockBackend::default());
+        let serial = format!("virtio-serial,logFilePath={}", log.display());
+        let ctx = context(
+            &["--cpus", "1", "--memory", "512", "--device", &serial],
+            &backend,
+        );
+        let created = log.exists();
+        std::fs::remove_file(&log).unwrap();
+        ctx.unwrap();
+
+        assert!(created);
+        assert_eq!(
+            backend.calls()[2..],
+            [Call::SetConsoleOutput(0, log.display().to_string())]
+        );
+
+        let err = context(
+            &[
+                "--cpus",
+                "1",
+                "--memory",
+                "512",
+                "--device",
+                "virtio-serial,logFilePath=/nonexistent/serial.log",
+            ],
+            &Arc::new(MockBackend::default()),
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "unable to create virtio-serial log file /nonexistent/serial.log"
+        );
+
+        // The console can be attached to a pseudo-terminal, whose path is inspectable.
+        let backend = Arc::new(MockBackend::default());
+        let ctx = context(
+            &[
+                "--cpus",
+                "1",
+                "--memory",
+                "512",
+                "--device",
+                "virtio-serial,pty",
+            ],
+            &backend,
+        )
+        .unwrap();
+        let calls = backend.calls();
+        assert_eq!(calls[2], Call::DisableImplicitConsole(0));
+        assert!(matches!(calls[3], Call::AddVirtioConsoleDefault(0, fd, out, _) if fd == out));
+
+        let devices = serde_json::to_value(&ctx.args.devices).unwrap();
+        let pty = devices[0]["ptyPath"].as_str().unwrap();
+        assert!(std::path::Path::new(pty).exists());
+
+        // Rotated or timestamped logs are written by krunkit, through a console.
+        let backend = Arc::new(MockBackend::default());
+        let rotated = format!("{},maxSize=1M,maxFiles=3,timestamps=true", serial);
+        let ctx = context(
+            &["--cpus", "1", "--memory", "512", "--device", &rotated],
+            &backend,
+        );
+        let created = log.exists();
+        std::fs::remove_file(&log).unwrap();
+        ctx.unwrap();
+
+        assert!(created);
+        let calls = backend.calls();
+        assert_eq!(calls[2], Call::DisableImplicitConsole(0));
+        assert!(matches!(calls[3], Call::AddVirtioConsoleDefault(0, fd, out, _) if fd != out));
+        assert!(!calls
+            .iter()
+            .any(|c| matches!(c, Call::SetConsoleOutput(..))));
+
+        for invalid in [
+            "virtio-serial,stdio,timestamps=true",
+            "virtio-serial,logFilePath=serial.log,maxFiles=2",
+            "virtio-serial,logFilePath=serial.log,maxSize=0",
+            "virtio-serial,logFilePath=serial.log,maxSize=1X",
+            "virtio-serial,logFilePath=serial.log,timestamps=yes",
+        ] {
+            assert!(context(
+                &["--cpus", "1", "--memory", "512", "--device", invalid],
+                &Arc::new(MockBackend::default()),
+            )
+            .is_err());
+        }
+
+        // libkrun has a single console.
+        assert!(context(
+            &["--cpus", "1", "--memory", "512", "--device", &serial, "--device", &serial,],
+            &Arc::new(MockBackend::default()),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn rng_source() {
+        // libkrun provides the device itself.
+        let backend = Arc::new(MockBackend::default());
+        context(
+            &["--cpus", "1", "--memory", "512", "--device", "virtio-rng"],
+            &backend,
+        )
+        .unwrap();
+        assert_eq!(backend.calls()[2..], []);
+
+        let err = "virtio-rng,source=/dev/urandom"
+            .parse::<virtio::VirtioDeviceConfig>()
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "virtio-rng source is not configurable, libkrun provides the entropy source"
+        );
+        let err = toml::from_str::<virtio::VirtioDeviceConfig>(
+            "kind = \"virtio-rng\"\nsource = \"/dev/urandom\"\n",
+        )
+        .err()
+        .unwrap();
+        assert!(err
+            .to_string()
+            .contains("virtio-rng source is not configurable"));
+
+        assert!("virtio-rng,entropy=/dev/random"
+            .parse::<virtio::VirtioDeviceConfig>()
+            .is_err());
+    }
+
+    #[test]
+    fn vsock_actions() {
+        let socket = temp_path("vsock.sock");
+        let vsock = |action: &str| {
+            let backend = Arc::new(MockBackend::default());
+            let device = format!(
+                "virtio-vsock,port=1024,socketURL={},{}",
+                socket.display(),
+                action
+            );
+            context(
+                &["--cpus", "1", "--memory", "512", "--device", &device],
+                &backend,
+            )
+            .map(|_| backend.calls()[2..].to_vec())
+        };
+        let path = socket.display().to_string();
+
+        // Listen sockets must be served by a host process, listen being the default action.
+        let err = vsock("listen").err().unwrap();
+        assert_eq!(err.to_string(), format!("vsock socket {} not found", path));
+
+        let listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+        assert_eq!(
+            vsock("listen").unwrap(),
+            [Call::AddVsockPort2(0, 1024, path.clone(), false)]
+        );
+        let default = format!("virtio-vsock,port=1024,socketURL={}", path);
+        let backend = Arc::new(MockBackend::default());
+        context(
+            &["--cpus", "1", "--memory", "512", "--device", &default],
+            &backend,
+        )
+        .unwrap();
+        assert_eq!(
+            backend.calls()[2..],
+            [Call::AddVsockPort2(0, 1024, path.clone(), false)]
+        );
+
+        // Connect sockets are created by libkrun, replacing stale ones.
+        let err = vsock("connect").err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            format!("vsock socket {} is in use by another process", path)
+        );
+
+        drop(listener);
+        assert!(socket.exists());
+        assert_eq!(
+            vsock("connect").unwrap(),
+            [Call::AddVsockPort2(0, 1024, path.clone(), true)]
+        );
+        assert!(!socket.exists());
+
+        std::fs::write(&socket, b"").unwrap();
+        let listen = vsock("listen");
+        let connect = vsock("connect");
+        std::fs::remove_file(&socket).unwrap();
+        assert!(listen.is_err());
+        assert!(connect.is_err());
+    }
+
+    #[test]
+    fn vsock_validation() {
+        let vsock = |port: u32, socket: &str| {
+            format!("virtio-vsock,port={},socketURL={},connect", port, socket)
+        };
+        let check = |devices: &[String]| {
+            let mut args = vec!["--cpus", "1", "--memory", "512"];
+            for device in devices {
+                args.extend(["--device", device]);
+            }
+            context(&args, &Arc::new(MockBackend::default()))
+                .err()
+                .map(|e| e.to_string())
+        };
+
+        let a = temp_path("a.sock").display().to_string();
+        let b = temp_path("b.sock").display().to_string();
+
+        assert_eq!(check(&[vsock(1024, &a), vsock(1025, &b)]), None);
+        assert_eq!(
+            check(&[vsock(1024, &a), vsock(1024, &b)]).unwrap(),
+            "vsock port 1024 is used by more than one virtio-vsock device"
+        );
+        assert_eq!(
+            check(&[vsock(1024, &a), vsock(1025, &a)]).unwrap(),
+            format!(
+                "vsock socket {} is used by more than one virtio-vsock device",
+                a
+            )
+        );
+        for reserved in [0, 1, 2, u32::MAX] {
+            assert_eq!(
+                check(&[vsock(reserved, &a)]).unwrap(),
+                format!("vsock port {} is reserved", reserved)
+            );
+        }
+
+        // Socket paths are compared once normalized.
+        let a_path = std::path::Path::new(&a);
+        let dir = a_path.parent().unwrap();
+        let name = a_path.file_name().unwrap().to_str().unwrap();
+        let dotted = format!("{}/./sub/../{}", dir.display(), name);
+        assert!(check(&[vsock(1024, &a), vsock(1025, &dotted)]).is_some());
+        let cwd = std::env::current_dir().unwrap();
+        let absolute = cwd.join("vsock.sock").display().to_string();
+        assert!(check(&[vsock(1024, "vsock.sock"), vsock(1025, &absolute)]).is_some());
+    }
+
+    #[test]
+    fn validated_before_creating() {
+        let log = temp_path("serial.log");
+        let serial = format!("virtio-serial,logFilePath={},maxFiles=2", log.display());
+        let socket = temp_path("ssh.sock");
+        let listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+        let vsock = format!("virtio-vsock,port=22,socketURL={},listen", socket.display());
+        let missing = format!(
+            "virtio-vsock,port=22,socketURL={},listen",
+            temp_path("missing.sock").display()
+        );
+
+        for (device, forward) in [
+            (serial.as_str(), None),
+            (vsock.as_str(), Some("tcp:127.0.0.1:2222=vsock:22")),
+            (
+                "virtio-vsock,port=1,socketURL=/tmp/vsock.sock,connect",
+                None,
+            ),
+            (missing.as_str(), None),
+        ] {
+            let mut args = vec!["--cpus", "1", "--memory", "512", "--device", device];
+            args.extend(forward.iter().flat_map(|f| ["--forward", *f]));
+
+            let backend = Arc::new(MockBackend::default());
+            assert!(context(&args, &backend).is_err());
+            assert_eq!(backend.calls(), []);
+        }
+        drop(listener);
+        std::fs::remove_file(&socket).unwrap();
+        assert!(!log.exists());
+    }
+
+    #[test]
+    fn secure_boot_rejected() {
+        let vstore = temp_path("vstore");
+        let bootloader = format!(
+            "efi,variable-store={},create,secure-boot=/tmp/sb-keys",
+            vstore.display()
+        );
+        let args = Args::try_parse_from([
+            "krunkit",
+            "--cpus",
+            "1",
+            "--memory",
+            "512",
+            "--bootloader",
+            &bootloader,
+        ])
+        .unwrap();
+
+        let backend = Arc::new(MockBackend::default());
+        let err = KrunContext::new(args, backend.clone()).err().unwrap();
+
+        assert!(err.to_string().contains("would boot without Secure Boot"));
+        assert_eq!(backend.calls(), []);
+        assert!(!vstore.exists());
+    }
+
+    #[test]
+    fn boot_check() {
+        let disk = temp_path("disk.img");
+        std::fs::write(&disk, esp_disk(false)).unwrap();
+        let device = format!("virtio-blk,path={}", disk.display());
+        let args = ["--cpus", "1", "--memory", "512", "--device", &device];
+
+        let checked = context(&args, &Arc::new(MockBackend::default()));
+        let unchecked = context(
+            &[&args[..], &["--no-boot-check"]].concat(),
+            &Arc::new(MockBackend::default()),
+        );
+        std::fs::remove_file(&disk).unwrap();
+
+        if crate::bootloader::default_loader().is_some() {
+            assert!(checked.err().unwrap().to_string().contains("has no"));
+        }
+        unchecked.unwrap();
+    }
+
+    #[test]
+    fn zero_cpus_rejected() {
+        let backend = Arc::new(MockBackend::default());
+        assert!(context(&["--cpus", "0", "--memory", "2048"], &backend).is_err());
+        assert_eq!(backend.calls(), vec![Call::CreateCtx]);
+    }
+
+    #[test]
+    fn backend_failure_reported() {
+        let disk = raw_disk("disk.img");
+        let backend = Arc::new(MockBackend::failing("AddDisk3"));
+        let err = context(
+            &[
+                "--cpus",
+                "1",
+                "--memory",
+                "512",
+                "--device",
+                &format!("virtio-blk,path={}", disk),
+            ],
+            &backend,
+        )
+        .err()
+        .unwrap();
+        std::fs::remove_file(&disk).unwrap();
+
+        assert_eq!(
+            err.to_string(),
+            format!("unable to add virtio-blk disk {} (root)", disk)
+        );
+
+        // Invalid images are reported before they are attached.
+        let err = context(
+            &[
+                "--cpus",
+                "1",
+                "--memory",
+                "512",
+                "--device",
+                "virtio-blk,path=/nonexistent/disk.qcow2,format=qcow2",
+            ],
+            &backend,
+        )
+        .err()
+        .unwrap();
+
+        assert_eq!(
+            err.to_string(),
+            "invalid disk image /nonexistent/disk.qcow2"
+        );
+    }
+
+    #[test]
+    fn run_without_listener() {
+        let backend = Arc::new(MockBackend::default());
+        let ctx = context(
+            &["--cpus", "1", "--memory", "512", "--restful-uri", "none"],
+            &backend,
+        )
+        .unwrap();
+
+        ctx.run().unwrap();
+
+        assert_eq!(ctx.state.status(), VmStatus::Stopped);
+        assert_eq!(backend.calls().last(), Some(&Call::StartEnter(0)));
+    }
 }
@@ -0,0 +1,424 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    io::{self, IsTerminal, Read, Seek, SeekFrom, Write},
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+    process,
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Size of the chunks in which images are decompressed. Chunks of zeros are skipped rather than
+/// written, leaving holes in the decompressed image.
+const CHUNK_SIZE: usize = 64 << 10;
+
+/// Compression formats of disk images.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+impl Compression {
+    /// Identify the compression of a file from its header, None if it is not compressed.
+    pub fn detect(path: &Path) -> Result<Option<Self>> {
+        let mut header = [0u8; 6];
+        let len = File::open(path)
+            .and_then(|mut file| file.read(&mut header))
+            .context(format!("invalid disk image {}", path.display()))?;
+        let header = &header[..len];
+
+        if header.starts_with(b"\xfd7zXZ\0") {
+            Ok(Some(Self::Xz))
+        } else if header.starts_with(&[0x1f, 0x8b]) {
+            Ok(Some(Self::Gzip))
+        } else if header.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Ok(Some(Self::Zstd))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Reader of the decompressed contents of a compressed stream.
+    fn decoder<'a>(&self, input: impl Read + 'a) -> Result<Box<dyn Read + 'a>> {
+        let input = io::BufReader::new(input);
+
+        Ok(match self {
+            Self::Gzip => Box::new(flate2::bufread::MultiGzDecoder::new(input)),
+            Self::Xz => Box::new(xz2::bufread::XzDecoder::new_multi_decoder(input)),
+            Self::Zstd => Box::new(zstd::Decoder::with_buffer(input)?),
+        })
+    }
+}
+
+/// Path of the decompressed copy of an image in the cache, decompressing it if the cache has
+/// no copy yet. Returns None if the image is not compressed.
+///
+/// Copies are named after the SHA-256 checksum of the compressed image, so a copy is reused
+/// as long as the compressed image is unchanged. The path, size and modification time of the
+/// compressed image are recorded alongside the copy, so that the image is only hashed again
+/// when they change. Copies are read-only, as they may be shared between VMs.
+pub fn cached_image(path: &Path) -> Result<Option<PathBuf>> {
+    match Compression::detect(path)? {
+        Some(compression) => Ok(Some(cache(path, compression, &cache_dir()?)?)),
+        None => Ok(None),
+    }
+}
+
+/// Directory of the decompressed images: $XDG_CACHE_HOME/krunkit/images, defaulting to
+/// ~/Library/Caches on macOS and ~/.cache elsewhere.
+fn cache_dir() -> Result<PathBuf> {
+    let base = match env::var_os("XDG_CACHE_HOME").filter(|d| !d.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            let home = env::var_os("HOME")
+                .ok_or_else(|| anyhow!("HOME is not set, unable to locate the image cache"))?;
+
+            if cfg!(target_os = "macos") {
+                Path::new(&home).join("Library/Caches")
+            } else {
+                Path::new(&home).join(".cache")
+            }
+        }
+    };
+
+    Ok(base.join("krunkit").join("images"))
+}
+
+/// Compressed image a cached copy was decompressed from, recorded in a "<checksum>.source" file
+/// next to the copy.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+struct Source {
+    path: PathBuf,
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+}
+
+impl Source {
+    fn new(path: &Path) -> Result<Self> {
+        let path = fs::canonicalize(path)
+            .context(format!("unable to open disk image {}", path.display()))?;
+        let metadata = fs::metadata(&path)?;
+
+        Ok(Self {
+            path,
+            size: metadata.len(),
+            mtime: metadata.mtime(),
+            mtime_nsec: metadata.mtime_nsec(),
+        })
+    }
+
+    /// Cached copy of the image, if recorded and still present, without hashing the image.
+    fn find(&self, dir: &Path) -> Option<PathBuf> {
+        fs::read_dir(dir).ok()?.flatten().find_map(|entry| {
+            let record = entry.path();
+            if record.extension()? != "source" {
+                return None;
+            }
+
+            let source: Self = serde_json::from_slice(&fs::read(&record).ok()?).ok()?;
+            let cached = record.with_extension("");
+            (source == *self && cached.exists()).then_some(cached)
+        })
+    }
+
+    /// Record the image as the source of a cached copy. Failures only cost a hash next time.
+    fn record(&self, cached: &Path) {
+        if let Ok(json) = serde_json::to_vec(self) {
+            let _ = fs::write(cached.with_extension("source"), json);
+        }
+    }
+}
+
+/// Decompress an image into a cache directory, unless the directory already holds it.
+fn cache(path: &Path, compression: Compression, dir: &Path) -> Result<PathBuf> {
+    let source = Source::new(path)?;
+    if let Some(cached) = source.find(dir) {
+        return Ok(cached);
+    }
+
+    let checksum = sha256(path)?;
+    let cached = dir.join(&checksum);
+    if cached.exists() {
+        source.record(&cached);
+        return Ok(cached);
+    }
+
+    fs::create_dir_all(dir).context(format!(
+        "unable to create image cache directory {}",
+        dir.display()
+    ))?;
+
+    // Decompress to a temporary file first, so that an interrupted decompression is never
+    // mistaken for a complete image.
+    let partial = dir.join(format!("{}.partial-{}", checksum, process::id()));
+    let result = decompress(path, compression, &partial).and_then(|()| {
+        fs::set_permissions(&partial, fs::Permissions::from_mode(0o444))?;
+        fs::rename(&partial, &cached)?;
+
+        Ok(())
+    });
+
+    if let Err(err) = result {
+        let _ = fs::remove_file(&partial);
+
+        return Err(err.context(format!(
+            "unable to decompress disk image {}",
+            path.display()
+        )));
+    }
+
+    source.record(&cached);
+
+    Ok(cached)
+}
+
+/// SHA-256 checksum of a file, as a hexadecimal string.
+fn sha256(path: &Path) -> Result<String> {
+    let mut file =
+        File::open(path).context(format!("unable to open disk image {}", path.display()))?;
+
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)
+        .context(format!("unable to read disk image {}", path.display()))?;
+
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// Decompress an image into a new file, leaving holes for the chunks of zeros and reporting
+/// the progress on stderr.
+fn decompress(path: &Path, compression: Compression, dest: &Path) -> Result<()> {
+    let input = File::open(path)?;
+    let mut progress = Progress::new(path, input.metadata()?.len());
+    let mut decoder = compression.decoder(CountingReader {
+        inner: input,
+        progress: &mut progress,
+    })?;
+
+    let mut output = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(dest)
+        .context(format!("unable to create {}", dest.display()))?;
+
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    let mut size = 0u64;
+    loop {
+        let len = read_chunk(&mut decoder, &mut chunk)?;
+        if len == 0 {
+            break;
+        }
+
+        if chunk[..len].iter().all(|b| *b == 0) {
+            output.seek(SeekFrom::Current(len as i64))?;
+        } else {
+            output.write_all(&chunk[..len])?;
+        }
+        size += len as u64;
+    }
+    drop(decoder);
+
+    // Trailing holes are only part of the file once its size is set.
+    output.set_len(size)?;
+    output.sync_all()?;
+    progress.finish(size);
+
+    Ok(())
+}
+
+/// Fill a buffer from a reader, stopping short only at the end of the stream.
+fn read_chunk(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(len)
+}
+
+/// Reader of the compressed image, reporting how much of it was read.
+struct CountingReader<'a> {
+    inner: File,
+    progress: &'a mut Progress,
+}
+
+impl Read for CountingReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.progress.advance(len as u64);
+
+        Ok(len)
+    }
+}
+
+/// Progress of a decompression, as the percentage of the compressed image read. It is updated
+/// in place on terminals, and only the start and end are reported otherwise.
+struct Progress {
+    name: String,
+    total: u64,
+    read: u64,
+    percent: u64,
+    terminal: bool,
+}
+
+impl Progress {
+    fn new(path: &Path, total: u64) -> Self {
+        let terminal = io::stderr().is_terminal();
+        let name = path.display().to_string();
+        if !terminal {
+            eprintln!("Decompressing {}", name);
+        }
+
+        Self {
+            name,
+            total,
+            read: 0,
+            percent: 0,
+            terminal,
+        }
+    }
+
+    fn advance(&mut self, len: u64) {
+        self.read += len;
+
+        let percent = (self.read * 100).checked_div(self.total).unwrap_or(100);
+        if self.terminal && percent != self.percent {
+            eprint!("\rDecompressing {}: {}%", self.name, percent.min(100));
+        }
+        self.percent = percent;
+    }
+
+    fn finish(&self, size: u64) {
+        if self.terminal {
+            eprintln!();
+        }
+        eprintln!("Decompressed {} ({} MiB)", self.name, size >> 20);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_util::temp_path;
+
+    use std::os::unix::fs::MetadataExt;
+
+    /// A 4 MiB disk image with data only at its start and end.
+    fn image() -> Vec<u8> {
+        let mut image = vec![0u8; 4 << 20];
+        image[..512].fill(0xaa);
+        image[(4 << 20) - 512..].fill(0x55);
+
+        image
+    }
+
+    fn compress(compression: Compression, data: &[u8]) -> Vec<u8> {
+        match compression {
+            Compression::Gzip => {
+                let mut enc =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+                enc.write_all(data).unwrap();
+                enc.finish().unwrap()
+            }
+            Compression::Xz => {
+                let mut enc = xz2::write::XzEncoder::new(Vec::new(), 1);
+                enc.write_all(data).unwrap();
+                enc.finish().unwrap()
+            }
+            Compression::Zstd => zstd::encode_all(data, 1).unwrap(),
+        }
+    }
+
+    #[test]
+    fn decompress_to_cache() {
+        let dir = temp_path("cache");
+        let image = image();
+
+        for compression in [Compression::Gzip, Compression::Xz, Compression::Zstd] {
+            let path = temp_path("disk.img.compressed");
+            fs::write(&path, compress(compression, &image)).unwrap();
+
+            let detected = Compression::detect(&path).unwrap();
+            let cached = cache(&path, compression, &dir).unwrap();
+            let metadata = fs::metadata(&cached).unwrap();
+            let contents = fs::read(&cached).unwrap();
+
+            // The cached copy is reused while the compressed image is unchanged.
+            let modified = fs::metadata(&cached).unwrap().modified().unwrap();
+            let reused = cache(&path, compression, &dir).unwrap();
+
+            // Without rehashing the image while its size and modification time are unchanged.
+            let image_mtime = fs::metadata(&path).unwrap().modified().unwrap();
+            let mut other = compress(compression, &image);
+            other.iter_mut().skip(64).for_each(|b| *b = !*b);
+            fs::write(&path, &other).unwrap();
+            File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(image_mtime)
+                .unwrap();
+            let unchanged = cache(&path, compression, &dir).unwrap();
+
+            fs::remove_file(&path).unwrap();
+
+            assert_eq!(detected, Some(compression));
+            assert_eq!(cached.file_name().unwrap().len(), 64);
+            assert!(contents == image);
+            assert!(metadata.permissions().readonly());
+            assert!(metadata.blocks() * 512 < metadata.len());
+            assert_eq!(reused, cached);
+            assert_eq!(fs::metadata(&reused).unwrap().modified().unwrap(), modified);
+            assert_eq!(unchanged, cached);
+        }
+
+        let raw = temp_path("disk.img");
+        fs::write(&raw, image).unwrap();
+        let detected = Compression::detect(&raw);
+        fs::remove_file(&raw).unwrap();
+        assert_eq!(detected.unwrap(), None);
+
+        // One copy per compressed image, each with its source.
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 6);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupted_image_not_cached() {
+        let dir = temp_path("cache");
+        let path = temp_path("disk.img.xz");
+        let mut compressed = compress(Compression::Xz, &image());
+        let len = compressed.len();
+        compressed[len / 2..].fill(0);
+        fs::write(&path, compressed).unwrap();
+
+        let err = cache(&path, Compression::Xz, &dir);
+        let entries = fs::read_dir(&dir).unwrap().count();
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            err.err().unwrap().to_string(),
+            format!("unable to decompress disk image {}", path.display())
+        );
+        assert_eq!(entries, 0);
+    }
+}
@@ -0,0 +1,304 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    cmdline::size_parse,
+    image::{self, Format, Image},
+    lock::{FileLock, LockMode},
+    partition::PartitionTable,
+};
+
+use std::{os::unix::fs::MetadataExt, path::PathBuf, str::FromStr};
+
+use anyhow::{anyhow, Context, Result};
+use clap::Subcommand;
+use serde_json::{json, Value};
+
+/// Arguments of the disk subcommand.
+#[derive(Clone, Debug, clap::Args)]
+pub struct DiskArgs {
+    #[command(subcommand)]
+    action: DiskAction,
+}
+
+/// Operations on disk images.
+#[derive(Clone, Debug, Subcommand)]
+enum DiskAction {
+    /// Create an empty disk image.
+    Create {
+        /// Path of the image to create.
+        path: PathBuf,
+
+        /// Virtual size of the disk (e.g. 20G), defaulting to the size of the backing file.
+        #[arg(long)]
+        size: Option<Size>,
+
+        /// Format of the image (raw or qcow2), defaulting to qcow2 for .qcow2 files and raw
+        /// otherwise.
+        #[arg(long)]
+        format: Option<Format>,
+
+        /// Image backing a qcow2 image, which then only records the changes made to it.
+        #[arg(long)]
+        backing: Option<PathBuf>,
+    },
+
+    /// Change the virtual size of a disk image.
+    Resize {
+        /// Path of the image.
+        path: PathBuf,
+
+        /// New virtual size of the disk (e.g. 40G), or the size to grow it by (e.g. +10G).
+        #[arg(long, allow_hyphen_values = true)]
+        size: NewSize,
+
+        /// Format of the image.
+        #[arg(long, default_value_t = Format::Auto)]
+        format: Format,
+
+        /// Allow shrinking a raw image, discarding the data past the new size.
+        #[arg(long)]
+        shrink: bool,
+    },
+
+    /// Print the format, sizes, backing files and partition table of a disk image as JSON.
+    Info {
+        /// Path of the image.
+        path: PathBuf,
+
+        /// Format of the image.
+        #[arg(long, default_value_t = Format::Auto)]
+        format: Format,
+    },
+}
+
+/// A size in bytes, given with an optional binary unit suffix (K, M, G or T).
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Size(u64);
+
+impl FromStr for Size {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let size = size_parse(s)?;
+
+        if size == 0 || !size.is_multiple_of(512) {
+            return Err(anyhow!(
+                "size {} must be a non-zero multiple of 512 bytes",
+                s
+            ));
+        }
+
+        Ok(Self(size))
+    }
+}
+
+/// New size of a resized disk.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum NewSize {
+    Absolute(u64),
+    Grow(u64),
+}
+
+impl FromStr for NewSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix('+') {
+            Some(grow) => Ok(Self::Grow(Size::from_str(grow)?.0)),
+            None => Ok(Self::Absolute(Size::from_str(s)?.0)),
+        }
+    }
+}
+
+impl DiskArgs {
+    pub fn run(&self) -> Result<()> {
+        match &self.action {
+            DiskAction::Create {
+                path,
+                size,
+                format,
+                backing,
+            } => {
+                let format = format.unwrap_or(match path.extension() {
+                    Some(ext) if ext == "qcow2" => Format::Qcow2,
+                    _ => Format::Raw,
+                });
+
+                let backing = backing
+                    .as_deref()
+                    .map(|b| Image::open(b, Format::Auto))
+                    .transpose()?;
+
+                let size = match (size, &backing) {
+                    (Some(size), _) => size.0,
+                    (None, Some(backing)) => backing.virtual_size,
+                    (None, None) => return Err(anyhow!("--size is required")),
+                };
+
+                match format {
+                    Format::Qcow2 => image::create_qcow2(path, size, backing.as_ref())?,
+                    Format::Raw if backing.is_none() => image::create_raw(path, size)?,
+                    Format::Raw => {
+                        return Err(anyhow!("only qcow2 images can have a backing file"))
+                    }
+                    Format::Auto => return Err(anyhow!("the format of a new image must be given")),
+                }
+            }
+            DiskAction::Resize {
+                path,
+                size,
+                format,
+                shrink,
+            } => {
+                // A running VM must not see its disk change size.
+                let _lock = FileLock::new(path, LockMode::Exclusive, "disk image")?;
+                let image = Image::open(path, *format)?;
+                let size = match size {
+                    NewSize::Absolute(size) => *size,
+                    NewSize::Grow(grow) => image
+                        .virtual_size
+                        .checked_add(*grow)
+                        .ok_or_else(|| anyhow!("size too large"))?,
+                };
+
+                image.resize(size, *shrink)?;
+            }
+            DiskAction::Info { path, format } => {
+                let image = Image::open(path, *format)?;
+                println!("{}", serde_json::to_string_pretty(&image_json(&image)?)?);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// JSON description of an image: format, sizes, backing file chain and partition table.
+fn image_json(image: &Image) -> Result<Value> {
+    let allocated = |image: &Image| -> Result<u64> {
+        let metadata = image
+            .path
+            .metadata()
+            .context(format!("unable to read {}", image.path.display()))?;
+
+        Ok(metadata.blocks() * 512)
+    };
+
+    let mut chain = Vec::new();
+    let mut backing = image.backing.as_deref();
+    while let Some(b) = backing {
+        chain.push(json!({
+            "path": b.path,
+            "format": b.format,
+            "virtualSize": b.virtual_size,
+            "allocatedSize": allocated(b)?,
+        }));
+        backing = b.backing.as_deref();
+    }
+
+    let partitions = PartitionTable::read(&image.reader()?).context(format!(
+        "unable to read partition table of {}",
+        image.path.display()
+    ))?;
+
+    Ok(json!({
+        "path": image.path,
+        "format": image.format,
+        "virtualSize": image.virtual_size,
+        "allocatedSize": allocated(image)?,
+        "backingChain": chain,
+        "partitionTable": partitions,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        partition::ESP_TYPE,
+        test_util::{gpt_disk, temp_path},
+    };
+
+    use std::fs;
+
+    fn run(args: &[&str]) -> Result<()> {
+        use clap::Parser;
+
+        #[derive(Parser)]
+        struct Cli {
+            #[command(flatten)]
+            disk: DiskArgs,
+        }
+
+        Cli::try_parse_from(["disk"].iter().chain(args))?.disk.run()
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(Size::from_str("20G").unwrap(), Size(20 << 30));
+        assert_eq!(Size::from_str("512MiB").unwrap(), Size(512 << 20));
+        assert_eq!(Size::from_str("4096").unwrap(), Size(4096));
+        assert!(Size::from_str("100").is_err());
+        assert!(Size::from_str("1X").is_err());
+        assert_eq!(NewSize::from_str("+1G").unwrap(), NewSize::Grow(1 << 30));
+    }
+
+    #[test]
+    fn create_and_resize() {
+        let raw = temp_path("disk.raw");
+        let qcow = temp_path("disk.qcow2");
+        let (raw_s, qcow_s) = (raw.display().to_string(), qcow.display().to_string());
+
+        run(&["create", "--size", "1M", &raw_s]).unwrap();
+        let exists = run(&["create", "--size", "1M", &raw_s]);
+        run(&["resize", "--size", "+1M", &raw_s]).unwrap();
+        let shrink = run(&["resize", "--size", "1M", &raw_s]);
+        let raw_size = fs::metadata(&raw).unwrap().len();
+
+        run(&["create", "--backing", &raw_s, &qcow_s]).unwrap();
+        run(&["resize", "--size", "4G", &qcow_s]).unwrap();
+        let qcow_img = Image::open(&qcow, Format::Auto);
+        let qcow_shrink = run(&["resize", "--size", "1M", &qcow_s]);
+
+        // Disks in use cannot be resized.
+        let lock = FileLock::new(&qcow, LockMode::Shared, "disk image").unwrap();
+        let locked = run(&["resize", "--size", "8G", &qcow_s]);
+        drop(lock);
+
+        fs::remove_file(&raw).unwrap();
+        fs::remove_file(&qcow).unwrap();
+
+        assert!(exists.is_err());
+        assert!(shrink.is_err());
+        assert_eq!(raw_size, 2 << 20);
+
+        let qcow_img = qcow_img.unwrap();
+        assert_eq!(qcow_img.format, Format::Qcow2);
+        assert_eq!(qcow_img.virtual_size, 4 << 30);
+        assert!(qcow_shrink.is_err());
+        assert!(format!("{:#}", locked.unwrap_err()).contains("is in use by process"));
+    }
+
+    #[test]
+    fn info() {
+        let base = temp_path("base.img");
+        let overlay = temp_path("overlay.qcow2");
+        fs::write(&base, gpt_disk(1 << 20, &[(ESP_TYPE, 34, 1023, "EFI")])).unwrap();
+
+        let base_img = Image::open(&base, Format::Auto).unwrap();
+        image::create_qcow2(&overlay, 2 << 20, Some(&base_img)).unwrap();
+        let info = Image::open(&overlay, Format::Auto).and_then(|img| image_json(&img));
+
+        fs::remove_file(&base).unwrap();
+        fs::remove_file(&overlay).unwrap();
+
+        // The partition table is read through the overlay from the backing file.
+        let info = info.unwrap();
+        assert_eq!(info["format"], "qcow2");
+        assert_eq!(info["virtualSize"], 2 << 20);
+        assert_eq!(info["backingChain"][0]["format"], "raw");
+        assert_eq!(info["partitionTable"]["scheme"], "gpt");
+        assert_eq!(info["partitionTable"]["partitions"][0]["name"], "EFI");
+    }
+}
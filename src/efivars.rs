@@ -0,0 +1,335 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    bootloader::Vstore,
+    lock::{FileLock, LockMode},
+    varstore::{Guid, Variable, VariableStore},
+};
+
+use std::{fmt::Write, fs, path::PathBuf, str::FromStr};
+
+use anyhow::{anyhow, Context, Result};
+use clap::Subcommand;
+use serde_json::{json, Value};
+
+/// Vendor GUID of the variables defined by the UEFI specification.
+pub const EFI_GLOBAL_VARIABLE: Guid = Guid::new(
+    0x8be4df61,
+    0x93ca,
+    0x11d2,
+    [0xaa, 0x0d, 0x00, 0xe0, 0x98, 0x03, 0x2b, 0x8c],
+);
+
+/// Variable attributes, with the names used to print and parse them.
+const ATTRIBUTES: [(u32, &str); 7] = [
+    (0x01, "nv"),
+    (0x02, "bs"),
+    (0x04, "rt"),
+    (0x08, "hw-error-record"),
+    (0x10, "authenticated-write-access"),
+    (0x20, "time-based-authenticated-write-access"),
+    (0x40, "append-write"),
+];
+
+/// Arguments of the efivars subcommand.
+#[derive(Clone, Debug, clap::Args)]
+pub struct EfivarsArgs {
+    /// Path of the EFI variable store.
+    #[arg(long = "variable-store", value_name = "PATH")]
+    vstore: Vstore,
+
+    #[command(subcommand)]
+    action: EfivarsAction,
+}
+
+/// Operations on the variables of a variable store.
+#[derive(Clone, Debug, Subcommand)]
+enum EfivarsAction {
+    /// List the variables as JSON.
+    List,
+
+    /// Set a variable, replacing it if it already exists.
+    Set {
+        /// Name of the variable.
+        name: String,
+
+        /// Vendor GUID of the variable.
+        #[arg(long, default_value_t = EFI_GLOBAL_VARIABLE)]
+        guid: Guid,
+
+        /// Comma-separated attributes of the variable (nv, bs, rt, ...) or their numeric value.
+        #[arg(long, default_value = "nv,bs,rt")]
+        attributes: Attributes,
+
+        #[command(flatten)]
+        value: VarValue,
+    },
+
+    /// Delete a variable.
+    Delete {
+        /// Name of the variable.
+        name: String,
+
+        /// Vendor GUID of the variable.
+        #[arg(long, default_value_t = EFI_GLOBAL_VARIABLE)]
+        guid: Guid,
+    },
+}
+
+/// Value to set a variable to.
+#[derive(Clone, Debug, clap::Args)]
+#[group(required = true, multiple = false)]
+struct VarValue {
+    /// Data as a hexadecimal string.
+    #[arg(long)]
+    data: Option<String>,
+
+    /// Path of a file holding the data.
+    #[arg(long)]
+    data_file: Option<PathBuf>,
+
+    /// Data as a string, stored as NULL-terminated UCS-2.
+    #[arg(long)]
+    string: Option<String>,
+
+    /// Comma-separated boot option numbers (e.g. 0001,0000), stored as a BootOrder list.
+    #[arg(long, value_delimiter = ',')]
+    boot_order: Option<Vec<String>>,
+}
+
+impl VarValue {
+    fn to_bytes(&self) -> Result<Vec<u8>> {
+        if let Some(data) = &self.data {
+            return hex_decode(data);
+        }
+
+        if let Some(path) = &self.data_file {
+            return fs::read(path).context(format!("unable to read {}", path.display()));
+        }
+
+        if let Some(string) = &self.string {
+            return Ok(ucs2_encode(string));
+        }
+
+        let mut bytes = Vec::new();
+        for num in self.boot_order.iter().flatten() {
+            let num = u16::from_str_radix(num, 16)
+                .context(format!("invalid boot option number: {}", num))?;
+            bytes.extend_from_slice(&num.to_le_bytes());
+        }
+
+        Ok(bytes)
+    }
+}
+
+/// Variable attributes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Attributes(u32);
+
+impl FromStr for Attributes {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(hex) = s.strip_prefix("0x") {
+            return Ok(Self(
+                u32::from_str_radix(hex, 16).context(format!("invalid attributes: {}", s))?,
+            ));
+        }
+
+        let mut attributes = 0;
+        for name in s.split(',') {
+            let name = name.trim().to_lowercase();
+            let (bit, _) = ATTRIBUTES
+                .iter()
+                .find(|(_, n)| *n == name)
+                .ok_or_else(|| anyhow!("invalid variable attribute: {}", name))?;
+
+            attributes |= bit;
+        }
+
+        Ok(Self(attributes))
+    }
+}
+
+impl EfivarsArgs {
+    pub fn run(&self) -> Result<()> {
+        let path = self.vstore.path();
+
+        // The firmware of a running VM writes to the store, it must not be modified meanwhile.
+        let _lock = match self.action {
+            EfivarsAction::List => None,
+            _ => Some(FileLock::new(path, LockMode::Exclusive, "variable store")?),
+        };
+        let mut store = VariableStore::load(path)?;
+
+        match &self.action {
+            EfivarsAction::List => {
+                let vars: Vec<Value> = store.variables.iter().map(variable_json).collect();
+                println!("{}", serde_json::to_string_pretty(&vars)?);
+            }
+            EfivarsAction::Set {
+                name,
+                guid,
+                attributes,
+                value,
+            } => {
+                store.set(Variable::new(name, *guid, attributes.0, value.to_bytes()?));
+                store.save(path)?;
+            }
+            EfivarsAction::Delete { name, guid } => {
+                if !store.remove(name, guid) {
+                    return Err(anyhow!("variable {}-{} not found", name, guid));
+                }
+                store.save(path)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// JSON description of a variable, with the contents of well-known variables decoded.
+pub fn variable_json(var: &Variable) -> Value {
+    let attributes: Vec<&str> = ATTRIBUTES
+        .iter()
+        .filter(|(bit, _)| var.attributes & bit != 0)
+        .map(|(_, name)| *name)
+        .collect();
+
+    let mut value = json!({
+        "name": var.name,
+        "guid": var.guid.to_string(),
+        "attributes": attributes,
+        "data": hex_encode(&var.data),
+    });
+
+    if var.guid == EFI_GLOBAL_VARIABLE {
+        if let Some(decoded) = decode_global(&var.name, &var.data) {
+            value["value"] = decoded;
+        }
+    }
+
+    value
+}
+
+/// Decode the contents of BootOrder and Boot#### variables.
+fn decode_global(name: &str, data: &[u8]) -> Option<Value> {
+    if name == "BootOrder" || name == "BootNext" || name == "BootCurrent" {
+        if !data.len().is_multiple_of(2) {
+            return None;
+        }
+
+        let nums: Vec<String> = data
+            .chunks(2)
+            .map(|c| format!("{:04X}", u16::from_le_bytes([c[0], c[1]])))
+            .collect();
+
+        return Some(json!(nums));
+    }
+
+    let num = name.strip_prefix("Boot")?;
+    if num.len() != 4 || u16::from_str_radix(num, 16).is_err() {
+        return None;
+    }
+
+    // EFI_LOAD_OPTION: attributes, length of the device path list, description and device path
+    // list, followed by optional data.
+    let attributes = u32::from_le_bytes(data.get(0..4)?.try_into().ok()?);
+    let path_len = u16::from_le_bytes(data.get(4..6)?.try_into().ok()?) as usize;
+
+    let desc: Vec<u16> = data[6..]
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|c| *c != 0)
+        .collect();
+    let desc_end = 6 + (desc.len() + 1) * 2;
+    let device_path = data.get(desc_end..desc_end + path_len)?;
+
+    Some(json!({
+        "active": attributes & 0x1 != 0,
+        "attributes": attributes,
+        "description": String::from_utf16_lossy(&desc),
+        "devicePath": hex_encode(device_path),
+        "optionalData": hex_encode(&data[desc_end + path_len..]),
+    }))
+}
+
+/// Encode a string as NULL-terminated UCS-2.
+pub fn ucs2_encode(s: &str) -> Vec<u8> {
+    s.encode_utf16()
+        .chain([0])
+        .flat_map(u16::to_le_bytes)
+        .collect()
+}
+
+pub fn hex_encode(data: &[u8]) -> String {
+    data.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{:02x}", b);
+        s
+    })
+}
+
+pub fn hex_decode(s: &str) -> Result<Vec<u8>> {
+    let s = s.trim();
+    if !s.len().is_multiple_of(2) {
+        return Err(anyhow!("hexadecimal data has an odd number of digits"));
+    }
+
+    // Decode byte pairs rather than slicing the string, which may hold multi-byte characters.
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| match (hex_digit(pair[0]), hex_digit(pair[1])) {
+            (Some(hi), Some(lo)) => Ok(hi << 4 | lo),
+            _ => Err(anyhow!("invalid hexadecimal data: {}", s)),
+        })
+        .collect()
+}
+
+/// Value of a hexadecimal digit.
+fn hex_digit(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|d| d as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attributes() {
+        assert_eq!(Attributes::from_str("nv,bs,rt").unwrap(), Attributes(0x7));
+        assert_eq!(Attributes::from_str("0x27").unwrap(), Attributes(0x27));
+        assert!(Attributes::from_str("nv,xx").is_err());
+    }
+
+    #[test]
+    fn decode_boot_variables() {
+        let order = Variable::new("BootOrder", EFI_GLOBAL_VARIABLE, 0x7, vec![1, 0, 0, 0]);
+        assert_eq!(variable_json(&order)["value"], json!(["0001", "0000"]));
+
+        let mut option = vec![1, 0, 0, 0, 4, 0];
+        option.extend(ucs2_encode("disk"));
+        option.extend([0x7f, 0xff, 0x04, 0x00, 0xab]);
+        let boot = Variable::new("Boot0001", EFI_GLOBAL_VARIABLE, 0x7, option);
+
+        assert_eq!(
+            variable_json(&boot)["value"],
+            json!({
+                "active": true,
+                "attributes": 1,
+                "description": "disk",
+                "devicePath": "7fff0400",
+                "optionalData": "ab",
+            })
+        );
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(hex_decode("00ff10").unwrap(), vec![0x00, 0xff, 0x10]);
+        assert_eq!(hex_encode(&[0x00, 0xff, 0x10]), "00ff10");
+        assert!(hex_decode("0").is_err());
+        assert!(hex_decode("+f").is_err());
+        assert!(hex_decode("é0").is_err());
+        assert!(hex_decode("0é").is_err());
+    }
+}
@@ -0,0 +1,325 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::image::ImageReader;
+
+use anyhow::{anyhow, Result};
+
+use std::collections::HashSet;
+
+pub const DIR_ENTRY_LEN: usize = 32;
+
+/// Directory entry attributes.
+const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_LONG_NAME: u8 = 0x0f;
+
+/// FAT variants, identified by their number of clusters.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+/// Location of a directory's entries.
+#[derive(Clone, Copy, Debug)]
+enum Dir {
+    /// The fixed-size root directory of FAT12 and FAT16 volumes: offset and number of entries.
+    FixedRoot(u64, usize),
+
+    /// A directory stored in a cluster chain, starting at the given cluster.
+    Chain(u32),
+}
+
+/// A FAT file system of a partition, read-only.
+pub struct FatFs<'a> {
+    disk: &'a ImageReader,
+    fat_type: FatType,
+    cluster_size: u64,
+    fat_offset: u64,
+    data_offset: u64,
+    clusters: u32,
+    root: Dir,
+}
+
+impl<'a> FatFs<'a> {
+    /// Open the FAT file system starting at the given offset of the disk.
+    pub fn open(disk: &'a ImageReader, offset: u64) -> Result<Self> {
+        let mut bs = [0u8; 512];
+        disk.read_exact_at(&mut bs, offset)?;
+
+        if bs[510..512] != [0x55, 0xaa] {
+            return Err(anyhow!("no FAT boot sector found"));
+        }
+
+        let sector_size = le_u16(&bs, 11) as u64;
+        let sectors_per_cluster = bs[13] as u64;
+        let reserved = le_u16(&bs, 14) as u64;
+        let fats = bs[16] as u64;
+        let root_entries = le_u16(&bs, 17) as u64;
+        let total = match le_u16(&bs, 19) {
+            0 => le_u32(&bs, 32) as u64,
+            n => n as u64,
+        };
+        let fat_size = match le_u16(&bs, 22) {
+            0 => le_u32(&bs, 36) as u64,
+            n => n as u64,
+        };
+
+        if !matches!(sector_size, 512 | 1024 | 2048 | 4096)
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved == 0
+            || fats == 0
+            || fat_size == 0
+        {
+            return Err(anyhow!("invalid FAT boot sector"));
+        }
+
+        let root_sectors = (root_entries * DIR_ENTRY_LEN as u64).div_ceil(sector_size);
+        let data_start = reserved + fats * fat_size + root_sectors;
+        let clusters = total
+            .checked_sub(data_start)
+            .ok_or_else(|| anyhow!("invalid FAT boot sector"))?
+            / sectors_per_cluster;
+
+        let fat_type = match clusters {
+            0..4085 => FatType::Fat12,
+            4085..65525 => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+
+        let root = match fat_type {
+            FatType::Fat32 => Dir::Chain(le_u32(&bs, 44)),
+            _ => Dir::FixedRoot(
+                offset + (reserved + fats * fat_size) * sector_size,
+                root_entries as usize,
+            ),
+        };
+
+        Ok(Self {
+            disk,
+            fat_type,
+            cluster_size: sectors_per_cluster * sector_size,
+            fat_offset: offset + reserved * sector_size,
+            data_offset: offset + data_start * sector_size,
+            clusters: clusters as u32,
+            root,
+        })
+    }
+
+    /// Size of the file at the given path (components separated by '\' or '/', matched
+    /// case-insensitively against the short names of the entries), None if it does not exist.
+    pub fn file_size(&self, path: &str) -> Result<Option<u32>> {
+        let components: Vec<&str> = path.split(['\\', '/']).filter(|c| !c.is_empty()).collect();
+
+        let mut dir = self.root;
+        for (i, name) in components.iter().enumerate() {
+            let Some(entry) = self.find(dir, name)? else {
+                return Ok(None);
+            };
+
+            let is_dir = entry[11] & ATTR_DIRECTORY != 0;
+            if i + 1 == components.len() {
+                return Ok((!is_dir).then(|| le_u32(&entry, 28)));
+            }
+            if !is_dir {
+                return Ok(None);
+            }
+
+            let mut cluster = le_u16(&entry, 26) as u32;
+            if self.fat_type == FatType::Fat32 {
+                cluster |= (le_u16(&entry, 20) as u32) << 16;
+            }
+            dir = match cluster {
+                // ".." entries of subdirectories of the root point at cluster 0.
+                0 => self.root,
+                c => Dir::Chain(c),
+            };
+        }
+
+        Ok(None)
+    }
+
+    /// Find the entry of the given name in a directory.
+    fn find(&self, dir: Dir, name: &str) -> Result<Option<[u8; DIR_ENTRY_LEN]>> {
+        let short = short_name(name);
+
+        let entries = self.read_dir(dir)?;
+        for entry in entries.chunks_exact(DIR_ENTRY_LEN) {
+            match entry[0] {
+                0 => break,
+                0xe5 => continue,
+                _ => (),
+            }
+
+            let attr = entry[11];
+            if attr & ATTR_LONG_NAME == ATTR_LONG_NAME || attr & ATTR_VOLUME_ID != 0 {
+                continue;
+            }
+
+            if short.as_ref().is_some_and(|s| entry[0..11] == s[..]) {
+                return Ok(Some(entry.try_into().unwrap()));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Read the entries of a directory.
+    fn read_dir(&self, dir: Dir) -> Result<Vec<u8>> {
+        match dir {
+            Dir::FixedRoot(offset, entries) => {
+                let mut buf = vec![0u8; entries * DIR_ENTRY_LEN];
+                self.disk.read_exact_at(&mut buf, offset)?;
+
+                Ok(buf)
+            }
+            Dir::Chain(start) => {
+                let mut buf = Vec::new();
+                let mut visited = HashSet::new();
+                let mut cluster = start;
+                loop {
+                    if cluster < 2 || cluster - 2 >= self.clusters {
+                        return Err(anyhow!("invalid FAT cluster {}", cluster));
+                    }
+                    if !visited.insert(cluster) {
+                        return Err(anyhow!("FAT cluster chain loops at cluster {}", cluster));
+                    }
+
+                    let mut data = vec![0u8; self.cluster_size as usize];
+                    let offset = self.data_offset + (cluster - 2) as u64 * self.cluster_size;
+                    self.disk.read_exact_at(&mut data, offset)?;
+                    buf.extend(data);
+
+                    match self.next_cluster(cluster)? {
+                        Some(next) => cluster = next,
+                        None => return Ok(buf),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Next cluster of a chain, None at the end of the chain.
+    fn next_cluster(&self, cluster: u32) -> Result<Option<u32>> {
+        let (next, end) = match self.fat_type {
+            FatType::Fat12 => {
+                let offset = cluster as u64 + cluster as u64 / 2;
+                let mut b = [0u8; 2];
+                self.disk.read_exact_at(&mut b, self.fat_offset + offset)?;
+                let v = u16::from_le_bytes(b);
+                let v = if cluster & 1 == 1 { v >> 4 } else { v & 0xfff };
+
+                (v as u32, 0xff8)
+            }
+            FatType::Fat16 => {
+                let mut b = [0u8; 2];
+                self.disk
+                    .read_exact_at(&mut b, self.fat_offset + cluster as u64 * 2)?;
+
+                (u16::from_le_bytes(b) as u32, 0xfff8)
+            }
+            FatType::Fat32 => {
+                let mut b = [0u8; 4];
+                self.disk
+                    .read_exact_at(&mut b, self.fat_offset + cluster as u64 * 4)?;
+
+                (u32::from_le_bytes(b) & 0x0fff_ffff, 0x0fff_fff8)
+            }
+        };
+
+        Ok((next < end).then_some(next))
+    }
+}
+
+/// The 8.3 directory entry name of a file name, None if the name has no short form.
+pub fn short_name(name: &str) -> Option<[u8; 11]> {
+    let upper = name.to_ascii_uppercase();
+    let (base, ext) = upper.rsplit_once('.').unwrap_or((&upper, ""));
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || !upper.is_ascii() {
+        return None;
+    }
+
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base.as_bytes());
+    short[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+
+    Some(short)
+}
+
+fn le_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn le_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        image::{Format, Image},
+        test_util::{fat_image, temp_path},
+    };
+
+    use std::fs;
+
+    #[test]
+    fn lookup() {
+        let path = temp_path("fat.img");
+        fs::write(
+            &path,
+            fat_image(&[
+                ("\\EFI\\BOOT\\BOOTAA64.EFI", &[0xaa; 1000]),
+                ("\\EFI\\fedora\\shim.efi", b"shim"),
+                ("\\startup.nsh", b"fs0:"),
+            ]),
+        )
+        .unwrap();
+
+        let reader = Image::open(&path, Format::Raw).unwrap().reader().unwrap();
+        let fat = FatFs::open(&reader, 0).unwrap();
+
+        assert_eq!(fat.fat_type, FatType::Fat12);
+        assert_eq!(
+            fat.file_size("\\EFI\\BOOT\\BOOTAA64.EFI").unwrap(),
+            Some(1000)
+        );
+        assert_eq!(fat.file_size("/efi/boot/bootaa64.efi").unwrap(), Some(1000));
+        assert_eq!(fat.file_size("\\EFI\\FEDORA\\SHIM.EFI").unwrap(), Some(4));
+        assert_eq!(fat.file_size("\\STARTUP.NSH").unwrap(), Some(4));
+        assert_eq!(fat.file_size("\\EFI\\BOOT\\BOOTX64.EFI").unwrap(), None);
+        assert_eq!(fat.file_size("\\EFI\\BOOT").unwrap(), None);
+        assert_eq!(fat.file_size("\\STARTUP.NSH\\X").unwrap(), None);
+
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(short_name("bootx64.efi"), Some(*b"BOOTX64 EFI"));
+        assert_eq!(short_name("averylongname"), None);
+    }
+
+    #[test]
+    fn cluster_loop() {
+        // \EFI is the first directory created, at cluster 2: make its chain point at itself.
+        let mut image = fat_image(&[("\\EFI\\BOOT\\BOOTAA64.EFI", b"MZ")]);
+        image[512 + 3] = 0x02;
+        image[512 + 4] &= 0xf0;
+
+        let path = temp_path("fat.img");
+        fs::write(&path, image).unwrap();
+
+        let reader = Image::open(&path, Format::Raw).unwrap().reader().unwrap();
+        let result = FatFs::open(&reader, 0)
+            .unwrap()
+            .file_size("\\EFI\\BOOT\\BOOTAA64.EFI");
+
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "FAT cluster chain loops at cluster 2"
+        );
+    }
+}
@@ -0,0 +1,224 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::virtio::{self, VirtioDeviceConfig};
+
+use std::{
+    fmt, io,
+    net::{Shutdown, TcpListener, TcpStream},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    str::FromStr,
+    thread,
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+
+/// Forwarding of a host TCP port to a guest vsock port, given as tcp:HOST:PORT=vsock:PORT.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Forward {
+    /// Host address to listen on, as "host:port".
+    addr: String,
+
+    /// Guest vsock port to which connections are forwarded.
+    vsock_port: u32,
+}
+
+impl FromStr for Forward {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow!("invalid forward {}: expected tcp:HOST:PORT=vsock:PORT", s);
+
+        let (host, guest) = s.split_once('=').ok_or_else(invalid)?;
+        let addr = host.strip_prefix("tcp:").ok_or_else(invalid)?;
+        let vsock_port = guest.strip_prefix("vsock:").ok_or_else(invalid)?;
+
+        let (host, port) = addr.rsplit_once(':').ok_or_else(invalid)?;
+        if host.is_empty() {
+            return Err(anyhow!("forward {} does not specify a host", s));
+        }
+        u16::from_str(port).context(format!("invalid TCP port in forward {}", s))?;
+
+        Ok(Self {
+            addr: addr.to_string(),
+            vsock_port: u32::from_str(vsock_port)
+                .context(format!("invalid vsock port in forward {}", s))?,
+        })
+    }
+}
+
+impl TryFrom<String> for Forward {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Self::from_str(&s)
+    }
+}
+
+impl fmt::Display for Forward {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "tcp:{}=vsock:{}", self.addr, self.vsock_port)
+    }
+}
+
+impl Forward {
+    /// Find the socket created by libkrun for the guest vsock port. The vsock port must be
+    /// configured by a virtio-vsock device with the connect action.
+    pub fn check<'a>(&self, devices: &'a [VirtioDeviceConfig]) -> Result<&'a Path> {
+        virtio::vsock_connect_socket(devices, self.vsock_port).ok_or_else(|| {
+            anyhow!(
+                "forward {}: no virtio-vsock device with the connect action on port {}",
+                self,
+                self.vsock_port
+            )
+        })
+    }
+
+    /// Bind the host TCP port, connecting to the socket of the guest vsock port.
+    pub fn bind(&self, devices: &[VirtioDeviceConfig]) -> Result<Forwarder> {
+        let socket = self.check(devices)?;
+
+        let listener = TcpListener::bind(&self.addr)
+            .context(format!("forward {}: unable to bind {}", self, self.addr))?;
+
+        Ok(Forwarder {
+            listener,
+            socket: socket.to_path_buf(),
+        })
+    }
+}
+
+/// A bound forwarding, splicing each TCP connection to a connection to the vsock socket.
+pub struct Forwarder {
+    listener: TcpListener,
+    socket: PathBuf,
+}
+
+impl Forwarder {
+    /// Accept and forward connections on a new thread.
+    pub fn start(self) {
+        thread::spawn(move || {
+            for stream in self.listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let socket = self.socket.clone();
+                        thread::spawn(move || splice(stream, &socket));
+                    }
+                    Err(e) => eprintln!("Error accepting forwarded connection: {e}"),
+                }
+            }
+        });
+    }
+}
+
+/// Copy the bytes of a TCP connection to and from a new connection to the vsock socket, until
+/// both sides are closed.
+fn splice(tcp: TcpStream, socket: &Path) {
+    let vsock = match UnixStream::connect(socket) {
+        Ok(vsock) => vsock,
+        Err(e) => {
+            eprintln!(
+                "Unable to forward connection to vsock socket {}: {e}",
+                socket.display()
+            );
+            return;
+        }
+    };
+
+    let (Ok(mut tcp_read), Ok(mut vsock_write)) = (tcp.try_clone(), vsock.try_clone()) else {
+        return;
+    };
+
+    let to_guest = thread::spawn(move || {
+        let _ = io::copy(&mut tcp_read, &mut vsock_write);
+        let _ = vsock_write.shutdown(Shutdown::Write);
+    });
+
+    let (mut vsock_read, mut tcp_write) = (vsock, tcp);
+    let _ = io::copy(&mut vsock_read, &mut tcp_write);
+    let _ = tcp_write.shutdown(Shutdown::Write);
+
+    let _ = to_guest.join();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_util::temp_path;
+
+    use std::{
+        io::{Read, Write},
+        os::unix::net::UnixListener,
+    };
+
+    #[test]
+    fn parse() {
+        let forward = Forward::from_str("tcp:127.0.0.1:2222=vsock:22").unwrap();
+        assert_eq!(forward.addr, "127.0.0.1:2222");
+        assert_eq!(forward.vsock_port, 22);
+        assert_eq!(forward.to_string(), "tcp:127.0.0.1:2222=vsock:22");
+
+        for invalid in [
+            "127.0.0.1:2222=vsock:22",
+            "tcp:127.0.0.1:2222",
+            "tcp:127.0.0.1=vsock:22",
+            "tcp::2222=vsock:22",
+            "tcp:127.0.0.1:2222=vsock:ssh",
+            "udp:127.0.0.1:2222=vsock:22",
+        ] {
+            assert!(Forward::from_str(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn forward_connections() {
+        let socket = temp_path("vsock.sock");
+        let devices = [format!(
+            "virtio-vsock,port=22,socketURL={},connect",
+            socket.display()
+        )
+        .parse::<VirtioDeviceConfig>()
+        .unwrap()];
+
+        // The vsock port must be configured with the connect action.
+        let err = Forward::from_str("tcp:127.0.0.1:0=vsock:23")
+            .unwrap()
+            .bind(&devices)
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "forward tcp:127.0.0.1:0=vsock:23: no virtio-vsock device with the connect action on port 23"
+        );
+
+        // Stand in for libkrun, echoing the guest service's greeting and the client's bytes.
+        let guest = UnixListener::bind(&socket).unwrap();
+        let forwarder = Forward::from_str("tcp:127.0.0.1:0=vsock:22")
+            .unwrap()
+            .bind(&devices)
+            .unwrap();
+        let addr = forwarder.listener.local_addr().unwrap();
+        forwarder.start();
+
+        let server = thread::spawn(move || {
+            let (mut conn, _) = guest.accept().unwrap();
+            conn.write_all(b"SSH-2.0-guest\r\n").unwrap();
+            let mut request = Vec::new();
+            conn.read_to_end(&mut request).unwrap();
+            conn.write_all(&request).unwrap();
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"SSH-2.0-client\r\n").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        server.join().unwrap();
+        std::fs::remove_file(&socket).unwrap();
+
+        assert_eq!(response, b"SSH-2.0-guest\r\nSSH-2.0-client\r\n");
+    }
+}
@@ -0,0 +1,1061 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::{fd::FromRawFd, unix::fs::FileExt},
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        Mutex,
+    },
+    thread,
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+const QCOW2_MAGIC: &[u8; 4] = b"QFI\xfb";
+
+/// Length of a version 2 qcow2 header, and the minimum length of a version 3 header.
+const QCOW2_V2_HEADER_LEN: usize = 72;
+const QCOW2_V3_HEADER_LEN: usize = 104;
+
+/// qcow2 incompatible feature bits.
+const QCOW2_DIRTY: u64 = 1 << 0;
+const QCOW2_CORRUPT: u64 = 1 << 1;
+const QCOW2_EXTERNAL_DATA_FILE: u64 = 1 << 2;
+const QCOW2_EXTENDED_L2: u64 = 1 << 4;
+const QCOW2_KNOWN_FEATURES: u64 = (1 << 5) - 1;
+
+/// Offset of the host cluster in L1 and L2 table entries.
+const QCOW2_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+
+/// L2 table entry flags: compressed cluster, and (version 3) cluster reading as zeros.
+const QCOW2_COMPRESSED: u64 = 1 << 62;
+const QCOW2_ZERO: u64 = 1;
+
+/// qcow2 header extension recording the format of the backing file.
+const QCOW2_EXT_BACKING_FORMAT: u32 = 0xe279_2aca;
+
+/// Cluster size of the qcow2 images created by krunkit (64 KiB).
+const QCOW2_CLUSTER_BITS: u32 = 16;
+
+/// Maximum length of a backing file chain, guarding against chains that loop.
+const MAX_BACKING_CHAIN: usize = 16;
+
+/// Disk image format.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// Detect the format from the image header.
+    #[default]
+    Auto,
+    Raw,
+    Qcow2,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &s.to_lowercase()[..] {
+            "auto" => Ok(Self::Auto),
+            "raw" => Ok(Self::Raw),
+            "qcow2" => Ok(Self::Qcow2),
+            _ => Err(anyhow!(
+                "unsupported disk image format {} (expected raw, qcow2 or auto)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Auto => "auto",
+            Self::Raw => "raw",
+            Self::Qcow2 => "qcow2",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+impl Format {
+    /// Value identifying the format in the libkrun API (KRUN_DISK_FORMAT_*).
+    pub fn krun_format(&self) -> u32 {
+        match self {
+            Self::Raw | Self::Auto => 0,
+            Self::Qcow2 => 1,
+        }
+    }
+
+    /// Identify the format of an image from its header, returning an error naming the format of
+    /// images that cannot be used.
+    fn detect(header: &[u8], len: u64) -> Result<Self> {
+        if header.starts_with(QCOW2_MAGIC) {
+            return Ok(Self::Qcow2);
+        }
+
+        let unsupported = if header.starts_with(b"KDMV") || header.starts_with(b"# Disk Descriptor")
+        {
+            Some("VMDK")
+        } else if header.get(0x40..0x44) == Some(&[0x7f, 0x10, 0xda, 0xbe]) {
+            Some("VDI")
+        } else if header.starts_with(b"vhdxfile") {
+            Some("VHDX")
+        } else if header.starts_with(b"conectix") {
+            Some("VHD")
+        } else if header.starts_with(b"QED\0") {
+            Some("QED")
+        } else if header.starts_with(b"\xfd7zXZ\0") {
+            Some("xz-compressed")
+        } else if header.starts_with(&[0x1f, 0x8b]) {
+            Some("gzip-compressed")
+        } else if header.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some("zstd-compressed")
+        } else {
+            None
+        };
+
+        match unsupported {
+            Some(name) => Err(anyhow!("{} images are not supported", name)),
+            None if len == 0 => Err(anyhow!("image is empty")),
+            None => Ok(Self::Raw),
+        }
+    }
+}
+
+/// A validated disk image.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Image {
+    pub path: PathBuf,
+
+    /// Format of the image, never Auto.
+    pub format: Format,
+
+    /// Size of the disk presented to the guest, in bytes.
+    pub virtual_size: u64,
+
+    /// Backing image of a qcow2 image.
+    pub backing: Option<Box<Image>>,
+}
+
+impl Image {
+    /// Open a disk image, detecting its format if Auto is given, and validate it along with its
+    /// backing file chain.
+    pub fn open(path: &Path, format: Format) -> Result<Self> {
+        Self::open_chain(path, format, &mut Vec::new())
+            .context(format!("invalid disk image {}", path.display()))
+    }
+
+    fn open_chain(path: &Path, format: Format, chain: &mut Vec<PathBuf>) -> Result<Self> {
+        let canonical = path
+            .canonicalize()
+            .context(format!("unable to open {}", path.display()))?;
+        if chain.contains(&canonical) {
+            return Err(anyhow!("backing file chain loops at {}", path.display()));
+        }
+        if chain.len() >= MAX_BACKING_CHAIN {
+            return Err(anyhow!(
+                "backing file chain longer than {} images",
+                MAX_BACKING_CHAIN
+            ));
+        }
+        chain.push(canonical);
+
+        let mut file = File::open(path).context(format!("unable to open {}", path.display()))?;
+        let len = file.metadata()?.len();
+
+        let mut header = Vec::new();
+        (&mut file)
+            .take(0x200)
+            .read_to_end(&mut header)
+            .context(format!("unable to read {}", path.display()))?;
+
+        let format = match format {
+            Format::Auto => Format::detect(&header, len)?,
+            Format::Qcow2 if !header.starts_with(QCOW2_MAGIC) => {
+                return Err(anyhow!("no qcow2 header found"))
+            }
+            format => format,
+        };
+
+        match format {
+            Format::Qcow2 => Self::open_qcow2(path, &mut file, len, chain),
+            _ => Ok(Self {
+                path: path.to_path_buf(),
+                format: Format::Raw,
+                virtual_size: len,
+                backing: None,
+            }),
+        }
+    }
+
+    /// Open the image for reading the disk contents seen by the guest.
+    pub fn reader(&self) -> Result<ImageReader> {
+        let file =
+            File::open(&self.path).context(format!("unable to open {}", self.path.display()))?;
+
+        let qcow2 = match self.format {
+            Format::Qcow2 => {
+                let len = file.metadata()?.len();
+                let header = Qcow2Header::read(&mut &file, len)?;
+                if header.extended_l2 {
+                    return Err(anyhow!(
+                        "reading qcow2 images with extended L2 entries is not supported"
+                    ));
+                }
+
+                let mut l1 = vec![0u8; header.l1_size as usize * 8];
+                file.read_exact_at(&mut l1, header.l1_table_offset)
+                    .context("unable to read qcow2 L1 table")?;
+
+                Some(Qcow2Map {
+                    cluster_bits: header.cluster_bits,
+                    l1: l1.chunks(8).map(|e| be_u64(e, 0)).collect(),
+                })
+            }
+            _ => None,
+        };
+
+        let backing = match &self.backing {
+            Some(backing) => Some(Box::new(backing.reader()?)),
+            None => None,
+        };
+
+        Ok(ImageReader {
+            file,
+            size: self.virtual_size,
+            qcow2,
+            backing,
+        })
+    }
+
+    /// Change the virtual size of the image. Shrinking raw images discards the data past the new
+    /// size, and must be allowed explicitly. qcow2 images can only grow, up to the size addressed
+    /// by the clusters already allocated to their L1 table.
+    pub fn resize(&self, size: u64, shrink: bool) -> Result<()> {
+        if size == 0 {
+            return Err(anyhow!("virtual size must not be 0"));
+        }
+
+        if size < self.virtual_size && (!shrink || self.format == Format::Qcow2) {
+            return Err(match self.format {
+                Format::Qcow2 => anyhow!("shrinking qcow2 images is not supported"),
+                _ => anyhow!("shrinking the image discards data, use --shrink to allow it"),
+            });
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.path)
+            .context(format!("unable to open {}", self.path.display()))?;
+
+        match self.format {
+            Format::Qcow2 => resize_qcow2(&file, size),
+            _ => file.set_len(size).map_err(anyhow::Error::from),
+        }
+        .context(format!("unable to resize {}", self.path.display()))
+    }
+
+    fn open_qcow2(
+        path: &Path,
+        file: &mut File,
+        len: u64,
+        chain: &mut Vec<PathBuf>,
+    ) -> Result<Self> {
+        let header = Qcow2Header::read(file, len)?;
+
+        let backing = match header.backing_file(file)? {
+            Some((name, format)) => {
+                // Relative backing file names are relative to the image's directory.
+                let backing_path = path.parent().unwrap_or(Path::new("")).join(name);
+                let backing = Self::open_chain(&backing_path, format, chain)
+                    .context(format!("invalid backing file {}", backing_path.display()))?;
+
+                Some(Box::new(backing))
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            format: Format::Qcow2,
+            virtual_size: header.size,
+            backing,
+        })
+    }
+}
+
+/// Create a sparse raw image of the given size. The file must not already exist.
+pub fn create_raw(path: &Path, size: u64) -> Result<()> {
+    if size == 0 {
+        return Err(anyhow!("virtual size must not be 0"));
+    }
+
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .context(format!("unable to create {}", path.display()))?
+        .set_len(size)
+        .context(format!("unable to write {}", path.display()))
+}
+
+/// Create an empty qcow2 image of the given virtual size, optionally backed by another image.
+///
+/// The image holds a header (with the backing file name), a refcount table, a single refcount
+/// block and the L1 table, each starting on a cluster boundary. The file must not already exist.
+pub fn create_qcow2(path: &Path, size: u64, backing: Option<&Image>) -> Result<()> {
+    if size == 0 {
+        return Err(anyhow!("virtual size must not be 0"));
+    }
+
+    let cluster_size = 1u64 << QCOW2_CLUSTER_BITS;
+
+    // Each L2 table maps a cluster of 8-byte entries, each mapping a cluster.
+    let l2_coverage = cluster_size * (cluster_size / 8);
+    let l1_size = size.div_ceil(l2_coverage);
+    let l1_clusters = (l1_size * 8).div_ceil(cluster_size);
+    let clusters = 3 + l1_clusters;
+
+    // A single refcount block of 16-bit refcounts covers the metadata clusters of any image of
+    // a sane size.
+    if clusters > cluster_size / 2 {
+        return Err(anyhow!("virtual size {} too large", size));
+    }
+
+    let mut header = vec![0u8; QCOW2_V3_HEADER_LEN];
+    header[0..4].copy_from_slice(QCOW2_MAGIC);
+    header[4..8].copy_from_slice(&3u32.to_be_bytes());
+    header[20..24].copy_from_slice(&QCOW2_CLUSTER_BITS.to_be_bytes());
+    header[24..32].copy_from_slice(&size.to_be_bytes());
+    header[36..40].copy_from_slice(&(l1_size as u32).to_be_bytes());
+    header[40..48].copy_from_slice(&(3 * cluster_size).to_be_bytes());
+    header[48..56].copy_from_slice(&cluster_size.to_be_bytes());
+    header[56..60].copy_from_slice(&1u32.to_be_bytes());
+    header[96..100].copy_from_slice(&4u32.to_be_bytes());
+    header[100..104].copy_from_slice(&(QCOW2_V3_HEADER_LEN as u32).to_be_bytes());
+
+    if let Some(backing) = backing {
+        let format = backing.format.to_string();
+        header.extend(QCOW2_EXT_BACKING_FORMAT.to_be_bytes());
+        header.extend((format.len() as u32).to_be_bytes());
+        header.extend(format.as_bytes());
+        header.resize(header.len().next_multiple_of(8), 0);
+    }
+
+    // End of the header extensions.
+    header.extend([0u8; 8]);
+
+    if let Some(backing) = backing {
+        let name = backing
+            .path
+            .canonicalize()
+            .context(format!("unable to resolve {}", backing.path.display()))?;
+        let name = name.as_os_str().as_encoded_bytes();
+        if name.len() > 1023 {
+            return Err(anyhow!("backing file name too long"));
+        }
+
+        let offset = header.len() as u64;
+        header[8..16].copy_from_slice(&offset.to_be_bytes());
+        header[16..20].copy_from_slice(&(name.len() as u32).to_be_bytes());
+        header.extend(name);
+    }
+
+    let refcounts: Vec<u8> = (0..clusters).flat_map(|_| 1u16.to_be_bytes()).collect();
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .context(format!("unable to create {}", path.display()))?;
+
+    file.set_len(clusters * cluster_size)
+        .and_then(|_| file.write_all(&header))
+        .and_then(|_| file.write_all_at(&(2 * cluster_size).to_be_bytes(), cluster_size))
+        .and_then(|_| file.write_all_at(&refcounts, 2 * cluster_size))
+        .and_then(|_| file.flush())
+        .context(format!("unable to write {}", path.display()))?;
+
+    Ok(())
+}
+
+/// Grow a qcow2 image, extending its L1 table within the clusters allocated to it.
+fn resize_qcow2(file: &File, size: u64) -> Result<()> {
+    let header = Qcow2Header::read(&mut &*file, file.metadata()?.len())?;
+
+    let cluster_size = 1u64 << header.cluster_bits;
+    let l2_coverage = cluster_size * (cluster_size / 8);
+    let l1_size = size.div_ceil(l2_coverage);
+    let allocated = (header.l1_size as u64 * 8).div_ceil(cluster_size).max(1) * cluster_size;
+
+    if l1_size * 8 > allocated || header.l1_table_offset == 0 {
+        return Err(anyhow!(
+            "the L1 table of the image cannot address {} bytes; use qemu-img resize instead",
+            size
+        ));
+    }
+
+    // Entries past the current L1 table may hold stale data, clear them before using them.
+    if l1_size > header.l1_size as u64 {
+        let start = header
+            .l1_table_offset
+            .checked_add(header.l1_size as u64 * 8)
+            .ok_or_else(|| anyhow!("invalid qcow2 L1 table"))?;
+        file.write_all_at(
+            &vec![0u8; (l1_size - header.l1_size as u64) as usize * 8],
+            start,
+        )?;
+    }
+
+    file.write_all_at(&size.to_be_bytes(), 24)?;
+    file.write_all_at(&(l1_size as u32).to_be_bytes(), 36)?;
+    file.sync_all()?;
+
+    Ok(())
+}
+
+/// Create a qcow2 overlay of a disk image in the overlay directory, recording writes to the disk
+/// while leaving the image untouched. The overlay is removed when krunkit exits.
+pub fn create_overlay(base: &Image, name: &str) -> Result<PathBuf> {
+    let path = overlay_dir()?.join(format!("{}.qcow2", name));
+    create_qcow2(&path, base.virtual_size, Some(base)).context(format!(
+        "unable to create overlay of {}",
+        base.path.display()
+    ))?;
+
+    Ok(path)
+}
+
+/// Directory holding the disk overlays of this krunkit process, created on first use and
+/// removed when the process exits, including on SIGINT, SIGTERM and SIGHUP.
+fn overlay_dir() -> Result<PathBuf> {
+    static DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+    // libkrun exits the process when the guest shuts down, so clean up in an exit handler.
+    extern "C" fn remove_overlay_dir() {
+        if let Some(dir) = DIR.lock().ok().and_then(|d| d.clone()) {
+            let _ = fs::remove_dir_all(dir);
+        }
+    }
+
+    let mut dir = DIR.lock().unwrap();
+    if let Some(dir) = &*dir {
+        return Ok(dir.clone());
+    }
+
+    let tmp = std::env::temp_dir();
+    remove_stale_overlay_dirs(&tmp);
+
+    let path = tmp.join(format!("krunkit-{}-overlays", process::id()));
+    fs::create_dir(&path).context(format!(
+        "unable to create overlay directory {}",
+        path.display()
+    ))?;
+
+    unsafe {
+        libc::atexit(remove_overlay_dir);
+    }
+    exit_on_signals()?;
+
+    *dir = Some(path.clone());
+
+    Ok(path)
+}
+
+/// Remove the overlay directories of krunkit processes that no longer exist, left behind when
+/// they were killed by a signal that cannot be handled.
+fn remove_stale_overlay_dirs(tmp: &Path) {
+    let Ok(entries) = fs::read_dir(tmp) else {
+        return;
+    };
+
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let Some(pid) = name
+            .to_str()
+            .and_then(|n| n.strip_prefix("krunkit-"))
+            .and_then(|n| n.strip_suffix("-overlays"))
+            .and_then(|pid| libc::pid_t::from_str(pid).ok())
+        else {
+            continue;
+        };
+
+        if pid > 0
+            && unsafe { libc::kill(pid, 0) } < 0
+            && io::Error::last_os_error().raw_os_error() == Some(libc::ESRCH)
+        {
+            let _ = fs::remove_dir_all(entry.path());
+        }
+    }
+}
+
+/// Exit the process on SIGINT, SIGTERM and SIGHUP, so that the exit handlers skipped by the
+/// default action of these signals are run.
+fn exit_on_signals() -> Result<()> {
+    static PIPE: AtomicI32 = AtomicI32::new(-1);
+
+    // Signal handlers may only make async-signal-safe calls, hand the signal over to a thread.
+    extern "C" fn forward_signal(signal: libc::c_int) {
+        let byte = signal as u8;
+        unsafe {
+            libc::write(
+                PIPE.load(Ordering::Relaxed),
+                &byte as *const u8 as *const libc::c_void,
+                1,
+            )
+        };
+    }
+
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error()).context("unable to create signal pipe");
+    }
+    PIPE.store(fds[1], Ordering::Relaxed);
+
+    let mut signals = unsafe { File::from_raw_fd(fds[0]) };
+    thread::spawn(move || {
+        let mut signal = [0u8];
+        if signals.read_exact(&mut signal).is_ok() {
+            process::exit(128 + signal[0] as i32);
+        }
+    });
+
+    for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+        unsafe { libc::signal(signal, forward_signal as *const () as libc::sighandler_t) };
+    }
+
+    Ok(())
+}
+
+/// Reader of the disk contents seen by the guest, following the backing file chain.
+pub struct ImageReader {
+    file: File,
+    size: u64,
+    qcow2: Option<Qcow2Map>,
+    backing: Option<Box<ImageReader>>,
+}
+
+/// Mapping of the guest clusters of a qcow2 image to host clusters.
+struct Qcow2Map {
+    cluster_bits: u32,
+    l1: Vec<u64>,
+}
+
+impl ImageReader {
+    /// Size of the disk, in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Read exactly buf.len() bytes of the disk, starting at the given offset.
+    pub fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        if offset
+            .checked_add(buf.len() as u64)
+            .is_none_or(|end| end > self.size)
+        {
+            return Err(anyhow!("read past the end of the disk"));
+        }
+
+        let Some(map) = &self.qcow2 else {
+            return Ok(self.file.read_exact_at(buf, offset)?);
+        };
+
+        // Read each cluster in turn, as consecutive guest clusters may be anywhere in the file.
+        let cluster_size = 1u64 << map.cluster_bits;
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let in_cluster = pos & (cluster_size - 1);
+            let len = ((cluster_size - in_cluster) as usize).min(buf.len() - done);
+            let chunk = &mut buf[done..done + len];
+
+            match self.qcow2_cluster(map, pos)? {
+                Cluster::Data(host) => self.file.read_exact_at(chunk, host + in_cluster)?,
+                Cluster::Zero => chunk.fill(0),
+                Cluster::Unallocated => self.read_backing(chunk, pos)?,
+            }
+
+            done += len;
+        }
+
+        Ok(())
+    }
+
+    /// Host cluster holding a guest offset.
+    fn qcow2_cluster(&self, map: &Qcow2Map, pos: u64) -> Result<Cluster> {
+        let l2_bits = map.cluster_bits - 3;
+        let l1_index = (pos >> (map.cluster_bits + l2_bits)) as usize;
+        let l2_index = (pos >> map.cluster_bits) & ((1 << l2_bits) - 1);
+
+        let l2_offset = map.l1.get(l1_index).copied().unwrap_or(0) & QCOW2_OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok(Cluster::Unallocated);
+        }
+
+        let mut entry = [0u8; 8];
+        self.file
+            .read_exact_at(&mut entry, l2_offset + l2_index * 8)?;
+        let entry = u64::from_be_bytes(entry);
+
+        if entry & QCOW2_COMPRESSED != 0 {
+            return Err(anyhow!(
+                "reading compressed qcow2 clusters is not supported"
+            ));
+        }
+
+        if entry & QCOW2_ZERO != 0 {
+            return Ok(Cluster::Zero);
+        }
+
+        match entry & QCOW2_OFFSET_MASK {
+            0 => Ok(Cluster::Unallocated),
+            host => Ok(Cluster::Data(host)),
+        }
+    }
+
+    /// Read unallocated data, from the backing file if any and as zeros otherwise.
+    fn read_backing(&self, buf: &mut [u8], pos: u64) -> Result<()> {
+        buf.fill(0);
+
+        if let Some(backing) = &self.backing {
+            // The backing file may be smaller than the image.
+            let end = (pos + buf.len() as u64).min(backing.size);
+            if pos < end {
+                backing.read_exact_at(&mut buf[..(end - pos) as usize], pos)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Location of the data of a qcow2 guest cluster.
+enum Cluster {
+    /// Host offset of the cluster.
+    Data(u64),
+
+    /// The cluster reads as zeros, without falling back to the backing file.
+    Zero,
+
+    /// The cluster is read from the backing file, or as zeros without one.
+    Unallocated,
+}
+
+/// The fields of a qcow2 header needed to validate an image.
+struct Qcow2Header {
+    header_len: usize,
+    cluster_bits: u32,
+    cluster_size: u64,
+    l1_table_offset: u64,
+    l1_size: u32,
+    extended_l2: bool,
+    backing_file_offset: u64,
+    backing_file_size: u32,
+    size: u64,
+    len: u64,
+}
+
+impl Qcow2Header {
+    fn read(file: &mut (impl Read + Seek), len: u64) -> Result<Self> {
+        let mut buf = [0u8; QCOW2_V3_HEADER_LEN];
+        file.seek(SeekFrom::Start(0))?;
+        let n = file.read(&mut buf)?;
+        if n < QCOW2_V2_HEADER_LEN {
+            return Err(anyhow!("qcow2 header truncated"));
+        }
+
+        let version = be_u32(&buf, 4);
+        let cluster_bits = be_u32(&buf, 20);
+        let size = be_u64(&buf, 24);
+        let crypt_method = be_u32(&buf, 32);
+
+        let mut extended_l2 = false;
+        let header_len = match version {
+            2 => QCOW2_V2_HEADER_LEN,
+            3 => {
+                if n < QCOW2_V3_HEADER_LEN {
+                    return Err(anyhow!("qcow2 header truncated"));
+                }
+
+                let features = be_u64(&buf, 72);
+                if features & QCOW2_CORRUPT != 0 {
+                    return Err(anyhow!("qcow2 image is marked corrupt"));
+                }
+                if features & QCOW2_DIRTY != 0 {
+                    return Err(anyhow!(
+                        "qcow2 image was not closed cleanly; repair it with qemu-img check -r all"
+                    ));
+                }
+                if features & QCOW2_EXTERNAL_DATA_FILE != 0 {
+                    return Err(anyhow!(
+                        "qcow2 images with external data files are not supported"
+                    ));
+                }
+                if features & !QCOW2_KNOWN_FEATURES != 0 {
+                    return Err(anyhow!(
+                        "qcow2 image uses unknown incompatible features {:#x}",
+                        features & !QCOW2_KNOWN_FEATURES
+                    ));
+                }
+
+                extended_l2 = features & QCOW2_EXTENDED_L2 != 0;
+
+                be_u32(&buf, 100) as usize
+            }
+            _ => return Err(anyhow!("unsupported qcow2 version {}", version)),
+        };
+
+        if !(9..=21).contains(&cluster_bits) {
+            return Err(anyhow!("invalid qcow2 cluster size 2^{}", cluster_bits));
+        }
+        if crypt_method != 0 {
+            return Err(anyhow!("encrypted qcow2 images are not supported"));
+        }
+        if size == 0 {
+            return Err(anyhow!("qcow2 image has a virtual size of 0"));
+        }
+
+        let cluster_size = 1u64 << cluster_bits;
+        if header_len < QCOW2_V2_HEADER_LEN || header_len as u64 > cluster_size {
+            return Err(anyhow!("invalid qcow2 header length {}", header_len));
+        }
+
+        let header = Self {
+            header_len,
+            cluster_bits,
+            cluster_size,
+            l1_table_offset: be_u64(&buf, 40),
+            l1_size: be_u32(&buf, 36),
+            extended_l2,
+            backing_file_offset: be_u64(&buf, 8),
+            backing_file_size: be_u32(&buf, 16),
+            size,
+            len,
+        };
+
+        if header.backing_file_offset != 0
+            && (header.backing_file_size == 0
+                || header.backing_file_size > 1023
+                || header
+                    .backing_file_offset
+                    .checked_add(header.backing_file_size as u64)
+                    .is_none_or(|end| end > len))
+        {
+            return Err(anyhow!("invalid qcow2 backing file name"));
+        }
+
+        // The L1 table is read whole, it must be within the file rather than sized by the header.
+        if header.l1_size != 0
+            && header
+                .l1_table_offset
+                .checked_add(header.l1_size as u64 * 8)
+                .is_none_or(|end| end > len)
+        {
+            return Err(anyhow!("invalid qcow2 L1 table"));
+        }
+
+        Ok(header)
+    }
+
+    /// Name and format of the backing file, if any.
+    fn backing_file(&self, file: &mut File) -> Result<Option<(PathBuf, Format)>> {
+        if self.backing_file_offset == 0 {
+            return Ok(None);
+        }
+
+        let mut name = vec![0u8; self.backing_file_size as usize];
+        file.seek(SeekFrom::Start(self.backing_file_offset))?;
+        file.read_exact(&mut name)?;
+        let name = String::from_utf8(name).context("backing file name is not valid UTF-8")?;
+
+        Ok(Some((PathBuf::from(name), self.backing_format(file)?)))
+    }
+
+    /// Format of the backing file recorded in the header extensions, Auto if not recorded.
+    fn backing_format(&self, file: &mut File) -> Result<Format> {
+        // Header extensions follow the header, within the first cluster.
+        let end = self.cluster_size.min(self.len) as usize;
+        let mut cluster = Vec::new();
+        file.seek(SeekFrom::Start(0))?;
+        file.take(end as u64).read_to_end(&mut cluster)?;
+
+        let mut offset = self.header_len;
+        while offset + 8 <= cluster.len() {
+            let kind = be_u32(&cluster, offset);
+            let ext_len = be_u32(&cluster, offset + 4) as usize;
+            let data = cluster
+                .get(offset + 8..offset + 8 + ext_len)
+                .ok_or_else(|| anyhow!("qcow2 header extension truncated"))?;
+
+            match kind {
+                0 => break,
+                QCOW2_EXT_BACKING_FORMAT => {
+                    let name = String::from_utf8_lossy(data);
+                    return match &name[..] {
+                        "raw" => Ok(Format::Raw),
+                        "qcow2" => Ok(Format::Qcow2),
+                        _ => Err(anyhow!("unsupported backing file format {}", name)),
+                    };
+                }
+                _ => (),
+            }
+
+            offset += 8 + ext_len.next_multiple_of(8);
+        }
+
+        Ok(Format::Auto)
+    }
+}
+
+fn be_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn be_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_util::temp_path;
+
+    use std::fs;
+
+    /// A version 3 qcow2 header with 64 KiB clusters, optionally backed by a file of the given
+    /// format.
+    fn qcow2(size: u64, backing: Option<(&str, &str)>, features: u64) -> Vec<u8> {
+        let mut img = vec![0u8; 0x200];
+        img[0..4].copy_from_slice(QCOW2_MAGIC);
+        img[4..8].copy_from_slice(&3u32.to_be_bytes());
+        img[20..24].copy_from_slice(&16u32.to_be_bytes());
+        img[24..32].copy_from_slice(&size.to_be_bytes());
+        img[72..80].copy_from_slice(&features.to_be_bytes());
+        img[100..104].copy_from_slice(&(QCOW2_V3_HEADER_LEN as u32).to_be_bytes());
+
+        if let Some((name, format)) = backing {
+            // Backing format extension, end of extensions, then the backing file name.
+            let mut ext = QCOW2_EXT_BACKING_FORMAT.to_be_bytes().to_vec();
+            ext.extend((format.len() as u32).to_be_bytes());
+            ext.extend(format.as_bytes());
+            ext.resize(8 + format.len().next_multiple_of(8), 0);
+            img[104..104 + ext.len()].copy_from_slice(&ext);
+
+            img[8..16].copy_from_slice(&0x100u64.to_be_bytes());
+            img[16..20].copy_from_slice(&(name.len() as u32).to_be_bytes());
+            img[0x100..0x100 + name.len()].copy_from_slice(name.as_bytes());
+        }
+
+        img
+    }
+
+    #[test]
+    fn detect_formats() {
+        let raw = temp_path("raw.img");
+        fs::write(&raw, vec![0u8; 4096]).unwrap();
+        let img = Image::open(&raw, Format::Auto).unwrap();
+        assert_eq!((img.format, img.virtual_size), (Format::Raw, 4096));
+        assert!(Image::open(&raw, Format::Qcow2).is_err());
+
+        let qcow = temp_path("disk.qcow2");
+        fs::write(&qcow, qcow2(1 << 30, None, 0)).unwrap();
+        let img = Image::open(&qcow, Format::Auto).unwrap();
+        assert_eq!((img.format, img.virtual_size), (Format::Qcow2, 1 << 30));
+
+        // An explicit raw format is trusted.
+        assert_eq!(Image::open(&qcow, Format::Raw).unwrap().format, Format::Raw);
+
+        let vmdk = temp_path("disk.vmdk");
+        fs::write(&vmdk, b"KDMV\x01\0\0\0").unwrap();
+        let err = Image::open(&vmdk, Format::Auto).unwrap_err();
+
+        for path in [&raw, &qcow, &vmdk] {
+            fs::remove_file(path).unwrap();
+        }
+
+        assert_eq!(
+            format!("{:#}", err),
+            format!(
+                "invalid disk image {}: VMDK images are not supported",
+                vmdk.display()
+            )
+        );
+    }
+
+    #[test]
+    fn invalid_headers() {
+        let path = temp_path("invalid.qcow2");
+
+        // A huge L1 table past the end of the file.
+        let mut huge_l1 = qcow2(1 << 30, None, 0);
+        huge_l1[36..40].copy_from_slice(&u32::MAX.to_be_bytes());
+        huge_l1[40..48].copy_from_slice(&0x200u64.to_be_bytes());
+
+        // A backing file name whose end overflows.
+        let mut overflow = qcow2(1 << 30, None, 0);
+        overflow[8..16].copy_from_slice(&(u64::MAX - 4).to_be_bytes());
+        overflow[16..20].copy_from_slice(&16u32.to_be_bytes());
+
+        let mut errors = Vec::new();
+        for img in [huge_l1, overflow] {
+            fs::write(&path, img).unwrap();
+            errors.push(format!(
+                "{:#}",
+                Image::open(&path, Format::Auto).unwrap_err()
+            ));
+        }
+        fs::remove_file(&path).unwrap();
+
+        let prefix = format!("invalid disk image {}: ", path.display());
+        assert_eq!(
+            errors,
+            [
+                format!("{prefix}invalid qcow2 L1 table"),
+                format!("{prefix}invalid qcow2 backing file name"),
+            ]
+        );
+    }
+
+    #[test]
+    fn stale_overlay_dirs() {
+        let tmp = temp_path("tmp");
+        let dead = tmp.join(format!("krunkit-{}-overlays", libc::pid_t::MAX));
+        let alive = tmp.join(format!("krunkit-{}-overlays", process::id()));
+        let other = tmp.join("krunkit-other-overlays");
+        for dir in [&dead, &alive, &other] {
+            fs::create_dir_all(dir).unwrap();
+        }
+        fs::write(dead.join("disk.qcow2"), b"").unwrap();
+
+        remove_stale_overlay_dirs(&tmp);
+        let exists = [&dead, &alive, &other].map(|dir| dir.exists());
+        fs::remove_dir_all(&tmp).unwrap();
+
+        assert_eq!(exists, [false, true, true]);
+    }
+
+    #[test]
+    fn backing_chain() {
+        let base = temp_path("base.img");
+        let overlay = temp_path("overlay.qcow2");
+        let base_name = base.file_name().unwrap().to_str().unwrap();
+
+        fs::write(&base, vec![0u8; 1 << 20]).unwrap();
+        fs::write(&overlay, qcow2(1 << 20, Some((base_name, "raw")), 0)).unwrap();
+        let img = Image::open(&overlay, Format::Auto);
+
+        // The chain must not loop.
+        let looping = temp_path("loop.qcow2");
+        let loop_name = looping.file_name().unwrap().to_str().unwrap();
+        fs::write(&looping, qcow2(1 << 20, Some((loop_name, "qcow2")), 0)).unwrap();
+        let looped = Image::open(&looping, Format::Auto);
+
+        // Backing files must exist.
+        fs::remove_file(&base).unwrap();
+        let missing = Image::open(&overlay, Format::Auto);
+
+        fs::remove_file(&overlay).unwrap();
+        fs::remove_file(&looping).unwrap();
+
+        let backing = img.unwrap().backing.unwrap();
+        assert_eq!(backing.path, base);
+        assert_eq!(backing.format, Format::Raw);
+        assert!(format!("{:#}", looped.unwrap_err()).contains("loops"));
+        assert!(missing.is_err());
+    }
+
+    #[test]
+    fn overlay() {
+        let base_path = temp_path("base.img");
+        fs::write(&base_path, vec![0xaa; 1 << 20]).unwrap();
+        let base = Image::open(&base_path, Format::Auto).unwrap();
+        let canonical = base_path.canonicalize().unwrap();
+
+        let overlay = create_overlay(&base, &format!("{}-test", process::id())).unwrap();
+        let img = Image::open(&overlay, Format::Auto);
+        let bytes = fs::read(&overlay).unwrap();
+
+        // Overlays are never overwritten.
+        let again = create_overlay(&base, &format!("{}-test", process::id()));
+
+        fs::remove_file(&overlay).unwrap();
+        fs::remove_file(&base_path).unwrap();
+
+        let img = img.unwrap();
+        assert_eq!((img.format, img.virtual_size), (Format::Qcow2, 1 << 20));
+        assert_eq!(img.backing.unwrap().path, canonical);
+        assert!(again.is_err());
+
+        // Refcount table pointing at the refcount block, which covers the 4 metadata clusters.
+        assert_eq!(bytes.len(), 4 << 16);
+        assert_eq!(be_u64(&bytes, 1 << 16), 2 << 16);
+        assert_eq!(
+            bytes[2 << 16..(2 << 16) + 10],
+            [0, 1, 0, 1, 0, 1, 0, 1, 0, 0]
+        );
+        assert_eq!(be_u32(&bytes, 36), 1);
+    }
+
+    #[test]
+    fn read_qcow2() {
+        let base_path = temp_path("base.img");
+        let overlay_path = temp_path("overlay.qcow2");
+        fs::write(&base_path, vec![0xbb; 1 << 17]).unwrap();
+        let base = Image::open(&base_path, Format::Raw).unwrap();
+        create_qcow2(&overlay_path, 1 << 18, Some(&base)).unwrap();
+
+        // Allocate guest cluster 1: L2 table in cluster 4, data in cluster 5.
+        let file = OpenOptions::new().write(true).open(&overlay_path).unwrap();
+        file.set_len(6 << 16).unwrap();
+        file.write_all_at(&(4u64 << 16).to_be_bytes(), 3 << 16)
+            .unwrap();
+        file.write_all_at(&(5u64 << 16).to_be_bytes(), (4 << 16) + 8)
+            .unwrap();
+        file.write_all_at(&[0xcc; 1 << 16], 5 << 16).unwrap();
+
+        let mut buf = vec![0u8; 3 << 16];
+        let res = Image::open(&overlay_path, Format::Auto)
+            .and_then(|img| img.reader())
+            .and_then(|reader| reader.read_exact_at(&mut buf, 1 << 15));
+
+        fs::remove_file(&base_path).unwrap();
+        fs::remove_file(&overlay_path).unwrap();
+        res.unwrap();
+
+        // Backing file data, the allocated cluster, then zeros past the end of the backing file.
+        assert!(buf[..1 << 15].iter().all(|b| *b == 0xbb));
+        assert!(buf[1 << 15..3 << 15].iter().all(|b| *b == 0xcc));
+        assert!(buf[3 << 15..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn invalid_qcow2_rejected() {
+        let path = temp_path("bad.qcow2");
+
+        fs::write(&path, qcow2(1 << 20, None, QCOW2_DIRTY)).unwrap();
+        let dirty = Image::open(&path, Format::Auto);
+
+        fs::write(&path, qcow2(0, None, 0)).unwrap();
+        let empty = Image::open(&path, Format::Auto);
+
+        fs::write(&path, qcow2(1 << 20, None, 1 << 7)).unwrap();
+        let unknown = Image::open(&path, Format::Auto);
+
+        fs::remove_file(&path).unwrap();
+
+        assert!(format!("{:#}", dirty.unwrap_err()).contains("not closed cleanly"));
+        assert!(empty.is_err());
+        assert!(unknown.is_err());
+    }
+}
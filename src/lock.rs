@@ -0,0 +1,137 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    os::unix::io::AsRawFd,
+    path::Path,
+};
+
+use anyhow::{anyhow, Context, Result};
+
+/// Access to a locked file: shared between readers, or exclusive to a single writer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LockMode {
+    Shared,
+    Exclusive,
+}
+
+/// An advisory lock of a file, held until it is dropped. Locks are taken with flock(2), so they
+/// belong to the open file description and survive other descriptors of the file being closed.
+#[derive(Debug)]
+pub struct FileLock {
+    _file: File,
+}
+
+impl FileLock {
+    /// Lock a file without waiting, failing if another open file description holds a
+    /// conflicting lock. The kind of file is used to describe it in errors.
+    pub fn new(path: &Path, mode: LockMode, kind: &str) -> Result<Self> {
+        let file = OpenOptions::new().read(true).open(path).context(format!(
+            "unable to open {} {} to lock it",
+            kind,
+            path.display()
+        ))?;
+
+        let op = match mode {
+            LockMode::Shared => libc::LOCK_SH,
+            LockMode::Exclusive => libc::LOCK_EX,
+        };
+
+        if unsafe { libc::flock(file.as_raw_fd(), op | libc::LOCK_NB) } < 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EWOULDBLOCK) {
+                return Err(err).context(format!("unable to lock {} {}", kind, path.display()));
+            }
+
+            let holder = match holder(&file) {
+                Some(pid) => format!("process {}", pid),
+                None => "another process".to_string(),
+            };
+
+            return Err(anyhow!(
+                "{} {} is in use by {}",
+                kind,
+                path.display(),
+                holder
+            ));
+        }
+
+        Ok(Self { _file: file })
+    }
+}
+
+/// PID of a process holding a lock of the file, found in the kernel's table of file locks.
+#[cfg(target_os = "linux")]
+fn holder(file: &File) -> Option<i32> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = file.metadata().ok()?;
+    let dev = metadata.dev();
+    // Decode the device number as glibc's major() and minor() do.
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    let id = format!("{:02x}:{:02x}:{}", major, minor, metadata.ino());
+
+    // Lines look like "1: FLOCK  ADVISORY  WRITE 1234 fd:01:5678 0 EOF", with "->" after the
+    // index for processes waiting for a lock.
+    std::fs::read_to_string("/proc/locks")
+        .ok()?
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<&str>>())
+        .filter(|fields| fields.get(1) != Some(&"->"))
+        .find(|fields| fields.get(5) == Some(&id.as_str()))
+        .and_then(|fields| fields.get(4)?.parse().ok())
+        .filter(|pid| *pid > 0)
+}
+
+/// PID of a process holding a lock of the file. flock(2) locks are reported by F_GETLK on macOS.
+#[cfg(not(target_os = "linux"))]
+fn holder(file: &File) -> Option<i32> {
+    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+    lock.l_type = libc::F_WRLCK as _;
+    lock.l_whence = libc::SEEK_SET as _;
+
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETLK, &mut lock) } < 0 {
+        return None;
+    }
+
+    (lock.l_type != libc::F_UNLCK as _ && lock.l_pid > 0).then_some(lock.l_pid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_util::temp_path;
+
+    #[test]
+    fn conflicts() {
+        let path = temp_path("locked");
+        std::fs::write(&path, b"").unwrap();
+
+        let shared = FileLock::new(&path, LockMode::Shared, "file").unwrap();
+        let other_shared = FileLock::new(&path, LockMode::Shared, "file");
+        let exclusive = FileLock::new(&path, LockMode::Exclusive, "file");
+        let both_shared = other_shared.is_ok();
+        drop(shared);
+        drop(other_shared);
+
+        let exclusive_again = FileLock::new(&path, LockMode::Exclusive, "file");
+        let shared_again = FileLock::new(&path, LockMode::Shared, "file");
+
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(both_shared);
+        assert!(exclusive.is_err());
+        exclusive_again.unwrap();
+        assert_eq!(
+            shared_again.err().unwrap().to_string(),
+            format!(
+                "file {} is in use by process {}",
+                path.display(),
+                std::process::id()
+            )
+        );
+    }
+}
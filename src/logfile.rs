@@ -0,0 +1,288 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+
+/// Rotation and formatting of a log file.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LogOptions {
+    /// Size in bytes from which the log is rotated, None to never rotate it.
+    pub max_size: Option<u64>,
+
+    /// Number of rotated logs kept (path.1 being the most recent).
+    pub max_files: u32,
+
+    /// Prefix each line with the host time.
+    pub timestamps: bool,
+}
+
+/// Number of rotated logs kept by default.
+pub const DEFAULT_MAX_FILES: u32 = 5;
+
+/// A log file, rotated once it reaches its maximum size.
+pub struct RotatingLog {
+    path: PathBuf,
+    options: LogOptions,
+    file: File,
+    size: u64,
+    line_start: bool,
+
+    /// Length of the current line in this log, excluding its timestamp.
+    line_len: u64,
+}
+
+impl RotatingLog {
+    /// Open a log file, appending to it.
+    pub fn open(path: &Path, options: LogOptions) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .context(format!("unable to create log file {}", path.display()))?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            options,
+            file,
+            size,
+            line_start: true,
+            line_len: 0,
+        })
+    }
+
+    /// Append output to the log, timestamping the lines it starts and rotating the log once it
+    /// is full. Logs are rotated at line boundaries, unless a single line is longer than the
+    /// maximum size (e.g. progress bars redrawn with carriage returns), which is then split
+    /// across logs.
+    pub fn write(&mut self, mut buf: &[u8]) -> Result<()> {
+        while !buf.is_empty() {
+            if let Some(max) = self.options.max_size {
+                if self.size >= max && (self.line_start || self.line_len >= max) {
+                    self.rotate()?;
+                }
+            }
+
+            if self.line_start && self.options.timestamps {
+                let stamp = format!("[{}] ", timestamp(SystemTime::now()));
+                self.append(stamp.as_bytes())?;
+            }
+
+            let mut len = buf
+                .iter()
+                .position(|b| *b == b'\n')
+                .map_or(buf.len(), |i| i + 1);
+            if let Some(max) = self.options.max_size {
+                // Cut lines longer than a log, continuing them in the next log.
+                len = len.min((max - self.line_len) as usize);
+            }
+            let (line, rest) = buf.split_at(len);
+
+            self.append(line)?;
+            self.line_len += line.len() as u64;
+            self.line_start = line.ends_with(b"\n");
+            if self.line_start {
+                self.line_len = 0;
+            }
+            buf = rest;
+        }
+
+        Ok(())
+    }
+
+    fn append(&mut self, buf: &[u8]) -> Result<()> {
+        self.file
+            .write_all(buf)
+            .context(format!("unable to write log file {}", self.path.display()))?;
+        self.size += buf.len() as u64;
+
+        Ok(())
+    }
+
+    /// Move the log to path.1, shifting the older logs and removing the oldest, and start a new
+    /// log.
+    fn rotate(&mut self) -> Result<()> {
+        let rotated = |n: u32| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        };
+
+        if self.options.max_files == 0 {
+            let _ = fs::remove_file(&self.path);
+        } else {
+            let _ = fs::remove_file(rotated(self.options.max_files));
+            for n in (1..self.options.max_files).rev() {
+                let _ = fs::rename(rotated(n), rotated(n + 1));
+            }
+            fs::rename(&self.path, rotated(1))
+                .context(format!("unable to rotate log file {}", self.path.display()))?;
+        }
+
+        // A line split across logs is continued without a new timestamp.
+        let line_start = self.line_start;
+        *self = Self::open(&self.path, self.options)?;
+        self.line_start = line_start;
+
+        Ok(())
+    }
+}
+
+/// Local time with millisecond precision and UTC offset, e.g. 2024-02-29T12:34:56.789+01:00.
+fn timestamp(t: SystemTime) -> String {
+    let since_epoch = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs() as libc::time_t;
+
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::localtime_r(&secs, &mut tm) };
+
+    let offset = tm.tm_gmtoff / 60;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}{}{:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec,
+        since_epoch.subsec_millis(),
+        if offset < 0 { '-' } else { '+' },
+        offset.abs() / 60,
+        offset.abs() % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_util::temp_path;
+
+    #[test]
+    fn rotation() {
+        let path = temp_path("serial.log");
+        let options = LogOptions {
+            max_size: Some(10),
+            max_files: 2,
+            timestamps: false,
+        };
+
+        let mut log = RotatingLog::open(&path, options).unwrap();
+        log.write(b"line 1\nline 2\nline ").unwrap();
+        log.write(b"3\nline 4\nline 5\n").unwrap();
+        log.write(b"line 6\nline 7\n").unwrap();
+
+        let read = |p: &Path| fs::read_to_string(p).unwrap_or_default();
+        let logs: Vec<String> = ["", ".1", ".2", ".3"]
+            .iter()
+            .map(|ext| {
+                let mut name = path.clone().into_os_string();
+                name.push(ext);
+                let p = PathBuf::from(name);
+                let contents = read(&p);
+                let _ = fs::remove_file(p);
+                contents
+            })
+            .collect();
+
+        // Logs are rotated at line boundaries, and only 2 rotated logs are kept.
+        assert_eq!(
+            logs,
+            ["line 7\n", "line 5\nline 6\n", "line 3\nline 4\n", ""]
+        );
+    }
+
+    #[test]
+    fn long_line_rotation() {
+        let path = temp_path("serial.log");
+        let options = LogOptions {
+            max_size: Some(10),
+            max_files: 3,
+            timestamps: false,
+        };
+
+        // Progress output without newlines, in one write and then in several.
+        let mut log = RotatingLog::open(&path, options).unwrap();
+        log.write(&[b'a'; 25]).unwrap();
+        for _ in 0..4 {
+            log.write(b"\rb").unwrap();
+        }
+
+        let logs: Vec<String> = ["", ".1", ".2", ".3"]
+            .iter()
+            .map(|ext| {
+                let mut name = path.clone().into_os_string();
+                name.push(ext);
+                let contents = fs::read_to_string(&name).unwrap_or_default();
+                let _ = fs::remove_file(name);
+                contents
+            })
+            .collect();
+
+        // The line is split, no log growing past the maximum size.
+        assert_eq!(logs, ["b\rb", "aaaaa\rb\rb\r", "aaaaaaaaaa", "aaaaaaaaaa"]);
+    }
+
+    #[test]
+    fn line_split_across_writes() {
+        let path = temp_path("serial.log");
+        let options = LogOptions {
+            max_size: Some(50),
+            max_files: 1,
+            timestamps: true,
+        };
+
+        // The log is full in the middle of "abcd", which stays in the same log.
+        let mut log = RotatingLog::open(&path, options).unwrap();
+        log.write(b"first line\n").unwrap();
+        log.write(b"ab").unwrap();
+        log.write(b"cd\n").unwrap();
+        log.write(b"next\n").unwrap();
+
+        let mut rotated = path.clone().into_os_string();
+        rotated.push(".1");
+        let full = fs::read_to_string(&rotated).unwrap();
+        let current = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&rotated).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let lines: Vec<&str> = full.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with("] first line"));
+        assert!(lines[1].ends_with("] abcd"));
+        assert_eq!(lines[1].matches('[').count(), 1);
+        assert!(current.ends_with("] next\n"));
+        assert_eq!(current.matches('[').count(), 1);
+    }
+
+    #[test]
+    fn timestamps() {
+        let path = temp_path("serial.log");
+        let options = LogOptions {
+            timestamps: true,
+            ..Default::default()
+        };
+
+        let mut log = RotatingLog::open(&path, options).unwrap();
+        log.write(b"Booting").unwrap();
+        log.write(b" Linux\n\nlogin: ").unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let lines: Vec<&str> = contents.split('\n').collect();
+        assert_eq!(lines.len(), 3);
+        for (line, text) in lines.iter().zip(["Booting Linux", "", "login: "]) {
+            // [YYYY-MM-DDTHH:MM:SS.mmm+HH:MM] text
+            assert_eq!(&line[0..1], "[");
+            assert_eq!(&line[11..12], "T");
+            assert_eq!(&line[30..32], "] ");
+            assert_eq!(&line[32..], text);
+        }
+    }
+}
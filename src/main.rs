@@ -2,20 +2,45 @@
 
 #![allow(dead_code)]
 
+mod backend;
+mod bootloader;
 mod cmdline;
+mod config;
+mod console;
 mod context;
+mod decompress;
+mod disk;
+mod efivars;
+mod fat;
+mod forward;
+mod image;
+mod lock;
+mod logfile;
+mod partition;
+mod secureboot;
+mod state;
 mod status;
+#[cfg(test)]
+mod test_util;
+mod varstore;
 mod virtio;
 
-use cmdline::Args;
+use cmdline::{Args, Command};
 use context::KrunContext;
 
-use clap::Parser;
+use clap::CommandFactory;
 
 fn main() -> Result<(), anyhow::Error> {
+    let matches = Args::command().get_matches();
+
+    // Subcommands operate on VM files without running a VM.
+    if let Some(command) = Command::from_matches(&matches)? {
+        return command.run();
+    }
+
     // Gather the krun context from the command line arguments and configure the workload
     // accordingly.
-    let ctx = KrunContext::try_from(Args::parse())?;
+    let ctx = KrunContext::try_from(Args::from_matches(&matches)?)?;
 
     // Run the workload. If behaving properly, the main thread will not return from this
     // function.
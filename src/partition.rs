@@ -0,0 +1,405 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{image::ImageReader, varstore::Guid};
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+
+/// Size of the logical blocks addressed by MBR partition tables, and by GPTs unless 4Kn.
+pub const SECTOR_SIZE: u64 = 512;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_MIN_HEADER_LEN: usize = 92;
+const GPT_MIN_ENTRY_LEN: usize = 128;
+const GPT_MAX_ENTRY_LEN: usize = 1024;
+
+/// Logical block sizes of GPT disks, the offset of the GPT header (LBA 1) depending on it: 512
+/// bytes, and 4096 bytes for 4Kn disks.
+const GPT_BLOCK_SIZES: [u64; 2] = [512, 4096];
+
+/// Maximum number of GPT entries read, far more than any partitioning tool creates.
+const GPT_MAX_ENTRIES: u32 = 1024;
+
+/// MBR partition type of the protective partition spanning a GPT disk.
+const MBR_PROTECTIVE: u8 = 0xee;
+
+/// GPT partition type of the EFI system partition.
+pub const ESP_TYPE: Guid = Guid::new(
+    0xc12a7328,
+    0xf81f,
+    0x11d2,
+    [0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b],
+);
+
+/// Names of well-known GPT partition types.
+const GPT_TYPES: [(Guid, &str); 8] = [
+    (ESP_TYPE, "EFI system"),
+    (
+        Guid::new(
+            0x21686148,
+            0x6449,
+            0x6e6f,
+            [0x74, 0x4e, 0x65, 0x65, 0x64, 0x45, 0x46, 0x49],
+        ),
+        "BIOS boot",
+    ),
+    (
+        Guid::new(
+            0x0fc63daf,
+            0x8483,
+            0x4772,
+            [0x8e, 0x79, 0x3d, 0x69, 0xd8, 0x47, 0x7d, 0xe4],
+        ),
+        "Linux filesystem",
+    ),
+    (
+        Guid::new(
+            0xb921b045,
+            0x1df0,
+            0x41c3,
+            [0xaf, 0x44, 0x4c, 0x6f, 0x28, 0x0d, 0x3f, 0xae],
+        ),
+        "Linux root (ARM-64)",
+    ),
+    (
+        Guid::new(
+            0x4f68bce3,
+            0xe8cd,
+            0x4db1,
+            [0x96, 0xe7, 0xfb, 0xca, 0xf9, 0x84, 0xb7, 0x09],
+        ),
+        "Linux root (x86-64)",
+    ),
+    (
+        Guid::new(
+            0xbc13c2ff,
+            0x59e6,
+            0x4262,
+            [0xa3, 0x52, 0xb2, 0x75, 0xfd, 0x6f, 0x71, 0x72],
+        ),
+        "Linux extended boot",
+    ),
+    (
+        Guid::new(
+            0x0657fd6d,
+            0xa4ab,
+            0x43c4,
+            [0x84, 0xe5, 0x09, 0x33, 0xc8, 0x4b, 0x4f, 0x4f],
+        ),
+        "Linux swap",
+    ),
+    (
+        Guid::new(
+            0xe6d6d379,
+            0xf507,
+            0x44c2,
+            [0xa2, 0x3c, 0x23, 0x8f, 0x2a, 0x3d, 0xf9, 0x28],
+        ),
+        "Linux LVM",
+    ),
+];
+
+/// Partitioning scheme of a disk.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scheme {
+    Gpt,
+    Mbr,
+}
+
+/// A partition of a disk.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Partition {
+    /// Number of the partition, starting at 1.
+    pub number: u32,
+
+    /// Offset of the partition on the disk, in bytes.
+    pub start: u64,
+
+    /// Size of the partition, in bytes.
+    pub size: u64,
+
+    /// Partition type: a GUID on GPT disks, a hexadecimal byte on MBR disks.
+    #[serde(rename = "type")]
+    pub kind: String,
+
+    /// Name of a well-known partition type.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub type_name: Option<&'static str>,
+
+    /// Partition name (GPT only).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+impl Partition {
+    /// Reject a GPT partition extending past the end of the disk.
+    fn check(self, disk: &ImageReader) -> Result<Self> {
+        if self
+            .start
+            .checked_add(self.size)
+            .is_none_or(|end| end > disk.size())
+        {
+            return Err(anyhow!(
+                "partition {} is past the end of the disk",
+                self.number
+            ));
+        }
+
+        Ok(self)
+    }
+}
+
+/// Partition table of a disk.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PartitionTable {
+    pub scheme: Scheme,
+    pub partitions: Vec<Partition>,
+}
+
+impl PartitionTable {
+    /// Read the partition table of a disk, None if the disk is not partitioned.
+    pub fn read(disk: &ImageReader) -> Result<Option<Self>> {
+        if disk.size() < 2 * SECTOR_SIZE {
+            return Ok(None);
+        }
+
+        let mut mbr = [0u8; SECTOR_SIZE as usize];
+        disk.read_exact_at(&mut mbr, 0)?;
+        if mbr[510..512] != [0x55, 0xaa] {
+            return Ok(None);
+        }
+
+        let entries: Vec<&[u8]> = mbr[446..510].chunks(16).collect();
+        if entries.iter().any(|e| e[4] == MBR_PROTECTIVE) {
+            return Ok(Some(Self {
+                scheme: Scheme::Gpt,
+                partitions: read_gpt(disk)?,
+            }));
+        }
+
+        let partitions = entries
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| e[4] != 0)
+            .map(|(i, e)| Partition {
+                number: i as u32 + 1,
+                start: le_u32(e, 8) as u64 * SECTOR_SIZE,
+                size: le_u32(e, 12) as u64 * SECTOR_SIZE,
+                kind: format!("{:02x}", e[4]),
+                type_name: match e[4] {
+                    0xef => Some("EFI system"),
+                    0x83 => Some("Linux"),
+                    0x82 => Some("Linux swap"),
+                    0x8e => Some("Linux LVM"),
+                    _ => None,
+                },
+                name: None,
+            })
+            .collect();
+
+        Ok(Some(Self {
+            scheme: Scheme::Mbr,
+            partitions,
+        }))
+    }
+
+    /// The EFI system partition, if any.
+    pub fn esp(&self) -> Option<&Partition> {
+        self.partitions.iter().find(|p| match self.scheme {
+            Scheme::Gpt => p.kind == ESP_TYPE.to_string(),
+            Scheme::Mbr => p.kind == "ef",
+        })
+    }
+}
+
+/// Read the partitions of the primary GPT.
+fn read_gpt(disk: &ImageReader) -> Result<Vec<Partition>> {
+    let mut header = [0u8; SECTOR_SIZE as usize];
+    let mut block_size = None;
+    for size in GPT_BLOCK_SIZES.into_iter().filter(|s| disk.size() >= 2 * s) {
+        disk.read_exact_at(&mut header, size)?;
+        if &header[0..8] == GPT_SIGNATURE {
+            block_size = Some(size);
+            break;
+        }
+    }
+
+    let Some(block_size) = block_size else {
+        return Err(anyhow!("protective MBR found, but no GPT header"));
+    };
+
+    let header_len = le_u32(&header, 12) as usize;
+    if !(GPT_MIN_HEADER_LEN..=SECTOR_SIZE as usize).contains(&header_len) {
+        return Err(anyhow!("invalid GPT header size {}", header_len));
+    }
+
+    let mut crc_header = header[..header_len].to_vec();
+    crc_header[16..20].fill(0);
+    if crc32(&crc_header) != le_u32(&header, 16) {
+        return Err(anyhow!("GPT header checksum mismatch"));
+    }
+
+    let entries_lba = u64::from_le_bytes(header[72..80].try_into().unwrap());
+    let count = le_u32(&header, 80);
+    let entry_len = le_u32(&header, 84) as usize;
+    if count > GPT_MAX_ENTRIES
+        || !(GPT_MIN_ENTRY_LEN..=GPT_MAX_ENTRY_LEN).contains(&entry_len)
+        || !entry_len.is_multiple_of(8)
+    {
+        return Err(anyhow!("invalid GPT partition entry array"));
+    }
+
+    let entries_offset = entries_lba
+        .checked_mul(block_size)
+        .ok_or_else(|| anyhow!("invalid GPT partition entry array"))?;
+    let mut entries = vec![0u8; count as usize * entry_len];
+    disk.read_exact_at(&mut entries, entries_offset)?;
+    if crc32(&entries) != le_u32(&header, 88) {
+        return Err(anyhow!("GPT partition entries checksum mismatch"));
+    }
+
+    let partitions = entries
+        .chunks(entry_len)
+        .enumerate()
+        .filter(|(_, e)| e[0..16] != [0; 16])
+        .map(|(i, e)| {
+            let number = i as u32 + 1;
+            let kind = Guid::from_bytes(&e[0..16]);
+            let first = u64::from_le_bytes(e[32..40].try_into().unwrap());
+            let last = u64::from_le_bytes(e[40..48].try_into().unwrap());
+            let (Some(start), Some(end)) = (
+                first.checked_mul(block_size),
+                last.checked_add(1).and_then(|l| l.checked_mul(block_size)),
+            ) else {
+                return Err(anyhow!("partition {} is past the end of the disk", number));
+            };
+            let name: Vec<u16> = e[56..128]
+                .chunks(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .take_while(|c| *c != 0)
+                .collect();
+
+            Partition {
+                number,
+                start,
+                size: end.saturating_sub(start),
+                kind: kind.to_string(),
+                type_name: GPT_TYPES.iter().find(|(g, _)| *g == kind).map(|(_, n)| *n),
+                name: Some(String::from_utf16_lossy(&name)),
+            }
+            .check(disk)
+        })
+        .collect::<Result<_>>()?;
+
+    Ok(partitions)
+}
+
+fn le_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+/// CRC-32 (IEEE 802.3), as used by GPT.
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, b| {
+        (0..8).fold(crc ^ *b as u32, |crc, _| {
+            (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        image::{Format, Image},
+        test_util::{gpt_disk, gpt_disk_with_block_size, temp_path},
+    };
+
+    use std::fs;
+
+    fn read(bytes: &[u8]) -> Result<Option<PartitionTable>> {
+        let path = temp_path("disk.img");
+        fs::write(&path, bytes).unwrap();
+        let table = Image::open(&path, Format::Raw)
+            .and_then(|img| img.reader())
+            .and_then(|reader| PartitionTable::read(&reader));
+        fs::remove_file(&path).unwrap();
+
+        table
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+    }
+
+    #[test]
+    fn gpt() {
+        let disk = gpt_disk(
+            1 << 20,
+            &[
+                (ESP_TYPE, 34, 1023, "EFI"),
+                (GPT_TYPES[2].0, 1024, 2047, "root"),
+            ],
+        );
+        let table = read(&disk).unwrap().unwrap();
+
+        assert_eq!(table.scheme, Scheme::Gpt);
+        assert_eq!(table.partitions.len(), 2);
+        assert_eq!(
+            table.esp(),
+            Some(&Partition {
+                number: 1,
+                start: 34 * 512,
+                size: 990 * 512,
+                kind: "c12a7328-f81f-11d2-ba4b-00a0c93ec93b".into(),
+                type_name: Some("EFI system"),
+                name: Some("EFI".into()),
+            })
+        );
+        assert_eq!(table.partitions[1].type_name, Some("Linux filesystem"));
+
+        // Corrupted entries are detected.
+        let mut corrupted = disk.clone();
+        corrupted[1024 + 60] ^= 1;
+        assert!(read(&corrupted).is_err());
+
+        // The GPT of 4Kn disks is found at 4096 bytes.
+        let disk_4k = gpt_disk_with_block_size(4 << 20, 4096, &[(ESP_TYPE, 6, 261, "EFI")]);
+        let table = read(&disk_4k).unwrap().unwrap();
+        assert_eq!(table.scheme, Scheme::Gpt);
+        assert_eq!(
+            (table.partitions[0].start, table.partitions[0].size),
+            (6 * 4096, 256 * 4096)
+        );
+
+        // Partitions must be within the disk, without overflowing.
+        for last in [2048, u64::MAX] {
+            let past_end = gpt_disk(1 << 20, &[(ESP_TYPE, 34, last, "EFI")]);
+            assert_eq!(
+                read(&past_end).unwrap_err().to_string(),
+                "partition 1 is past the end of the disk"
+            );
+        }
+    }
+
+    #[test]
+    fn mbr() {
+        let mut disk = vec![0u8; 1 << 20];
+        disk[446 + 4] = 0x83;
+        disk[446 + 8..446 + 12].copy_from_slice(&2048u32.to_le_bytes());
+        disk[446 + 12..446 + 16].copy_from_slice(&4096u32.to_le_bytes());
+        disk[510..512].copy_from_slice(&[0x55, 0xaa]);
+
+        let table = read(&disk).unwrap().unwrap();
+        assert_eq!(table.scheme, Scheme::Mbr);
+        assert_eq!(table.partitions[0].start, 2048 * 512);
+        assert_eq!(table.partitions[0].type_name, Some("Linux"));
+        assert!(table.esp().is_none());
+
+        assert_eq!(read(&[0u8; 4096]).unwrap(), None);
+    }
+}
@@ -0,0 +1,375 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    efivars::EFI_GLOBAL_VARIABLE,
+    varstore::{Guid, Variable, VariableStore},
+};
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+/// Vendor GUID of the db and dbx variables.
+const IMAGE_SECURITY_DATABASE_GUID: Guid = Guid::new(
+    0xd719b2cb,
+    0x3d3a,
+    0x4596,
+    [0xa3, 0xbc, 0xda, 0xd0, 0x0e, 0x67, 0x65, 0x6f],
+);
+
+/// Vendor GUID of EDK II's SecureBootEnable variable.
+const SECURE_BOOT_ENABLE_GUID: Guid = Guid::new(
+    0xf0a30bc7,
+    0xaf08,
+    0x4556,
+    [0x99, 0xc4, 0x00, 0x10, 0x09, 0xc9, 0x3a, 0x44],
+);
+
+/// Vendor GUID of EDK II's CustomMode variable.
+const CUSTOM_MODE_GUID: Guid = Guid::new(
+    0xc076ec0c,
+    0x7028,
+    0x4399,
+    [0xa0, 0x72, 0x71, 0xee, 0x5c, 0x44, 0x8b, 0x9f],
+);
+
+/// Signature type of X.509 certificates.
+const CERT_X509_GUID: Guid = Guid::new(
+    0xa5c059a1,
+    0x94e4,
+    0x4aa7,
+    [0x87, 0xb5, 0xab, 0x15, 0x5c, 0x2b, 0xf0, 0x72],
+);
+
+/// Owner recorded in the signatures enrolled by krunkit.
+const KRUNKIT_OWNER_GUID: Guid = Guid::new(
+    0x8c5d2e4b,
+    0x6a1f,
+    0x4b0e,
+    [0x9d, 0x3c, 0x7f, 0x21, 0x5e, 0x84, 0xa6, 0x0b],
+);
+
+const ATTR_NV_BS: u32 = 0x03;
+const ATTR_NV_BS_RT_TIME_AUTH: u32 = 0x27;
+
+/// Secure Boot key databases, with the name of their variable and vendor GUID.
+const KEY_DATABASES: [(&str, Guid, bool); 4] = [
+    ("PK", EFI_GLOBAL_VARIABLE, true),
+    ("KEK", EFI_GLOBAL_VARIABLE, true),
+    ("db", IMAGE_SECURITY_DATABASE_GUID, true),
+    ("dbx", IMAGE_SECURITY_DATABASE_GUID, false),
+];
+
+/// Certificate file extensions, searched in order.
+const CERT_EXTENSIONS: [&str; 4] = ["pem", "crt", "cer", "der"];
+
+/// Enroll the Secure Boot keys found in a directory into the variable store.
+///
+/// The directory holds a certificate file for each key database, named after its variable (PK,
+/// KEK, db and the optional dbx) with a .pem, .crt, .cer or .der extension. Files may be PEM
+/// (possibly holding several certificates) or DER encoded.
+pub fn enroll(store: &mut VariableStore, keys_dir: &Path) -> Result<()> {
+    if !keys_dir.is_dir() {
+        return Err(anyhow!(
+            "Secure Boot keys directory {} not found",
+            keys_dir.display()
+        ));
+    }
+
+    let timestamp = efi_time(SystemTime::now());
+
+    for (name, guid, required) in KEY_DATABASES {
+        let Some(path) = cert_file(keys_dir, name) else {
+            if required {
+                return Err(anyhow!(
+                    "no {} certificate found in {} (expected {}.pem, .crt, .cer or .der)",
+                    name,
+                    keys_dir.display(),
+                    name
+                ));
+            }
+            continue;
+        };
+
+        let certs = load_certs(&path)?;
+        if name == "PK" && certs.len() != 1 {
+            return Err(anyhow!(
+                "{} must hold exactly one platform key certificate, found {}",
+                path.display(),
+                certs.len()
+            ));
+        }
+
+        let data = certs.iter().flat_map(|c| signature_list(c)).collect();
+        store.set(Variable {
+            timestamp,
+            ..Variable::new(name, guid, ATTR_NV_BS_RT_TIME_AUTH, data)
+        });
+    }
+
+    store.set(Variable::new(
+        "SecureBootEnable",
+        SECURE_BOOT_ENABLE_GUID,
+        ATTR_NV_BS,
+        vec![1],
+    ));
+    store.set(Variable::new(
+        "CustomMode",
+        CUSTOM_MODE_GUID,
+        ATTR_NV_BS,
+        vec![0],
+    ));
+
+    Ok(())
+}
+
+/// Find the certificate file of a key database.
+fn cert_file(dir: &Path, name: &str) -> Option<PathBuf> {
+    CERT_EXTENSIONS
+        .iter()
+        .map(|ext| dir.join(format!("{}.{}", name, ext)))
+        .find(|p| p.is_file())
+}
+
+/// Load the DER-encoded certificates of a PEM or DER file.
+fn load_certs(path: &Path) -> Result<Vec<Vec<u8>>> {
+    let bytes = fs::read(path).context(format!("unable to read {}", path.display()))?;
+
+    let certs = if bytes.starts_with(b"-----BEGIN") || bytes.starts_with(b"\xef\xbb\xbf-----") {
+        pem_certs(&String::from_utf8_lossy(&bytes))
+            .context(format!("invalid PEM file {}", path.display()))?
+    } else {
+        vec![bytes]
+    };
+
+    if certs.is_empty() {
+        return Err(anyhow!("no certificate found in {}", path.display()));
+    }
+
+    for cert in &certs {
+        check_der_cert(cert).context(format!(
+            "{} does not hold a valid X.509 certificate",
+            path.display()
+        ))?;
+    }
+
+    Ok(certs)
+}
+
+/// Decode the CERTIFICATE blocks of a PEM file.
+fn pem_certs(pem: &str) -> Result<Vec<Vec<u8>>> {
+    let mut certs = Vec::new();
+    let mut block: Option<String> = None;
+
+    for line in pem.lines().map(str::trim) {
+        match (&mut block, line) {
+            (None, "-----BEGIN CERTIFICATE-----") => block = Some(String::new()),
+            (Some(b64), "-----END CERTIFICATE-----") => {
+                certs.push(STANDARD.decode(b64.as_bytes())?);
+                block = None;
+            }
+            (Some(b64), _) => b64.push_str(line),
+            (None, _) => (),
+        }
+    }
+
+    if block.is_some() {
+        return Err(anyhow!("unterminated certificate block"));
+    }
+
+    Ok(certs)
+}
+
+/// Check that the data is a DER-encoded X.509 certificate: a SEQUENCE spanning the whole data
+/// and starting with the SEQUENCE of the certificate's contents.
+fn check_der_cert(der: &[u8]) -> Result<()> {
+    let (len, header_len) = der_sequence(der).ok_or_else(|| anyhow!("not a DER SEQUENCE"))?;
+    if header_len + len != der.len() {
+        return Err(anyhow!("DER length mismatch"));
+    }
+
+    der_sequence(&der[header_len..]).ok_or_else(|| anyhow!("no certificate contents found"))?;
+
+    Ok(())
+}
+
+/// Parse the header of a DER SEQUENCE, returning the length of its contents and of the header.
+fn der_sequence(der: &[u8]) -> Option<(usize, usize)> {
+    if *der.first()? != 0x30 {
+        return None;
+    }
+
+    let first = *der.get(1)? as usize;
+    if first < 0x80 {
+        return Some((first, 2));
+    }
+
+    let n = first & 0x7f;
+    if n == 0 || n > 4 {
+        return None;
+    }
+
+    let len = der
+        .get(2..2 + n)?
+        .iter()
+        .fold(0usize, |acc, b| (acc << 8) | *b as usize);
+
+    Some((len, 2 + n))
+}
+
+/// Build an EFI_SIGNATURE_LIST holding a single X.509 certificate.
+fn signature_list(cert: &[u8]) -> Vec<u8> {
+    // Header: signature type, list size, signature header size and signature size, followed by
+    // the signature's owner and data.
+    let sig_size = 16 + cert.len();
+    let list_size = 28 + sig_size;
+
+    let mut list = Vec::with_capacity(list_size);
+    list.extend_from_slice(CERT_X509_GUID.as_bytes());
+    list.extend_from_slice(&(list_size as u32).to_le_bytes());
+    list.extend_from_slice(&0u32.to_le_bytes());
+    list.extend_from_slice(&(sig_size as u32).to_le_bytes());
+    list.extend_from_slice(KRUNKIT_OWNER_GUID.as_bytes());
+    list.extend_from_slice(cert);
+
+    list
+}
+
+/// Encode a time as an EFI_TIME in UTC.
+fn efi_time(t: SystemTime) -> [u8; 16] {
+    let secs = t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (days, rem) = (secs / 86400, secs % 86400);
+
+    // Convert days since the epoch to a civil date (Howard Hinnant's algorithm).
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = (yoe + era * 400 + i64::from(month <= 2)) as u16;
+
+    let mut time = [0u8; 16];
+    time[0..2].copy_from_slice(&year.to_le_bytes());
+    time[2] = month;
+    time[3] = day;
+    time[4] = (rem / 3600) as u8;
+    time[5] = (rem % 3600 / 60) as u8;
+    time[6] = (rem % 60) as u8;
+
+    time
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_util::temp_path;
+
+    use std::time::Duration;
+
+    /// A minimal DER certificate-like structure: a SEQUENCE holding a SEQUENCE.
+    fn der_cert(content: &[u8]) -> Vec<u8> {
+        let mut inner = vec![0x30, content.len() as u8];
+        inner.extend_from_slice(content);
+
+        let mut der = vec![0x30, inner.len() as u8];
+        der.extend(inner);
+
+        der
+    }
+
+    fn pem(certs: &[Vec<u8>]) -> String {
+        certs
+            .iter()
+            .map(|c| {
+                format!(
+                    "-----BEGIN CERTIFICATE-----\n{}\n-----END CERTIFICATE-----\n",
+                    STANDARD.encode(c)
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn enroll_keys() {
+        let dir = temp_path("keys");
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("PK.pem"), pem(&[der_cert(b"pk")])).unwrap();
+        fs::write(dir.join("KEK.der"), der_cert(b"kek")).unwrap();
+        fs::write(
+            dir.join("db.crt"),
+            pem(&[der_cert(b"db1"), der_cert(b"db2")]),
+        )
+        .unwrap();
+
+        let mut store = VariableStore::default();
+        let res = enroll(&mut store, &dir);
+        fs::remove_dir_all(&dir).unwrap();
+        res.unwrap();
+
+        let pk = store.get("PK", &EFI_GLOBAL_VARIABLE).unwrap();
+        assert_eq!(pk.attributes, ATTR_NV_BS_RT_TIME_AUTH);
+        assert_eq!(pk.data, signature_list(&der_cert(b"pk")));
+        assert_eq!(&pk.data[..16], CERT_X509_GUID.as_bytes());
+        assert_eq!(pk.data.len(), 28 + 16 + 6);
+
+        let db = store.get("db", &IMAGE_SECURITY_DATABASE_GUID).unwrap();
+        assert_eq!(db.data.len(), 2 * (28 + 16 + 7));
+        assert!(store.get("dbx", &IMAGE_SECURITY_DATABASE_GUID).is_none());
+
+        let enable = store
+            .get("SecureBootEnable", &SECURE_BOOT_ENABLE_GUID)
+            .unwrap();
+        assert_eq!(enable.data, vec![1]);
+
+        // The enrolled keys survive being written to and read from a store file.
+        let parsed = VariableStore::parse(&store.to_bytes().unwrap()).unwrap();
+        assert_eq!(parsed, store);
+    }
+
+    #[test]
+    fn invalid_keys_rejected() {
+        let dir = temp_path("keys");
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("PK.der"), der_cert(b"pk")).unwrap();
+        fs::write(dir.join("KEK.der"), der_cert(b"kek")).unwrap();
+
+        // db is missing.
+        let missing = enroll(&mut VariableStore::default(), &dir);
+
+        // db is not a certificate.
+        fs::write(dir.join("db.der"), b"not a certificate").unwrap();
+        let invalid = enroll(&mut VariableStore::default(), &dir);
+
+        // Only a single platform key is allowed.
+        fs::write(dir.join("db.der"), der_cert(b"db")).unwrap();
+        fs::remove_file(dir.join("PK.der")).unwrap();
+        fs::write(dir.join("PK.pem"), pem(&[der_cert(b"a"), der_cert(b"b")])).unwrap();
+        let two_pks = enroll(&mut VariableStore::default(), &dir);
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(missing
+            .unwrap_err()
+            .to_string()
+            .starts_with("no db certificate"));
+        assert!(invalid.is_err());
+        assert!(two_pks.is_err());
+    }
+
+    #[test]
+    fn efi_time_encoding() {
+        // 2024-02-29 12:34:56 UTC.
+        let t = UNIX_EPOCH + Duration::from_secs(1709210096);
+
+        assert_eq!(efi_time(t)[..7], [0xe8, 0x07, 2, 29, 12, 34, 56]);
+    }
+}
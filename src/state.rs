@@ -0,0 +1,241 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::anyhow;
+use serde_json::{json, Value};
+
+/// Lifecycle of a krun VM.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VmStatus {
+    Starting,
+    Running,
+    Stopping,
+    Stopped,
+    Error,
+}
+
+impl VmStatus {
+    /// Check if the VM is allowed to move from this status to another.
+    fn can_transition(self, to: Self) -> bool {
+        matches!(
+            (self, to),
+            (Self::Starting, Self::Running)
+                | (Self::Starting, Self::Stopped)
+                | (Self::Running, Self::Stopping)
+                | (Self::Running, Self::Stopped)
+                | (Self::Stopping, Self::Stopped)
+                | (Self::Starting | Self::Running | Self::Stopping, Self::Error)
+        )
+    }
+}
+
+/// Status names as reported by the RESTful service (matching vfkit's).
+impl fmt::Display for VmStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Starting => "VirtualMachineStateStarting",
+            Self::Running => "VirtualMachineStateRunning",
+            Self::Stopping => "VirtualMachineStateStopping",
+            Self::Stopped => "VirtualMachineStateStopped",
+            Self::Error => "VirtualMachineStateError",
+        };
+
+        write!(f, "{}", s)
+    }
+}
+
+/// State of the VM, shared between the thread running the workload and the status listener.
+#[derive(Clone, Debug)]
+pub struct VmState(Arc<Mutex<Inner>>);
+
+#[derive(Debug)]
+struct Inner {
+    status: VmStatus,
+
+    /// Time of the latest status change.
+    since: SystemTime,
+
+    /// Time the VM state was created.
+    created: SystemTime,
+
+    /// Reason of the failure, if the VM is in the Error status.
+    error: Option<String>,
+}
+
+impl Default for VmState {
+    fn default() -> Self {
+        let now = SystemTime::now();
+
+        Self(Arc::new(Mutex::new(Inner {
+            status: VmStatus::Starting,
+            since: now,
+            created: now,
+            error: None,
+        })))
+    }
+}
+
+impl VmState {
+    /// Current status of the VM.
+    pub fn status(&self) -> VmStatus {
+        self.0.lock().unwrap().status
+    }
+
+    /// Move the VM to a new status. Fails if the transition is not allowed from the current
+    /// status.
+    pub fn transition(&self, to: VmStatus) -> Result<(), anyhow::Error> {
+        let mut inner = self.0.lock().unwrap();
+
+        if !inner.status.can_transition(to) {
+            return Err(anyhow!(
+                "invalid VM state transition from {} to {}",
+                inner.status,
+                to
+            ));
+        }
+
+        inner.status = to;
+        inner.since = SystemTime::now();
+
+        Ok(())
+    }
+
+    /// Move the VM to the Error status, recording the reason of the failure.
+    pub fn fail(&self, err: &anyhow::Error) {
+        let mut inner = self.0.lock().unwrap();
+
+        inner.status = VmStatus::Error;
+        inner.since = SystemTime::now();
+        inner.error = Some(format!("{:#}", err));
+    }
+
+    /// JSON description of the state and the state changes it allows.
+    pub fn to_json(&self) -> Value {
+        let inner = self.0.lock().unwrap();
+
+        let mut value = json!({
+            "state": inner.status.to_string(),
+            "canStart": false,
+            "canPause": false,
+            "canResume": false,
+            "canStop": inner.status == VmStatus::Running,
+            "canHardStop": matches!(inner.status, VmStatus::Running | VmStatus::Stopping),
+            "stateChangedAt": unix_secs(inner.since),
+            "createdAt": unix_secs(inner.created),
+        });
+
+        if let Some(error) = &inner.error {
+            value["error"] = json!(error);
+        }
+
+        value
+    }
+}
+
+/// Seconds elapsed since the UNIX epoch.
+fn unix_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    const ALL: [VmStatus; 5] = [
+        VmStatus::Starting,
+        VmStatus::Running,
+        VmStatus::Stopping,
+        VmStatus::Stopped,
+        VmStatus::Error,
+    ];
+
+    #[test]
+    fn transitions() {
+        use VmStatus::*;
+
+        let allowed = [
+            (Starting, Running),
+            (Starting, Stopped),
+            (Starting, Error),
+            (Running, Stopping),
+            (Running, Stopped),
+            (Running, Error),
+            (Stopping, Stopped),
+            (Stopping, Error),
+        ];
+
+        for from in ALL {
+            for to in ALL {
+                assert_eq!(
+                    from.can_transition(to),
+                    allowed.contains(&(from, to)),
+                    "{} to {}",
+                    from,
+                    to
+                );
+            }
+        }
+
+        let state = VmState::default();
+        assert_eq!(state.status(), Starting);
+        let err = state.transition(Stopping).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid VM state transition from VirtualMachineStateStarting to VirtualMachineStateStopping"
+        );
+        assert_eq!(state.status(), Starting);
+
+        state.transition(Running).unwrap();
+        state.transition(Stopping).unwrap();
+        assert!(state.transition(Running).is_err());
+        state.transition(Stopped).unwrap();
+
+        // Failures are recorded from any status, even once stopped.
+        state.fail(&anyhow!("guest crashed"));
+        assert_eq!(state.status(), Error);
+        assert!(state.transition(Running).is_err());
+    }
+
+    #[test]
+    fn json() {
+        let state = VmState::default();
+        let created = state.to_json();
+        assert_eq!(created["state"], "VirtualMachineStateStarting");
+        assert_eq!(created["stateChangedAt"], created["createdAt"]);
+        assert_eq!(created["canStop"], false);
+        assert!(created.get("error").is_none());
+
+        // Timestamps are in seconds since the UNIX epoch, and only the state change time moves.
+        state.0.lock().unwrap().since -= Duration::from_secs(60);
+        state.0.lock().unwrap().created -= Duration::from_secs(60);
+        state.transition(VmStatus::Running).unwrap();
+        let running = state.to_json();
+        let now = unix_secs(SystemTime::now());
+        assert!(now - running["stateChangedAt"].as_u64().unwrap() <= 1);
+        assert_eq!(
+            running["createdAt"].as_u64().unwrap(),
+            created["createdAt"].as_u64().unwrap() - 60
+        );
+        assert_eq!(running["canStop"], true);
+        assert_eq!(running["canHardStop"], true);
+
+        state
+            .fail(&anyhow!("unable to read disk").context("unable to begin running krun workload"));
+        let failed = state.to_json();
+        assert_eq!(failed["state"], "VirtualMachineStateError");
+        assert_eq!(
+            failed["error"],
+            "unable to begin running krun workload: unable to read disk"
+        );
+        assert_eq!(failed["canHardStop"], false);
+    }
+}
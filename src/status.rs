@@ -1,61 +1,649 @@
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::{
+    cmdline::Args,
+    state::{VmState, VmStatus},
+};
+
 use std::{
-    fs::File,
-    io::{Read, Write},
-    net::{Ipv4Addr, TcpListener},
-    os::fd::{FromRawFd, RawFd},
+    fs::{self, File},
+    io::{self, Read, Write},
+    net::TcpListener,
+    os::{
+        fd::{FromRawFd, RawFd},
+        unix::{
+            fs::FileTypeExt,
+            net::{UnixListener, UnixStream},
+        },
+    },
+    path::{Path, PathBuf},
+    str::FromStr,
 };
 
-#[link(name = "krun-efi")]
-extern "C" {
-    fn krun_get_shutdown_eventfd(ctx_id: u32) -> i32;
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// Maximum size of a request's headers and body.
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+
+/// Default URI of the status/shutdown listener.
+pub const DEFAULT_RESTFUL_URI: &str = "tcp://localhost:8081";
+
+/// URI of the status/shutdown listener.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub enum RestfulUri {
+    /// Listen on a TCP address, stored as "host:port".
+    Tcp(String),
+
+    /// Listen on a UNIX socket at the given path.
+    Unix(PathBuf),
+
+    /// Do not start a status/shutdown listener.
+    None,
+}
+
+impl FromStr for RestfulUri {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.to_lowercase() == "none" {
+            return Ok(Self::None);
+        }
+
+        let (scheme, rest) = s
+            .split_once("://")
+            .ok_or_else(|| anyhow!("invalid restful URI {}: expected scheme://address", s))?;
+
+        match &scheme.to_lowercase()[..] {
+            "tcp" => {
+                let (host, port) = rest
+                    .rsplit_once(':')
+                    .ok_or_else(|| anyhow!("restful URI {} does not specify a port", s))?;
+
+                if host.is_empty() {
+                    return Err(anyhow!("restful URI {} does not specify a host", s));
+                }
+
+                u16::from_str(port).context(format!("invalid port in restful URI {}", s))?;
+
+                Ok(Self::Tcp(rest.to_string()))
+            }
+            "unix" => {
+                if rest.is_empty() {
+                    return Err(anyhow!("restful URI {} does not specify a socket path", s));
+                }
+
+                Ok(Self::Unix(PathBuf::from(rest)))
+            }
+            _ => Err(anyhow!("invalid restful URI scheme: {}", scheme)),
+        }
+    }
+}
+
+impl Default for RestfulUri {
+    fn default() -> Self {
+        Self::from_str(DEFAULT_RESTFUL_URI).unwrap()
+    }
 }
 
-const HTTP_RUNNING: &str =
-    "HTTP/1.1 200 OK\r\nContent-type: application/json\r\n\r\n{\"state\": \"VirtualMachineStateRunning\"}\0";
+impl TryFrom<String> for RestfulUri {
+    type Error = anyhow::Error;
 
-const HTTP_STOPPING: &str =
-    "HTTP/1.1 200 OK\r\nContent-type: application/json\r\n\r\n{\"state\": \"VirtualMachineStateStopping\"}\0";
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Self::from_str(&s)
+    }
+}
+
+impl RestfulUri {
+    /// Bind a listener to the URI. Returns None if the listener is disabled.
+    pub fn bind(&self) -> Result<Option<StatusListener>, anyhow::Error> {
+        match self {
+            Self::Tcp(addr) => {
+                let listener = TcpListener::bind(addr)
+                    .context(format!("unable to bind restful listener to tcp://{}", addr))?;
 
-/// Retrieve the shutdown event file descriptor initialized by libkrun.
-pub unsafe fn get_shutdown_eventfd(ctx_id: u32) -> i32 {
-    let fd = krun_get_shutdown_eventfd(ctx_id);
-    if fd < 0 {
-        panic!("unable to retrieve krun shutdown file descriptor");
+                Ok(Some(StatusListener::Tcp(listener)))
+            }
+            Self::Unix(path) => {
+                remove_stale_socket(path)?;
+                let listener = UnixListener::bind(path).context(format!(
+                    "unable to bind restful listener to unix://{}",
+                    path.display()
+                ))?;
+
+                Ok(Some(StatusListener::Unix(listener)))
+            }
+            Self::None => Ok(None),
+        }
+    }
+}
+
+/// Remove a UNIX socket left behind by a process that is no longer running, so that its path
+/// can be bound again. Sockets still accepting connections are in use and left untouched.
+pub fn remove_stale_socket(path: &Path) -> Result<(), anyhow::Error> {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return Ok(());
+    };
+    if !metadata.file_type().is_socket() {
+        return Ok(());
+    }
+
+    match UnixStream::connect(path) {
+        Ok(_) => Err(anyhow!(
+            "socket {} is in use by another process",
+            path.display()
+        )),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path)
+            .context(format!("unable to remove stale socket {}", path.display())),
+        Err(_) => Ok(()),
     }
-    fd
+}
+
+/// A bound listener for status and shutdown requests.
+pub enum StatusListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
 }
 
 /// Listen for status and shutdown requests from the client. Shut down the krun VM when prompted.
-pub fn status_listener(shutdown_eventfd: RawFd) -> Result<(), anyhow::Error> {
+pub fn status_listener(
+    listener: StatusListener,
+    shutdown_eventfd: RawFd,
+    args: Args,
+    state: VmState,
+) -> Result<(), anyhow::Error> {
     // VM is shut down by writing to the shutdown event file.
     let mut shutdown = unsafe { File::from_raw_fd(shutdown_eventfd) };
 
-    let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, 8081)).unwrap();
-
-    for stream in listener.incoming() {
-        let mut buf = [0u8; 4096];
-        let mut stream = stream.unwrap();
-
-        match stream.read(&mut buf) {
-            Ok(_sz) => {
-                let request = String::from_utf8_lossy(&buf);
-                if request.contains("POST") {
-                    // Shut down the VM.
-                    if let Err(e) = stream.write_all(HTTP_STOPPING.as_bytes()) {
-                        println!("Error writting POST response: {e}");
-                    }
-                    if let Err(e) = shutdown.write_all(&1u64.to_le_bytes()) {
-                        println!("Error writting to shutdown fd: {e}");
-                    }
-                } else if let Err(e) = stream.write_all(HTTP_RUNNING.as_bytes()) {
-                    println!("Error writting GET response: {e}");
+    match listener {
+        StatusListener::Tcp(listener) => {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => handle_connection(stream, &mut shutdown, &args, &state),
+                    Err(e) => println!("Error accepting connection: {e}"),
+                }
+            }
+        }
+        StatusListener::Unix(listener) => {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => handle_connection(stream, &mut shutdown, &args, &state),
+                    Err(e) => println!("Error accepting connection: {e}"),
                 }
             }
-            Err(e) => println!("Error reading stream: {}", e),
         }
     }
 
     Ok(())
 }
+
+/// Serve a single request read from a client connection.
+fn handle_connection<S: Read + Write>(
+    mut stream: S,
+    shutdown: &mut File,
+    args: &Args,
+    state: &VmState,
+) {
+    let response = match Request::read(&mut stream) {
+        Ok(request) => route(&request, shutdown, args, state),
+        Err(e) => Response::error(400, &e.to_string()),
+    };
+
+    if let Err(e) = stream.write_all(&response.to_bytes()) {
+        println!("Error writting response: {e}");
+    }
+
+    // A hard stop terminates krunkit only after the client has been answered.
+    if response.hard_stop {
+        std::process::exit(0);
+    }
+}
+
+/// Dispatch a request to the handler of its path and method.
+fn route(request: &Request, shutdown: &mut File, args: &Args, state: &VmState) -> Response {
+    match (request.path.as_str(), request.method.as_str()) {
+        ("/vm/state", "GET") => Response::json(200, state.to_json()),
+        ("/vm/state", "POST") => set_vm_state(&request.body, shutdown, state),
+        ("/vm/state", _) => Response::method_not_allowed("GET, POST"),
+        ("/vm/inspect", "GET") => Response::json(200, vm_inspect(args)),
+        ("/vm/inspect", _) => Response::method_not_allowed("GET"),
+        _ => Response::error(404, &format!("no such resource: {}", request.path)),
+    }
+}
+
+/// A state change requested by the client.
+#[derive(Deserialize)]
+struct StateChange {
+    state: String,
+}
+
+/// Handle a POST to /vm/state, changing the state of the VM as requested.
+fn set_vm_state(body: &[u8], shutdown: &mut File, state: &VmState) -> Response {
+    let change: StateChange = match serde_json::from_slice(body) {
+        Ok(c) => c,
+        Err(e) => return Response::error(400, &format!("invalid state change request: {e}")),
+    };
+
+    match change.state.as_str() {
+        "Stop" => {
+            if let Err(e) = state.transition(VmStatus::Stopping) {
+                return Response::error(409, &e.to_string());
+            }
+
+            // Shut down the VM.
+            if let Err(e) = shutdown.write_all(&1u64.to_le_bytes()) {
+                println!("Error writting to shutdown fd: {e}");
+                state.fail(&anyhow!(e).context("unable to request VM shutdown"));
+                return Response::error(500, "unable to request VM shutdown");
+            }
+
+            Response::json(202, state.to_json())
+        }
+        "HardStop" => {
+            if let Err(e) = state.transition(VmStatus::Stopped) {
+                return Response::error(409, &e.to_string());
+            }
+
+            let mut response = Response::json(202, state.to_json());
+            response.hard_stop = true;
+
+            response
+        }
+        "Pause" | "Resume" => Response::error(
+            501,
+            &format!("state change {} not supported by libkrun", change.state),
+        ),
+        s => Response::error(400, &format!("invalid VM state: {s}")),
+    }
+}
+
+/// JSON body describing the VM configuration.
+fn vm_inspect(args: &Args) -> Value {
+    json!({
+        "cpus": args.cpus,
+        "memory": args.memory,
+        "bootloader": args.bootloader,
+        "devices": args.devices,
+    })
+}
+
+/// A parsed HTTP request.
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+impl Request {
+    /// Read and parse an HTTP request from a stream.
+    fn read<S: Read>(stream: &mut S) -> Result<Self, anyhow::Error> {
+        let mut buf = Vec::new();
+        let mut chunk = [0u8; 4096];
+
+        // Read until the end of the headers is found.
+        let header_end = loop {
+            if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                break pos + 4;
+            }
+
+            if buf.len() > MAX_REQUEST_SIZE {
+                return Err(anyhow!("request headers too large"));
+            }
+
+            let sz = stream.read(&mut chunk).context("unable to read request")?;
+            if sz == 0 {
+                return Err(anyhow!("incomplete request"));
+            }
+            buf.extend_from_slice(&chunk[..sz]);
+        };
+
+        let head = String::from_utf8_lossy(&buf[..header_end]).to_string();
+        let mut lines = head.split("\r\n");
+
+        let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+        let (method, target) = match (request_line.next(), request_line.next()) {
+            (Some(m), Some(t)) => (m.to_string(), t),
+            _ => return Err(anyhow!("malformed request line")),
+        };
+
+        // Ignore any query string.
+        let path = target.split('?').next().unwrap_or_default().to_string();
+
+        let mut content_length = 0;
+        for line in lines {
+            if let Some((name, value)) = line.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("content-length") {
+                    content_length =
+                        usize::from_str(value.trim()).context("invalid Content-Length header")?;
+                }
+            }
+        }
+
+        if content_length > MAX_REQUEST_SIZE {
+            return Err(anyhow!("request body too large"));
+        }
+
+        let mut body = buf[header_end..].to_vec();
+        while body.len() < content_length {
+            let sz = stream
+                .read(&mut chunk)
+                .context("unable to read request body")?;
+            if sz == 0 {
+                return Err(anyhow!("incomplete request body"));
+            }
+            body.extend_from_slice(&chunk[..sz]);
+        }
+        body.truncate(content_length);
+
+        Ok(Self { method, path, body })
+    }
+}
+
+/// An HTTP response with a JSON body.
+struct Response {
+    status: u16,
+    allow: Option<&'static str>,
+    body: Value,
+    hard_stop: bool,
+}
+
+impl Response {
+    fn json(status: u16, body: Value) -> Self {
+        Self {
+            status,
+            allow: None,
+            body,
+            hard_stop: false,
+        }
+    }
+
+    fn error(status: u16, msg: &str) -> Self {
+        Self::json(status, json!({ "error": msg }))
+    }
+
+    fn method_not_allowed(allow: &'static str) -> Self {
+        let mut response = Self::error(405, "method not allowed");
+        response.allow = Some(allow);
+
+        response
+    }
+
+    /// Serialize the response to be written to the client.
+    fn to_bytes(&self) -> Vec<u8> {
+        let reason = match self.status {
+            200 => "OK",
+            202 => "Accepted",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            409 => "Conflict",
+            501 => "Not Implemented",
+            _ => "Internal Server Error",
+        };
+
+        let body = self.body.to_string();
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+            self.status,
+            reason,
+            body.len()
+        );
+        if let Some(allow) = self.allow {
+            head.push_str(&format!("Allow: {allow}\r\n"));
+        }
+        head.push_str("\r\n");
+
+        [head.into_bytes(), body.into_bytes()].concat()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_util::temp_path;
+
+    use clap::Parser;
+    use std::io::Cursor;
+
+    /// A client connection, with the raw request to read and the response written.
+    struct Connection {
+        request: Cursor<Vec<u8>>,
+        response: Vec<u8>,
+    }
+
+    impl Read for Connection {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.request.read(buf)
+        }
+    }
+
+    impl Write for Connection {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.response.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn args() -> Args {
+        Args::try_parse_from([
+            "krunkit",
+            "--cpus",
+            "2",
+            "--memory",
+            "1024",
+            "--bootloader",
+            "efi,variable-store=/tmp/vstore,create",
+        ])
+        .unwrap()
+    }
+
+    /// Serve a raw HTTP request, returning the raw response and what was written to the
+    /// shutdown file.
+    fn serve_raw(request: &str, state: &VmState) -> (String, Vec<u8>) {
+        let path = temp_path("shutdown");
+        let mut shutdown = File::create(&path).unwrap();
+        let mut conn = Connection {
+            request: Cursor::new(request.as_bytes().to_vec()),
+            response: Vec::new(),
+        };
+
+        handle_connection(&mut conn, &mut shutdown, &args(), state);
+        let written = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        (String::from_utf8(conn.response).unwrap(), written)
+    }
+
+    /// Serve a raw HTTP request, returning the status line and the JSON body of the response,
+    /// and what was written to the shutdown file.
+    fn serve(request: &str, state: &VmState) -> (String, Value, Vec<u8>) {
+        let (response, written) = serve_raw(request, state);
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.lines().next().unwrap().to_string();
+
+        (status, serde_json::from_str(body).unwrap(), written)
+    }
+
+    fn post_state(state_change: &str) -> String {
+        let body = format!("{{\"state\": \"{}\"}}", state_change);
+        format!(
+            "POST /vm/state HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    }
+
+    fn running() -> VmState {
+        let state = VmState::default();
+        state.transition(VmStatus::Running).unwrap();
+
+        state
+    }
+
+    #[test]
+    fn get_state_and_inspect() {
+        let state = running();
+        let (status, body, _) = serve("GET /vm/state HTTP/1.1\r\n\r\n", &state);
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(body["state"], "VirtualMachineStateRunning");
+        assert_eq!(body["canStop"], true);
+        assert_eq!(body["canPause"], false);
+
+        let (status, body, _) = serve("GET /vm/inspect?verbose HTTP/1.1\r\n\r\n", &state);
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(body["cpus"], 2);
+        assert_eq!(body["memory"], 1024);
+        assert_eq!(body["devices"], json!([]));
+    }
+
+    #[test]
+    fn stop() {
+        let state = running();
+        let (status, body, written) = serve(&post_state("Stop"), &state);
+        assert_eq!(status, "HTTP/1.1 202 Accepted");
+        assert_eq!(body["state"], "VirtualMachineStateStopping");
+        assert_eq!(written, 1u64.to_le_bytes());
+
+        // The VM is already stopping.
+        let (status, body, written) = serve(&post_state("Stop"), &state);
+        assert_eq!(status, "HTTP/1.1 409 Conflict");
+        assert!(body["error"]
+            .as_str()
+            .unwrap()
+            .contains("invalid VM state transition"));
+        assert!(written.is_empty());
+    }
+
+    #[test]
+    fn hard_stop() {
+        // Serving a hard stop exits the process, so only route the request.
+        let state = running();
+        let path = temp_path("shutdown");
+        let mut shutdown = File::create(&path).unwrap();
+        let request = Request::read(&mut post_state("HardStop").as_bytes()).unwrap();
+        let response = route(&request, &mut shutdown, &args(), &state);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(response.status, 202);
+        assert!(response.hard_stop);
+        assert_eq!(response.body["state"], "VirtualMachineStateStopped");
+
+        // A stopped VM cannot be stopped again.
+        let (status, _, _) = serve(&post_state("HardStop"), &state);
+        assert_eq!(status, "HTTP/1.1 409 Conflict");
+    }
+
+    #[test]
+    fn unsupported_and_invalid_requests() {
+        let state = running();
+
+        for change in ["Pause", "Resume"] {
+            let (status, body, _) = serve(&post_state(change), &state);
+            assert_eq!(status, "HTTP/1.1 501 Not Implemented");
+            assert_eq!(
+                body["error"],
+                format!("state change {} not supported by libkrun", change)
+            );
+        }
+
+        let (status, body, _) = serve(&post_state("Reboot"), &state);
+        assert_eq!(status, "HTTP/1.1 400 Bad Request");
+        assert_eq!(body["error"], "invalid VM state: Reboot");
+
+        let malformed = "POST /vm/state HTTP/1.1\r\nContent-Length: 7\r\n\r\n{state:";
+        let (status, body, _) = serve(malformed, &state);
+        assert_eq!(status, "HTTP/1.1 400 Bad Request");
+        assert!(body["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("invalid state change request"));
+
+        let (status, _, _) = serve("garbage\r\n\r\n", &state);
+        assert_eq!(status, "HTTP/1.1 400 Bad Request");
+
+        let (status, body, _) = serve("GET /vm/unknown HTTP/1.1\r\n\r\n", &state);
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+        assert_eq!(body["error"], "no such resource: /vm/unknown");
+
+        let (status, _, _) = serve("DELETE /vm/state HTTP/1.1\r\n\r\n", &state);
+        assert_eq!(status, "HTTP/1.1 405 Method Not Allowed");
+        let (response, _) = serve_raw("PUT /vm/inspect HTTP/1.1\r\n\r\n", &state);
+        assert!(response.contains("\r\nAllow: GET\r\n"));
+
+        // The state is unchanged by the rejected requests.
+        assert_eq!(state.status(), VmStatus::Running);
+    }
+
+    #[test]
+    fn parse_uri() {
+        assert_eq!(
+            RestfulUri::from_str("tcp://localhost:8081").unwrap(),
+            RestfulUri::Tcp("localhost:8081".into())
+        );
+        assert_eq!(
+            RestfulUri::from_str("TCP://[::1]:80").unwrap(),
+            RestfulUri::Tcp("[::1]:80".into())
+        );
+        assert_eq!(
+            RestfulUri::from_str("unix:///run/krunkit.sock").unwrap(),
+            RestfulUri::Unix("/run/krunkit.sock".into())
+        );
+        assert_eq!(RestfulUri::from_str("None").unwrap(), RestfulUri::None);
+        assert_eq!(
+            RestfulUri::default(),
+            RestfulUri::Tcp("localhost:8081".into())
+        );
+
+        for (uri, err) in [
+            (
+                "localhost:8081",
+                "invalid restful URI localhost:8081: expected scheme://address",
+            ),
+            (
+                "tcp://localhost",
+                "restful URI tcp://localhost does not specify a port",
+            ),
+            (
+                "tcp://:8081",
+                "restful URI tcp://:8081 does not specify a host",
+            ),
+            (
+                "tcp://localhost:http",
+                "invalid port in restful URI tcp://localhost:http",
+            ),
+            (
+                "unix://",
+                "restful URI unix:// does not specify a socket path",
+            ),
+            ("http://localhost:80", "invalid restful URI scheme: http"),
+        ] {
+            assert_eq!(RestfulUri::from_str(uri).unwrap_err().to_string(), err);
+        }
+    }
+
+    #[test]
+    fn stale_unix_socket() {
+        let path = temp_path("restful.sock");
+        let uri = RestfulUri::Unix(path.clone());
+
+        // A socket left by a crashed krunkit is replaced.
+        drop(UnixListener::bind(&path).unwrap());
+        let listener = uri.bind();
+        assert!(matches!(listener, Ok(Some(StatusListener::Unix(_)))));
+
+        // A socket of a running krunkit is not.
+        let err = uri.bind().err().unwrap();
+        drop(listener);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            err.to_string(),
+            format!("socket {} is in use by another process", path.display())
+        );
+    }
+}
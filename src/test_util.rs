@@ -0,0 +1,168 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{
+    bootloader::default_loader,
+    fat::{short_name, ATTR_DIRECTORY, DIR_ENTRY_LEN},
+    partition::{crc32, ESP_TYPE},
+    varstore::Guid,
+};
+
+use std::{
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// A path in the temporary directory, unique to the calling test.
+pub fn temp_path(name: &str) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    std::env::temp_dir().join(format!(
+        "krunkit-test-{}-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed),
+        name
+    ))
+}
+
+/// A disk image with a GPT holding the given partitions (type, first and last LBA, name), with
+/// 512-byte logical blocks.
+pub fn gpt_disk(size: u64, partitions: &[(Guid, u64, u64, &str)]) -> Vec<u8> {
+    gpt_disk_with_block_size(size, 512, partitions)
+}
+
+/// A disk image with a GPT holding the given partitions, with logical blocks of the given size.
+pub fn gpt_disk_with_block_size(
+    size: u64,
+    block: usize,
+    partitions: &[(Guid, u64, u64, &str)],
+) -> Vec<u8> {
+    let mut disk = vec![0u8; size as usize];
+
+    // Protective MBR.
+    disk[446 + 4] = 0xee;
+    disk[446 + 8..446 + 12].copy_from_slice(&1u32.to_le_bytes());
+    disk[510..512].copy_from_slice(&[0x55, 0xaa]);
+
+    let mut entries = vec![0u8; 128 * 128];
+    for (i, (kind, first, last, name)) in partitions.iter().enumerate() {
+        let e = &mut entries[i * 128..(i + 1) * 128];
+        e[0..16].copy_from_slice(kind.as_bytes());
+        e[32..40].copy_from_slice(&first.to_le_bytes());
+        e[40..48].copy_from_slice(&last.to_le_bytes());
+        for (j, c) in name.encode_utf16().enumerate() {
+            e[56 + j * 2..58 + j * 2].copy_from_slice(&c.to_le_bytes());
+        }
+    }
+    disk[2 * block..2 * block + entries.len()].copy_from_slice(&entries);
+
+    let h = &mut disk[block..2 * block];
+    h[0..8].copy_from_slice(b"EFI PART");
+    h[8..12].copy_from_slice(&0x10000u32.to_le_bytes());
+    h[12..16].copy_from_slice(&92u32.to_le_bytes());
+    h[72..80].copy_from_slice(&2u64.to_le_bytes());
+    h[80..84].copy_from_slice(&128u32.to_le_bytes());
+    h[84..88].copy_from_slice(&128u32.to_le_bytes());
+    h[88..92].copy_from_slice(&crc32(&entries).to_le_bytes());
+    let crc = crc32(&h[..92]);
+    h[16..20].copy_from_slice(&crc.to_le_bytes());
+
+    disk
+}
+
+/// A 1 MiB FAT12 file system (512-byte clusters, 2 FATs of 6 sectors, 224 root entries)
+/// holding the given files, in directories created as needed.
+pub fn fat_image(files: &[(&str, &[u8])]) -> Vec<u8> {
+    const CLUSTER: usize = 512;
+    const FAT: usize = 512;
+    const ROOT: usize = FAT + 2 * 6 * 512;
+    const DATA: usize = ROOT + 224 * DIR_ENTRY_LEN;
+
+    let mut fs = vec![0u8; 1 << 20];
+    fs[11..13].copy_from_slice(&512u16.to_le_bytes());
+    fs[13] = 1;
+    fs[14..16].copy_from_slice(&1u16.to_le_bytes());
+    fs[16] = 2;
+    fs[17..19].copy_from_slice(&224u16.to_le_bytes());
+    fs[19..21].copy_from_slice(&2048u16.to_le_bytes());
+    fs[22..24].copy_from_slice(&6u16.to_le_bytes());
+    fs[510..512].copy_from_slice(&[0x55, 0xaa]);
+
+    let mut next = 2u32;
+    let mut dirs: Vec<(String, u32)> = Vec::new();
+
+    // Add an entry to a directory (None for the root), returning its first cluster.
+    let mut add = |fs: &mut Vec<u8>, parent: Option<u32>, name: &str, attr: u8, len: usize| {
+        let clusters = len.div_ceil(CLUSTER).max(1) as u32;
+        let first = next;
+        next += clusters;
+
+        for c in first..first + clusters {
+            let value = if c + 1 == first + clusters {
+                0xfff
+            } else {
+                c + 1
+            };
+            let offset = FAT + (c as usize * 3) / 2;
+            let mut v = u16::from_le_bytes([fs[offset], fs[offset + 1]]);
+            v = if c & 1 == 1 {
+                (v & 0x000f) | ((value as u16) << 4)
+            } else {
+                (v & 0xf000) | value as u16
+            };
+            fs[offset..offset + 2].copy_from_slice(&v.to_le_bytes());
+        }
+
+        let dir = match parent {
+            None => ROOT,
+            Some(c) => DATA + (c as usize - 2) * CLUSTER,
+        };
+        let slot = (dir..)
+            .step_by(DIR_ENTRY_LEN)
+            .find(|o| fs[*o] == 0)
+            .unwrap();
+        fs[slot..slot + 11].copy_from_slice(&short_name(name).unwrap());
+        fs[slot + 11] = attr;
+        fs[slot + 26..slot + 28].copy_from_slice(&(first as u16).to_le_bytes());
+        fs[slot + 28..slot + 32].copy_from_slice(&(len as u32).to_le_bytes());
+
+        first
+    };
+
+    for (path, data) in files {
+        let components: Vec<&str> = path.split('\\').filter(|c| !c.is_empty()).collect();
+        let mut parent = None;
+        for (i, name) in components.iter().enumerate() {
+            let dir_path = components[..=i].join("\\");
+            if i + 1 < components.len() {
+                parent = Some(match dirs.iter().find(|(p, _)| *p == dir_path) {
+                    Some((_, c)) => *c,
+                    None => {
+                        let c = add(&mut fs, parent, name, ATTR_DIRECTORY, 0);
+                        dirs.push((dir_path, c));
+                        c
+                    }
+                });
+            } else {
+                let c = add(&mut fs, parent, name, 0x20, data.len());
+                let offset = DATA + (c as usize - 2) * CLUSTER;
+                fs[offset..offset + data.len()].copy_from_slice(data);
+            }
+        }
+    }
+
+    fs
+}
+
+/// A 4 MiB GPT disk image with a 1 MiB EFI system partition, holding the default bootloader
+/// of the host architecture if requested.
+pub fn esp_disk(loader: bool) -> Vec<u8> {
+    let files: &[(&str, &[u8])] = match (loader, default_loader()) {
+        (true, Some(path)) => &[(path, b"MZ")],
+        _ => &[("\\EFI\\fedora\\shim.efi", b"MZ")],
+    };
+
+    let mut disk = gpt_disk(4 << 20, &[(ESP_TYPE, 2048, 4095, "EFI")]);
+    disk[1 << 20..2 << 20].copy_from_slice(&fat_image(files));
+
+    disk
+}
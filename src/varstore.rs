@@ -0,0 +1,654 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::efivars::hex_decode;
+
+use std::{fmt, fs, path::Path, str::FromStr};
+
+use anyhow::{anyhow, Context, Result};
+
+/// Size of a newly created variable store file.
+pub const VARSTORE_SIZE: usize = 0x40000;
+
+/// Size of the blocks described by the firmware volume block map.
+const BLOCK_SIZE: u32 = 0x1000;
+
+const FV_SIGNATURE: &[u8; 4] = b"_FVH";
+const FV_HEADER_LEN: usize = 72;
+const FV_ATTRIBUTES: u32 = 0x0004_feff;
+const FV_REVISION: u8 = 2;
+
+const VARSTORE_HEADER_LEN: usize = 28;
+const VARSTORE_FORMATTED: u8 = 0x5a;
+const VARSTORE_HEALTHY: u8 = 0xfe;
+
+const VAR_START_ID: u16 = 0x55aa;
+const VAR_HEADER_LEN: usize = 60;
+const VAR_ADDED: u8 = 0x3f;
+const VAR_IN_DELETED_TRANSITION: u8 = 0xfe;
+
+/// Firmware volume file system holding non-volatile variables.
+const NV_DATA_FV_GUID: Guid = Guid::new(
+    0xfff12b8d,
+    0x7696,
+    0x4c8b,
+    [0xa9, 0x85, 0x27, 0x47, 0x07, 0x5b, 0x4f, 0x50],
+);
+
+/// Variable store holding authenticated variables.
+const AUTHENTICATED_VARIABLE_GUID: Guid = Guid::new(
+    0xaaf32c78,
+    0x947b,
+    0x439a,
+    [0xa1, 0x80, 0x2e, 0x14, 0x4e, 0xc3, 0x77, 0x92],
+);
+
+/// Variable store holding variables without authentication data.
+const VARIABLE_GUID: Guid = Guid::new(
+    0xddcf3616,
+    0x3275,
+    0x4164,
+    [0x98, 0xb6, 0xfe, 0x85, 0x70, 0x7f, 0xfe, 0x7d],
+);
+
+/// An EFI GUID.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Guid([u8; 16]);
+
+impl Guid {
+    pub const fn new(d1: u32, d2: u16, d3: u16, d4: [u8; 8]) -> Self {
+        let a = d1.to_le_bytes();
+        let b = d2.to_le_bytes();
+        let c = d3.to_le_bytes();
+
+        Self([
+            a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], d4[0], d4[1], d4[2], d4[3], d4[4],
+            d4[5], d4[6], d4[7],
+        ])
+    }
+
+    pub fn from_bytes(b: &[u8]) -> Self {
+        let mut guid = [0u8; 16];
+        guid.copy_from_slice(&b[..16]);
+
+        Self(guid)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let b = &self.0;
+
+        write!(
+            f,
+            "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            u16::from_le_bytes([b[4], b[5]]),
+            u16::from_le_bytes([b[6], b[7]]),
+            b[8],
+            b[9],
+            b[10],
+            b[11],
+            b[12],
+            b[13],
+            b[14],
+            b[15]
+        )
+    }
+}
+
+impl FromStr for Guid {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split('-').collect();
+        let lens: Vec<usize> = parts.iter().map(|p| p.len()).collect();
+        if lens != [8, 4, 4, 4, 12] {
+            return Err(anyhow!("invalid GUID: {}", s));
+        }
+
+        let hex = hex_decode(&parts.concat()).context(format!("invalid GUID: {}", s))?;
+        let mut bytes = [0u8; 16];
+        bytes.copy_from_slice(&hex);
+
+        // The first three fields are stored little-endian.
+        bytes[0..4].reverse();
+        bytes[4..6].reverse();
+        bytes[6..8].reverse();
+
+        Ok(Self(bytes))
+    }
+}
+
+/// An EFI variable.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Variable {
+    pub name: String,
+    pub guid: Guid,
+    pub attributes: u32,
+    pub monotonic_count: u64,
+
+    /// EFI_TIME of the latest update of a time-based authenticated variable.
+    pub timestamp: [u8; 16],
+    pub pubkey_index: u32,
+    pub data: Vec<u8>,
+}
+
+impl Variable {
+    pub fn new(name: &str, guid: Guid, attributes: u32, data: Vec<u8>) -> Self {
+        Self {
+            name: name.to_string(),
+            guid,
+            attributes,
+            monotonic_count: 0,
+            timestamp: [0; 16],
+            pubkey_index: 0,
+            data,
+        }
+    }
+
+    /// Name encoded as a NULL-terminated UCS-2 string.
+    fn encoded_name(&self) -> Vec<u8> {
+        self.name
+            .encode_utf16()
+            .chain([0])
+            .flat_map(u16::to_le_bytes)
+            .collect()
+    }
+}
+
+/// The contents of an EFI variable store. The store file is laid out as an EDK II non-volatile
+/// variable firmware volume holding authenticated variables.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VariableStore {
+    pub variables: Vec<Variable>,
+
+    /// Contents of the store file with the variables erased: the firmware volume and variable
+    /// store headers, and any areas following the variable store (such as the fault tolerant
+    /// write areas of OVMF variable stores), written back unchanged.
+    layout: Vec<u8>,
+
+    /// Offset of the variable store header in the file.
+    store_offset: usize,
+
+    /// Length of the variable store, from its header.
+    store_len: usize,
+}
+
+impl Default for VariableStore {
+    fn default() -> Self {
+        let mut layout = vec![0xffu8; VARSTORE_SIZE];
+
+        // Firmware volume header, with the volume spanning the whole file.
+        let header = &mut layout[..FV_HEADER_LEN];
+        header[..16].fill(0);
+        header[16..32].copy_from_slice(NV_DATA_FV_GUID.as_bytes());
+        header[32..40].copy_from_slice(&(VARSTORE_SIZE as u64).to_le_bytes());
+        header[40..44].copy_from_slice(FV_SIGNATURE);
+        header[44..48].copy_from_slice(&FV_ATTRIBUTES.to_le_bytes());
+        header[48..50].copy_from_slice(&(FV_HEADER_LEN as u16).to_le_bytes());
+        header[50..52].fill(0);
+        header[52..55].fill(0);
+        header[55] = FV_REVISION;
+        header[56..60].copy_from_slice(&(VARSTORE_SIZE as u32 / BLOCK_SIZE).to_le_bytes());
+        header[60..64].copy_from_slice(&BLOCK_SIZE.to_le_bytes());
+        header[64..72].fill(0);
+        let sum = checksum(header);
+        header[50..52].copy_from_slice(&0u16.wrapping_sub(sum).to_le_bytes());
+
+        // Variable store header, with the store filling the rest of the volume.
+        let store_len = VARSTORE_SIZE - FV_HEADER_LEN;
+        let store = &mut layout[FV_HEADER_LEN..];
+        store[..16].copy_from_slice(AUTHENTICATED_VARIABLE_GUID.as_bytes());
+        store[16..20].copy_from_slice(&(store_len as u32).to_le_bytes());
+        store[20] = VARSTORE_FORMATTED;
+        store[21] = VARSTORE_HEALTHY;
+        store[22..28].fill(0);
+
+        Self {
+            variables: Vec::new(),
+            layout,
+            store_offset: FV_HEADER_LEN,
+            store_len,
+        }
+    }
+}
+
+impl VariableStore {
+    /// Read a variable store file.
+    pub fn load(path: &Path) -> Result<Self> {
+        let bytes =
+            fs::read(path).context(format!("unable to read variable store {}", path.display()))?;
+
+        Self::parse(&bytes).context(format!("invalid variable store {}", path.display()))
+    }
+
+    /// Write the variable store to a file.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, self.to_bytes()?)
+            .context(format!("unable to write variable store {}", path.display()))
+    }
+
+    /// Parse the contents of a variable store file.
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < FV_HEADER_LEN + VARSTORE_HEADER_LEN {
+            return Err(anyhow!("file too small"));
+        }
+
+        if &bytes[40..44] != FV_SIGNATURE || Guid::from_bytes(&bytes[16..]) != NV_DATA_FV_GUID {
+            return Err(anyhow!("no non-volatile variable firmware volume found"));
+        }
+
+        let fv_len = u64::from_le_bytes(bytes[32..40].try_into().unwrap());
+        let header_len = u16::from_le_bytes([bytes[48], bytes[49]]) as usize;
+        if fv_len > bytes.len() as u64 || header_len < FV_HEADER_LEN || header_len as u64 > fv_len {
+            return Err(anyhow!("invalid firmware volume header"));
+        }
+        let fv_len = fv_len as usize;
+
+        if checksum(&bytes[..header_len]) != 0 {
+            return Err(anyhow!("firmware volume header checksum mismatch"));
+        }
+
+        let store = &bytes[header_len..fv_len];
+        if store.len() < VARSTORE_HEADER_LEN {
+            return Err(anyhow!("variable store header truncated"));
+        }
+
+        let store_guid = Guid::from_bytes(store);
+        if store_guid != AUTHENTICATED_VARIABLE_GUID && store_guid != VARIABLE_GUID {
+            return Err(anyhow!("unknown variable store format {}", store_guid));
+        }
+        let authenticated = store_guid == AUTHENTICATED_VARIABLE_GUID;
+
+        let store_len = read_u32(store, 16) as usize;
+        if store_len < VARSTORE_HEADER_LEN
+            || store_len > store.len()
+            || store[20] != VARSTORE_FORMATTED
+        {
+            return Err(anyhow!("variable store not formatted"));
+        }
+        let store = &store[..store_len];
+
+        let mut variables = Vec::new();
+        let mut off = VARSTORE_HEADER_LEN;
+        while off + 2 <= store.len()
+            && u16::from_le_bytes([store[off], store[off + 1]]) == VAR_START_ID
+        {
+            let (var, len) = parse_variable(&store[off..], authenticated).context(format!(
+                "invalid variable at offset {:#x}",
+                header_len + off
+            ))?;
+
+            if let Some(var) = var {
+                variables.push(var);
+            }
+
+            off = align4(off + len);
+        }
+
+        // Variables are always written back with authentication data.
+        let mut layout = bytes.to_vec();
+        layout[header_len..header_len + 16].copy_from_slice(AUTHENTICATED_VARIABLE_GUID.as_bytes());
+        layout[header_len + VARSTORE_HEADER_LEN..header_len + store_len].fill(0xff);
+
+        Ok(Self {
+            variables,
+            layout,
+            store_offset: header_len,
+            store_len,
+        })
+    }
+
+    /// Serialize the variable store, with deleted variables removed. Only the variables are
+    /// rewritten, the rest of the file is kept as it was read.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = self.layout.clone();
+        let store = &mut bytes[self.store_offset..self.store_offset + self.store_len];
+
+        let mut off = VARSTORE_HEADER_LEN;
+        for var in &self.variables {
+            let name = var.encoded_name();
+            let len = VAR_HEADER_LEN + name.len() + var.data.len();
+            if off + len > self.store_len {
+                return Err(anyhow!("variables do not fit in the variable store"));
+            }
+
+            let v = &mut store[off..off + len];
+            v[0..2].copy_from_slice(&VAR_START_ID.to_le_bytes());
+            v[2] = VAR_ADDED;
+            v[3] = 0;
+            v[4..8].copy_from_slice(&var.attributes.to_le_bytes());
+            v[8..16].copy_from_slice(&var.monotonic_count.to_le_bytes());
+            v[16..32].copy_from_slice(&var.timestamp);
+            v[32..36].copy_from_slice(&var.pubkey_index.to_le_bytes());
+            v[36..40].copy_from_slice(&(name.len() as u32).to_le_bytes());
+            v[40..44].copy_from_slice(&(var.data.len() as u32).to_le_bytes());
+            v[44..60].copy_from_slice(var.guid.as_bytes());
+            v[60..60 + name.len()].copy_from_slice(&name);
+            v[60 + name.len()..].copy_from_slice(&var.data);
+
+            off = align4(off + len);
+        }
+
+        Ok(bytes)
+    }
+
+    /// Find a variable by name and vendor GUID.
+    pub fn get(&self, name: &str, guid: &Guid) -> Option<&Variable> {
+        self.variables
+            .iter()
+            .find(|v| v.name == name && v.guid == *guid)
+    }
+
+    /// Add a variable, replacing any existing variable of the same name and vendor GUID.
+    pub fn set(&mut self, var: Variable) {
+        match self
+            .variables
+            .iter_mut()
+            .find(|v| v.name == var.name && v.guid == var.guid)
+        {
+            Some(existing) => *existing = var,
+            None => self.variables.push(var),
+        }
+    }
+
+    /// Remove a variable. Returns whether the variable existed.
+    pub fn remove(&mut self, name: &str, guid: &Guid) -> bool {
+        let len = self.variables.len();
+        self.variables
+            .retain(|v| !(v.name == name && v.guid == *guid));
+
+        self.variables.len() != len
+    }
+}
+
+/// Parse a variable at the start of the buffer, returning it (or None if it is deleted) along with
+/// the length it occupies.
+fn parse_variable(b: &[u8], authenticated: bool) -> Result<(Option<Variable>, usize)> {
+    // Variable stores without authentication data have a shorter header, lacking the monotonic
+    // count, timestamp and public key index.
+    let header_len = if authenticated { VAR_HEADER_LEN } else { 32 };
+    if b.len() < header_len {
+        return Err(anyhow!("variable header truncated"));
+    }
+
+    let state = b[2];
+    let attributes = read_u32(b, 4);
+    let (monotonic_count, timestamp, pubkey_index, sizes) = if authenticated {
+        let mut timestamp = [0u8; 16];
+        timestamp.copy_from_slice(&b[16..32]);
+
+        (
+            u64::from_le_bytes(b[8..16].try_into().unwrap()),
+            timestamp,
+            read_u32(b, 32),
+            36,
+        )
+    } else {
+        (0, [0; 16], 0, 8)
+    };
+    let name_len = read_u32(b, sizes) as usize;
+    let data_len = read_u32(b, sizes + 4) as usize;
+    let guid = Guid::from_bytes(&b[sizes + 8..]);
+
+    let len = header_len + name_len + data_len;
+    if b.len() < len || !name_len.is_multiple_of(2) {
+        return Err(anyhow!("variable truncated"));
+    }
+
+    if state != VAR_ADDED && state != (VAR_ADDED & VAR_IN_DELETED_TRANSITION) {
+        return Ok((None, len));
+    }
+
+    let name: Vec<u16> = b[header_len..header_len + name_len]
+        .chunks(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|c| *c != 0)
+        .collect();
+    let name = String::from_utf16(&name).context("variable name not valid UCS-2")?;
+
+    let var = Variable {
+        name,
+        guid,
+        attributes,
+        monotonic_count,
+        timestamp,
+        pubkey_index,
+        data: b[header_len + name_len..len].to_vec(),
+    };
+
+    Ok((Some(var), len))
+}
+
+fn read_u32(b: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(b[off..off + 4].try_into().unwrap())
+}
+
+fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+/// Sum of the buffer's 16-bit words.
+fn checksum(b: &[u8]) -> u16 {
+    b.chunks(2)
+        .map(|c| u16::from_le_bytes([c[0], *c.get(1).unwrap_or(&0)]))
+        .fold(0u16, |acc, w| acc.wrapping_add(w))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VENDOR_GUID: Guid = Guid::new(
+        0x12345678,
+        0x9abc,
+        0xdef0,
+        [0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef],
+    );
+
+    fn sample_store() -> VariableStore {
+        let mut store = VariableStore::default();
+        store.set(Variable::new(
+            "BootOrder",
+            Guid::from_str("8be4df61-93ca-11d2-aa0d-00e098032b8c").unwrap(),
+            0x7,
+            vec![0x01, 0x00, 0x00, 0x00],
+        ));
+        store.set(Variable {
+            monotonic_count: 3,
+            timestamp: [0x07, 0xe8, 1, 2, 3, 4, 5, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            ..Variable::new("Custom", VENDOR_GUID, 0x27, vec![0xaa, 0xbb, 0xcc])
+        });
+
+        store
+    }
+
+    /// Replace the variable store header and variables of a serialized store.
+    fn with_variables(store_guid: Guid, vars: &[(u8, Vec<u8>)]) -> Vec<u8> {
+        let mut bytes = VariableStore::default().to_bytes().unwrap();
+        let store = &mut bytes[FV_HEADER_LEN..];
+        store[..16].copy_from_slice(store_guid.as_bytes());
+
+        let mut off = VARSTORE_HEADER_LEN;
+        for (state, var) in vars {
+            store[off..off + var.len()].copy_from_slice(var);
+            store[off + 2] = *state;
+            off = align4(off + var.len());
+        }
+
+        bytes
+    }
+
+    /// A variable laid out without authentication data.
+    fn plain_variable(name: &str, guid: &Guid, data: &[u8]) -> Vec<u8> {
+        let name: Vec<u8> = name
+            .encode_utf16()
+            .chain([0])
+            .flat_map(u16::to_le_bytes)
+            .collect();
+
+        let mut var = Vec::new();
+        var.extend_from_slice(&VAR_START_ID.to_le_bytes());
+        var.extend_from_slice(&[VAR_ADDED, 0]);
+        var.extend_from_slice(&7u32.to_le_bytes());
+        var.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        var.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        var.extend_from_slice(guid.as_bytes());
+        var.extend_from_slice(&name);
+        var.extend_from_slice(data);
+
+        var
+    }
+
+    #[test]
+    fn guid() {
+        let s = "8be4df61-93ca-11d2-aa0d-00e098032b8c";
+        let guid = Guid::from_str(s).unwrap();
+
+        assert_eq!(&guid.as_bytes()[..4], &[0x61, 0xdf, 0xe4, 0x8b]);
+        assert_eq!(guid.to_string(), s);
+        assert!(Guid::from_str("8be4df61-93ca-11d2-aa0d").is_err());
+        assert!(Guid::from_str("8be4df61-93ca-11d2-aa0d-00e098032bé").is_err());
+    }
+
+    #[test]
+    fn round_trip() {
+        let empty = VariableStore::default();
+        assert_eq!(
+            VariableStore::parse(&empty.to_bytes().unwrap()).unwrap(),
+            empty
+        );
+
+        let store = sample_store();
+        let bytes = store.to_bytes().unwrap();
+        let parsed = VariableStore::parse(&bytes).unwrap();
+
+        assert_eq!(parsed, store);
+        assert_eq!(parsed.to_bytes().unwrap(), bytes);
+    }
+
+    #[test]
+    fn set_and_remove() {
+        let mut store = sample_store();
+        store.set(Variable::new("Custom", VENDOR_GUID, 0x7, vec![0x01]));
+        assert_eq!(store.variables.len(), 2);
+        assert_eq!(store.get("Custom", &VENDOR_GUID).unwrap().data, vec![0x01]);
+
+        assert!(store.remove("Custom", &VENDOR_GUID));
+        assert!(!store.remove("Custom", &VENDOR_GUID));
+
+        let parsed = VariableStore::parse(&store.to_bytes().unwrap()).unwrap();
+        assert_eq!(parsed.variables.len(), 1);
+    }
+
+    #[test]
+    fn deleted_variables_skipped() {
+        let vars = [
+            (0x3c, plain_variable("Deleted", &VENDOR_GUID, &[1])),
+            (0x3e, plain_variable("Updating", &VENDOR_GUID, &[2, 3])),
+            (VAR_ADDED, plain_variable("Added", &VENDOR_GUID, &[4])),
+        ];
+        let store = VariableStore::parse(&with_variables(VARIABLE_GUID, &vars)).unwrap();
+
+        let names: Vec<&str> = store.variables.iter().map(|v| v.name.as_str()).collect();
+        assert_eq!(names, ["Updating", "Added"]);
+        assert_eq!(store.variables[0].data, vec![2, 3]);
+
+        // Stores without authentication data are rewritten as authenticated stores.
+        let rewritten = VariableStore::parse(&store.to_bytes().unwrap()).unwrap();
+        assert_eq!(rewritten, store);
+    }
+
+    /// A variable store laid out as OVMF's OVMF_VARS.fd: the firmware volume spans the variable
+    /// store followed by the fault tolerant write working and spare areas.
+    fn ovmf_store() -> Vec<u8> {
+        const FV_LEN: usize = 0x84000;
+        const STORE_LEN: usize = 0x40000 - FV_HEADER_LEN;
+
+        let mut bytes = vec![0xffu8; FV_LEN];
+        let header = &mut bytes[..FV_HEADER_LEN];
+        header[..16].fill(0);
+        header[16..32].copy_from_slice(NV_DATA_FV_GUID.as_bytes());
+        header[32..40].copy_from_slice(&(FV_LEN as u64).to_le_bytes());
+        header[40..44].copy_from_slice(FV_SIGNATURE);
+        header[44..48].copy_from_slice(&0x0004_feffu32.to_le_bytes());
+        header[48..50].copy_from_slice(&(FV_HEADER_LEN as u16).to_le_bytes());
+        header[50..56].copy_from_slice(&[0, 0, 0, 0, 0, FV_REVISION]);
+        header[56..60].copy_from_slice(&0x84u32.to_le_bytes());
+        header[60..64].copy_from_slice(&0x1000u32.to_le_bytes());
+        header[64..72].fill(0);
+        let sum = checksum(header);
+        header[50..52].copy_from_slice(&0u16.wrapping_sub(sum).to_le_bytes());
+
+        let store = &mut bytes[FV_HEADER_LEN..];
+        store[..16].copy_from_slice(AUTHENTICATED_VARIABLE_GUID.as_bytes());
+        store[16..20].copy_from_slice(&(STORE_LEN as u32).to_le_bytes());
+        store[20..28].copy_from_slice(&[VARSTORE_FORMATTED, VARSTORE_HEALTHY, 0, 0, 0, 0, 0, 0]);
+
+        // Fault tolerant write working block header (EDKII_WORKING_BLOCK_SIGNATURE_GUID) and
+        // spare area contents, opaque to the variable store.
+        let working = Guid::new(
+            0x9e58292b,
+            0x7c68,
+            0x497d,
+            [0xa0, 0xce, 0x65, 0x00, 0xfd, 0x9f, 0x1b, 0x95],
+        );
+        bytes[0x40000..0x40010].copy_from_slice(working.as_bytes());
+        bytes[0x40010..0x40020].copy_from_slice(&[
+            0x2c, 0x1b, 0x7a, 0x09, 0xfe, 0xff, 0xff, 0xff, 0xe0, 0x0f, 0, 0, 0, 0, 0, 0,
+        ]);
+        for (i, b) in bytes[0x42000..].iter_mut().enumerate() {
+            *b = i as u8;
+        }
+
+        bytes
+    }
+
+    #[test]
+    fn ovmf_layout_preserved() {
+        let original = ovmf_store();
+        let mut store = VariableStore::parse(&original).unwrap();
+        assert!(store.variables.is_empty());
+        assert_eq!(store.to_bytes().unwrap(), original);
+
+        store.set(Variable::new("Custom", VENDOR_GUID, 0x7, vec![0xaa; 16]));
+        let bytes = store.to_bytes().unwrap();
+        assert_eq!(bytes.len(), original.len());
+
+        // The firmware volume and variable store headers, and the fault tolerant write areas
+        // after the store, are unchanged.
+        let vars = FV_HEADER_LEN + VARSTORE_HEADER_LEN;
+        assert_eq!(bytes[..vars], original[..vars]);
+        assert_eq!(bytes[0x40000..], original[0x40000..]);
+
+        let parsed = VariableStore::parse(&bytes).unwrap();
+        assert_eq!(parsed, store);
+
+        // Removing the variable erases it.
+        store.remove("Custom", &VENDOR_GUID);
+        assert_eq!(store.to_bytes().unwrap(), original);
+
+        // Variables never spill into the areas after the store.
+        store.set(Variable::new("Large", VENDOR_GUID, 0x7, vec![0; 0x40000]));
+        assert!(store.to_bytes().is_err());
+    }
+
+    #[test]
+    fn corrupted_store_rejected() {
+        let mut bytes = sample_store().to_bytes().unwrap();
+        bytes[44] ^= 0xff;
+        assert!(VariableStore::parse(&bytes).is_err());
+
+        assert!(VariableStore::parse(b"not a variable store").is_err());
+
+        let mut bytes = sample_store().to_bytes().unwrap();
+        // Truncate the first variable's data size past the end of the store.
+        let off = FV_HEADER_LEN + VARSTORE_HEADER_LEN + 40;
+        bytes[off..off + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(VariableStore::parse(&bytes).is_err());
+    }
+}
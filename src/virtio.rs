@@ -1,40 +1,49 @@
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::cmdline::{args_parse, val_parse};
+use crate::{
+    backend::KrunBackend,
+    cmdline::{args_parse, size_parse, val_parse},
+    console::{self, Console},
+    decompress,
+    image::{self, Image},
+    lock::{FileLock, LockMode},
+    logfile::{self, LogOptions},
+};
 
 use std::{
-    ffi::{c_char, CString},
-    os::unix::ffi::OsStrExt,
-    path::{Path, PathBuf},
+    ffi::CString,
+    fs::{self, OpenOptions},
+    io,
+    os::unix::{ffi::OsStrExt, fs::FileTypeExt, net::UnixStream},
+    path::{Component, Path, PathBuf},
     str::FromStr,
 };
 
 use anyhow::{anyhow, Context, Result};
 use mac_address::MacAddress;
-
-#[link(name = "krun-efi")]
-extern "C" {
-    fn krun_set_root_disk(ctx_id: u32, c_disk_path: *const c_char) -> i32;
-    fn krun_add_vsock_port(ctx_id: u32, port: u32, c_filepath: *const c_char) -> i32;
-    fn krun_add_virtiofs(ctx_id: u32, c_tag: *const c_char, c_path: *const c_char) -> i32;
-    fn krun_set_gvproxy_path(ctx_id: u32, c_path: *const c_char) -> i32;
-    fn krun_set_net_mac(ctx_id: u32, c_mac: *const u8) -> i32;
-}
+use serde::{de, Deserialize, Deserializer, Serialize};
 
 /// Each virito device configures itself with krun differently. This is used by each virtio device
 /// to set their respective configurations with libkrun.
 pub trait KrunContextSet {
-    unsafe fn krun_ctx_set(&self, id: u32) -> Result<(), anyhow::Error>;
+    fn krun_ctx_set(&self, backend: &dyn KrunBackend, id: u32) -> Result<(), anyhow::Error>;
 }
 
 /// virtio device configurations.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "kind")]
 pub enum VirtioDeviceConfig {
+    #[serde(rename = "virtio-blk")]
     Blk(BlkConfig),
-    Rng,
+    #[serde(rename = "virtio-rng")]
+    Rng(RngConfig),
+    #[serde(rename = "virtio-serial")]
     Serial(SerialConfig),
+    #[serde(rename = "virtio-vsock")]
     Vsock(VsockConfig),
+    #[serde(rename = "virtio-net")]
     Net(NetConfig),
+    #[serde(rename = "virtio-fs")]
     Fs(FsConfig),
 }
 
@@ -55,7 +64,7 @@ impl FromStr for VirtioDeviceConfig {
 
         match &args[0][..] {
             "virtio-blk" => Ok(Self::Blk(BlkConfig::from_str(&rest)?)),
-            "virtio-rng" => Ok(Self::Rng),
+            "virtio-rng" => Ok(Self::Rng(RngConfig::from_str(&rest)?)),
             "virtio-serial" => Ok(Self::Serial(SerialConfig::from_str(&rest)?)),
             "virtio-vsock" => Ok(Self::Vsock(VsockConfig::from_str(&rest)?)),
             "virtio-net" => Ok(Self::Net(NetConfig::from_str(&rest)?)),
@@ -70,82 +79,698 @@ impl FromStr for VirtioDeviceConfig {
 
 /// Configure the device in the krun context based on which underlying device is contained.
 impl KrunContextSet for VirtioDeviceConfig {
-    unsafe fn krun_ctx_set(&self, id: u32) -> Result<(), anyhow::Error> {
+    fn krun_ctx_set(&self, backend: &dyn KrunBackend, id: u32) -> Result<(), anyhow::Error> {
         match self {
-            Self::Blk(blk) => blk.krun_ctx_set(id),
-            Self::Vsock(vsock) => vsock.krun_ctx_set(id),
-            Self::Net(net) => net.krun_ctx_set(id),
-            Self::Fs(fs) => fs.krun_ctx_set(id),
-
-            // virtio-rng and virtio-serial devices are currently not configured in krun.
-            _ => Ok(()),
+            Self::Blk(blk) => blk.krun_ctx_set(backend, id),
+            Self::Vsock(vsock) => vsock.krun_ctx_set(backend, id),
+            Self::Net(net) => net.krun_ctx_set(backend, id),
+            Self::Fs(fs) => fs.krun_ctx_set(backend, id),
+            Self::Serial(serial) => serial.krun_ctx_set(backend, id),
+            Self::Rng(rng) => rng.krun_ctx_set(backend, id),
         }
     }
 }
 
+/// Maximum length of a virtio-blk device ID (VIRTIO_BLK_ID_BYTES).
+const BLK_ID_MAX_LEN: usize = 20;
+
+/// libkrun disk sync modes (KRUN_SYNC_*).
+const KRUN_SYNC_NONE: u32 = 0;
+const KRUN_SYNC_RELAXED: u32 = 1;
+const KRUN_SYNC_FULL: u32 = 2;
+
 /// Configuration of a virtio-blk device.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub struct BlkConfig {
-    /// Path of the file to store as the root disk.
+    /// Path of the disk image file.
     path: PathBuf,
+
+    /// Format of the disk image.
+    #[serde(default)]
+    format: image::Format,
+
+    /// Identifier of the disk, exposed to the guest as the disk's serial number. Assigned by
+    /// prepare_disks if not given.
+    device_id: Option<String>,
+
+    /// Attach the disk read-only.
+    #[serde(default)]
+    readonly: bool,
+
+    /// Use the disk as the root disk.
+    #[serde(default)]
+    root: bool,
+
+    /// Attach a copy-on-write overlay of the image instead of the image itself, discarding the
+    /// VM's writes when krunkit exits.
+    #[serde(default)]
+    overlay: bool,
+
+    /// Host caching of the disk's I/O.
+    #[serde(default)]
+    cache: CacheMode,
+
+    /// Flushing of the guest's writes to the host's storage, libkrun's default if not given.
+    sync: Option<SyncMode>,
 }
 
 impl FromStr for BlkConfig {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let args = args_parse(s.to_string(), "virtio-blk", Some(1))?;
+        let args = args_parse(s.to_string(), "virtio-blk", None)?;
 
-        Ok(Self {
+        if args.is_empty() {
+            return Err(anyhow!("expected at least 1 argument, found 0"));
+        }
+
+        let mut blk = Self {
             path: PathBuf::from_str(&val_parse(args[0].clone(), "path")?)
                 .context("path argument not a valid path")?,
-        })
+            format: image::Format::Auto,
+            device_id: None,
+            readonly: false,
+            root: false,
+            overlay: false,
+            cache: CacheMode::Writeback,
+            sync: None,
+        };
+
+        for arg in &args[1..] {
+            match arg.split_once('=') {
+                Some(("format", format)) => blk.format = image::Format::from_str(format)?,
+                Some(("deviceId", id)) => blk.device_id = Some(id.to_string()),
+                Some(("cache", cache)) => blk.cache = CacheMode::from_str(cache)?,
+                Some(("sync", sync)) => blk.sync = Some(SyncMode::from_str(sync)?),
+                Some(("overlay", overlay)) => {
+                    blk.overlay = bool::from_str(overlay)
+                        .context(format!("invalid overlay argument: {}", overlay))?
+                }
+                None if arg == "readonly" => blk.readonly = true,
+                None if arg == "root" => blk.root = true,
+                _ => return Err(anyhow!("invalid virtio-blk argument: {}", arg)),
+            }
+        }
+
+        Ok(blk)
     }
 }
 
-/// Set the virtio-blk device to be the krun VM's root disk.
+/// Validate the disk image and attach the virtio-blk device to the krun VM.
 impl KrunContextSet for BlkConfig {
-    unsafe fn krun_ctx_set(&self, id: u32) -> Result<(), anyhow::Error> {
+    fn krun_ctx_set(&self, backend: &dyn KrunBackend, id: u32) -> Result<(), anyhow::Error> {
+        let image = Image::open(&self.path, self.format)?;
+
         let path_cstr = path_to_cstring(&self.path)?;
+        let device_id = self
+            .device_id
+            .as_deref()
+            .ok_or_else(|| anyhow!("virtio-blk device {} has no ID", self.path.display()))?;
+        let id_cstr = CString::new(device_id).context("deviceId contains a NULL byte")?;
+
+        if backend.add_disk3(
+            id,
+            &id_cstr,
+            &path_cstr,
+            image.format.krun_format(),
+            self.readonly,
+            self.cache == CacheMode::None,
+            self.krun_sync_mode()?,
+        ) < 0
+        {
+            return Err(anyhow!(
+                "unable to add virtio-blk disk {} ({})",
+                self.path.display(),
+                device_id
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl BlkConfig {
+    /// Value of the sync mode in the libkrun API (KRUN_SYNC_*). The unsafe cache mode ignores
+    /// flushes, so it cannot be combined with full syncing.
+    fn krun_sync_mode(&self) -> Result<u32> {
+        match (self.cache, self.sync) {
+            (CacheMode::Unsafe, Some(SyncMode::Full)) => Err(anyhow!(
+                "virtio-blk disk {} cannot use both cache=unsafe and sync=full",
+                self.path.display()
+            )),
+            (CacheMode::Unsafe, _) | (_, Some(SyncMode::None)) => Ok(KRUN_SYNC_NONE),
+            (_, Some(SyncMode::Full)) => Ok(KRUN_SYNC_FULL),
+            (_, None) => Ok(KRUN_SYNC_RELAXED),
+        }
+    }
+}
+
+/// Host caching of a disk's I/O.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheMode {
+    /// Bypass the host's page cache (direct I/O).
+    None,
+
+    /// Go through the host's page cache.
+    #[default]
+    Writeback,
+
+    /// Go through the host's page cache and ignore the guest's flushes.
+    Unsafe,
+}
+
+impl FromStr for CacheMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "writeback" => Ok(Self::Writeback),
+            "unsafe" => Ok(Self::Unsafe),
+            _ => Err(anyhow!(
+                "invalid cache mode {} (expected none, writeback or unsafe)",
+                s
+            )),
+        }
+    }
+}
+
+/// Flushing of a disk's writes to the host's storage on the guest's flush requests.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncMode {
+    /// Flush the writes all the way to permanent storage, through the drive's cache.
+    Full,
+
+    /// Ignore the guest's flush requests.
+    None,
+}
+
+impl FromStr for SyncMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(Self::Full),
+            "none" => Ok(Self::None),
+            _ => Err(anyhow!("invalid sync mode {} (expected full or none)", s)),
+        }
+    }
+}
+
+/// Select the root disk and assign the IDs of the disks not given one.
+///
+/// The root disk is the one marked as root, or the first disk otherwise. It is moved ahead of the
+/// other devices so that it is the guest's first disk, and is given the "root" ID by default.
+/// Data disks default to "data1", "data2", etc.
+pub fn prepare_disks(devices: &mut [VirtioDeviceConfig]) -> Result<()> {
+    let disks: Vec<usize> = devices
+        .iter()
+        .enumerate()
+        .filter(|(_, d)| matches!(d, VirtioDeviceConfig::Blk(_)))
+        .map(|(i, _)| i)
+        .collect();
+
+    let marked: Vec<usize> = disks
+        .iter()
+        .copied()
+        .filter(|i| matches!(&devices[*i], VirtioDeviceConfig::Blk(b) if b.root))
+        .collect();
+
+    let root = match marked[..] {
+        [] => match disks.first() {
+            Some(i) => *i,
+            None => return Ok(()),
+        },
+        [i] => i,
+        _ => {
+            let paths: Vec<String> = marked
+                .iter()
+                .filter_map(|i| match &devices[*i] {
+                    VirtioDeviceConfig::Blk(b) => Some(b.path.display().to_string()),
+                    _ => None,
+                })
+                .collect();
+
+            return Err(anyhow!(
+                "only one virtio-blk disk can be the root disk, found {}",
+                paths.join(", ")
+            ));
+        }
+    };
+
+    devices[..=root].rotate_right(1);
+
+    // Generated IDs must not clash with the ones given explicitly to later disks.
+    let explicit: Vec<String> = devices
+        .iter()
+        .filter_map(|d| match d {
+            VirtioDeviceConfig::Blk(b) => b.device_id.clone(),
+            _ => None,
+        })
+        .collect();
+
+    let mut ids: Vec<String> = Vec::new();
+    let mut data = 0;
+    for device in devices.iter_mut() {
+        let VirtioDeviceConfig::Blk(blk) = device else {
+            continue;
+        };
+
+        let is_root = ids.is_empty();
+        blk.root = is_root;
+
+        let id = match &blk.device_id {
+            Some(id) => id.clone(),
+            None if is_root => "root".to_string(),
+            None => loop {
+                data += 1;
+                let id = format!("data{}", data);
+                if !explicit.contains(&id) {
+                    break id;
+                }
+            },
+        };
+
+        if id.is_empty() || id.len() > BLK_ID_MAX_LEN {
+            return Err(anyhow!(
+                "virtio-blk deviceId {:?} must be 1 to {} bytes long",
+                id,
+                BLK_ID_MAX_LEN
+            ));
+        }
+
+        if ids.contains(&id) {
+            return Err(anyhow!("duplicate virtio-blk deviceId {}", id));
+        }
+
+        ids.push(id.clone());
+        blk.device_id = Some(id);
+    }
+
+    Ok(())
+}
+
+/// Replace the compressed disk images with their decompressed copies in the image cache. The
+/// copies are shared, so writable disks are attached through an overlay, discarding the VM's
+/// writes when krunkit exits. Must be called before prepare_overlays.
+pub fn prepare_compressed(devices: &mut [VirtioDeviceConfig]) -> Result<()> {
+    for device in devices.iter_mut() {
+        let VirtioDeviceConfig::Blk(blk) = device else {
+            continue;
+        };
+
+        if let Some(cached) = decompress::cached_image(&blk.path)? {
+            blk.path = cached;
+            blk.overlay |= !blk.readonly;
+        }
+    }
+
+    Ok(())
+}
+
+/// Replace the disks to be overlaid (all writable disks of an ephemeral VM, or those given the
+/// overlay option) with copy-on-write overlays of their images. Must be called after
+/// prepare_disks, as overlays are named after the disk IDs.
+pub fn prepare_overlays(devices: &mut [VirtioDeviceConfig], ephemeral: bool) -> Result<()> {
+    for device in devices.iter_mut() {
+        let VirtioDeviceConfig::Blk(blk) = device else {
+            continue;
+        };
+
+        if blk.overlay && blk.readonly {
+            return Err(anyhow!(
+                "virtio-blk disk {} cannot be both readonly and overlaid",
+                blk.path.display()
+            ));
+        }
+
+        let overlaid = blk.overlay || (ephemeral && !blk.readonly);
+        if !overlaid {
+            continue;
+        }
+
+        let base = Image::open(&blk.path, blk.format)?;
+        let name = blk.device_id.as_deref().unwrap_or("disk");
+
+        blk.path = image::create_overlay(&base, name)?;
+        blk.format = image::Format::Qcow2;
+        blk.overlay = true;
+    }
+
+    Ok(())
+}
+
+/// Lock the images of the disks, so that other processes cannot write to them while the VM
+/// runs: writable images are locked exclusively, and read-only images and backing files are
+/// shared with other readers.
+pub fn lock_disks(devices: &[VirtioDeviceConfig]) -> Result<Vec<FileLock>> {
+    let mut locks = Vec::new();
+    for device in devices {
+        let VirtioDeviceConfig::Blk(blk) = device else {
+            continue;
+        };
+
+        let mut mode = match blk.readonly {
+            true => LockMode::Shared,
+            false => LockMode::Exclusive,
+        };
+
+        let mut image = Some(Image::open(&blk.path, blk.format)?);
+        while let Some(img) = image {
+            locks.push(FileLock::new(&img.path, mode, "disk image")?);
+
+            image = img.backing.map(|b| *b);
+            mode = LockMode::Shared;
+        }
+    }
+
+    Ok(locks)
+}
+
+/// The image of the root disk, if any. Must be called after prepare_disks, which moves the root
+/// disk ahead of the other disks.
+pub fn root_disk(devices: &[VirtioDeviceConfig]) -> Result<Option<Image>> {
+    devices
+        .iter()
+        .find_map(|d| match d {
+            VirtioDeviceConfig::Blk(blk) => Some(Image::open(&blk.path, blk.format)),
+            _ => None,
+        })
+        .transpose()
+}
+
+/// libkrun feeds virtio-rng from the host's random number generator, the source cannot be
+/// chosen.
+const RNG_SOURCE_ERROR: &str =
+    "virtio-rng source is not configurable, libkrun provides the entropy source";
+
+/// Configuration of a virtio-rng device.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RngConfig {
+    /// Rejected with an explanation rather than as an unknown field.
+    #[serde(default, skip_serializing, deserialize_with = "reject_rng_source")]
+    source: (),
+}
+
+fn reject_rng_source<'de, D: Deserializer<'de>>(_: D) -> Result<(), D::Error> {
+    Err(de::Error::custom(RNG_SOURCE_ERROR))
+}
+
+impl FromStr for RngConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Ok(Self::default());
+        }
 
-        if krun_set_root_disk(id, path_cstr.as_ptr()) < 0 {
-            return Err(anyhow!("unable to set virtio-blk root disk"));
+        let args = args_parse(s.to_string(), "virtio-rng", None)?;
+        match args.first() {
+            None => Ok(Self::default()),
+            Some(arg) if arg.starts_with("source=") => Err(anyhow!(RNG_SOURCE_ERROR)),
+            Some(arg) => Err(anyhow!("invalid virtio-rng argument: {}", arg)),
         }
+    }
+}
 
+/// libkrun always adds a virtio-rng device fed from the host's random number generator, and
+/// has no API to configure it, so there is nothing to set.
+impl KrunContextSet for RngConfig {
+    fn krun_ctx_set(&self, _backend: &dyn KrunBackend, _id: u32) -> Result<(), anyhow::Error> {
         Ok(())
     }
 }
 
-/// Configuration of a virtio-serial device.
-#[derive(Clone, Debug)]
+/// Ensure that there is at most one virtio-serial device, as libkrun has a single console, and
+/// that its arguments are consistent.
+pub fn check_serial(devices: &[VirtioDeviceConfig]) -> Result<()> {
+    let serials: Vec<&SerialConfig> = devices
+        .iter()
+        .filter_map(|d| match d {
+            VirtioDeviceConfig::Serial(serial) => Some(serial),
+            _ => None,
+        })
+        .collect();
+
+    if serials.len() > 1 {
+        return Err(anyhow!(
+            "only one virtio-serial device is supported, found {}",
+            serials.len()
+        ));
+    }
+
+    for serial in serials {
+        if let SerialMode::LogFile(_) = serial.mode()? {
+            serial.log_options()?;
+        }
+        if let Some(escape) = &serial.escape {
+            console::parse_escape(escape)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Attach the console of the virtio-serial device to stdio or a pseudo-terminal if requested.
+/// The path of the pseudo-terminal is printed and recorded in the device configuration. Log
+/// files that are rotated or timestamped are written by krunkit rather than libkrun. Must be
+/// called after check_serial.
+pub fn prepare_serial(devices: &mut [VirtioDeviceConfig]) -> Result<Option<Console>> {
+    let Some(serial) = devices.iter_mut().find_map(|d| match d {
+        VirtioDeviceConfig::Serial(serial) => Some(serial),
+        _ => None,
+    }) else {
+        return Ok(None);
+    };
+
+    match serial.mode()? {
+        SerialMode::LogFile(path) => match serial.log_options()? {
+            Some(options) => Ok(Some(Console::log_file(path, options).context(format!(
+                "unable to create virtio-serial log file {}",
+                path.display()
+            ))?)),
+            None => Ok(None),
+        },
+        SerialMode::Stdio => {
+            let escape = match &serial.escape {
+                Some(escape) => console::parse_escape(escape)?,
+                None => Some(console::DEFAULT_ESCAPE),
+            };
+
+            Ok(Some(Console::stdio(escape)?))
+        }
+        SerialMode::Pty => {
+            let (console, path) = Console::pty()?;
+            eprintln!("virtio-serial console: {}", path.display());
+            serial.pty_path = Some(path);
+
+            Ok(Some(console))
+        }
+    }
+}
+
+/// Configuration of a virtio-serial device. The console is either written to a log file, or
+/// attached to krunkit's stdio or a pseudo-terminal.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub struct SerialConfig {
     /// Path of a file to use as the device's log.
-    log_file_path: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    log_file_path: Option<PathBuf>,
+
+    /// Attach the console to krunkit's stdin and stdout.
+    #[serde(default)]
+    stdio: bool,
+
+    /// Attach the console to a pseudo-terminal.
+    #[serde(default)]
+    pty: bool,
+
+    /// Escape character of the stdio console (e.g. ^]), or "none" to disable escape sequences.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    escape: Option<String>,
+
+    /// Size from which the log file is rotated (e.g. 10M).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_size: Option<String>,
+
+    /// Number of rotated log files kept.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_files: Option<u32>,
+
+    /// Prefix each line of the log file with the host time.
+    #[serde(default)]
+    timestamps: bool,
+
+    /// Path of the pseudo-terminal, once allocated.
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pty_path: Option<PathBuf>,
+}
+
+/// Destination of the virtio-serial console.
+enum SerialMode<'a> {
+    LogFile(&'a Path),
+    Stdio,
+    Pty,
+}
+
+impl SerialConfig {
+    fn mode(&self) -> Result<SerialMode<'_>> {
+        if self.escape.is_some() && !self.stdio {
+            return Err(anyhow!(
+                "the virtio-serial escape argument only applies to stdio consoles"
+            ));
+        }
+
+        let log_options = self.max_size.is_some() || self.max_files.is_some() || self.timestamps;
+        if log_options && self.log_file_path.is_none() {
+            return Err(anyhow!(
+                "the virtio-serial maxSize, maxFiles and timestamps arguments only apply to log files"
+            ));
+        }
+
+        match (&self.log_file_path, self.stdio, self.pty) {
+            (Some(path), false, false) => Ok(SerialMode::LogFile(path)),
+            (None, true, false) => Ok(SerialMode::Stdio),
+            (None, false, true) => Ok(SerialMode::Pty),
+            _ => Err(anyhow!(
+                "expected virtio-serial to have exactly one of logFilePath, stdio or pty"
+            )),
+        }
+    }
+
+    /// Rotation and timestamping of the log file, None if libkrun can write it as is.
+    fn log_options(&self) -> Result<Option<LogOptions>> {
+        if self.max_files.is_some() && self.max_size.is_none() {
+            return Err(anyhow!(
+                "the virtio-serial maxFiles argument requires maxSize"
+            ));
+        }
+
+        let max_size = match &self.max_size {
+            Some(size) => match size_parse(size)? {
+                0 => return Err(anyhow!("virtio-serial maxSize must not be zero")),
+                size => Some(size),
+            },
+            None => None,
+        };
+
+        if max_size.is_none() && !self.timestamps {
+            return Ok(None);
+        }
+
+        Ok(Some(LogOptions {
+            max_size,
+            max_files: self.max_files.unwrap_or(logfile::DEFAULT_MAX_FILES),
+            timestamps: self.timestamps,
+        }))
+    }
 }
 
 impl FromStr for SerialConfig {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let args = args_parse(s.to_string(), "virtio-serial", Some(1))?;
+        let args = args_parse(s.to_string(), "virtio-serial", None)?;
 
-        Ok(Self {
-            log_file_path: PathBuf::from_str(&val_parse(args[0].clone(), "logFilePath")?)
-                .context("logFilePath argument not a valid path")?,
-        })
+        let mut serial = Self {
+            log_file_path: None,
+            stdio: false,
+            pty: false,
+            escape: None,
+            max_size: None,
+            max_files: None,
+            timestamps: false,
+            pty_path: None,
+        };
+
+        for arg in &args[1..] {
+            match arg.split_once('=') {
+                Some(("escape", escape)) => serial.escape = Some(escape.to_string()),
+                Some(("maxSize", size)) => {
+                    size_parse(size)?;
+                    serial.max_size = Some(size.to_string());
+                }
+                Some(("maxFiles", files)) => {
+                    serial.max_files = Some(
+                        u32::from_str(files)
+                            .context(format!("invalid virtio-serial maxFiles: {}", files))?,
+                    )
+                }
+                Some(("timestamps", timestamps)) => {
+                    serial.timestamps = bool::from_str(timestamps)
+                        .context(format!("invalid virtio-serial timestamps: {}", timestamps))?
+                }
+                _ => return Err(anyhow!("invalid virtio-serial argument: {}", arg)),
+            }
+        }
+
+        match args[0].as_str() {
+            "stdio" => serial.stdio = true,
+            "pty" => serial.pty = true,
+            arg => {
+                serial.log_file_path = Some(
+                    PathBuf::from_str(&val_parse(arg.to_string(), "logFilePath")?)
+                        .context("logFilePath argument not a valid path")?,
+                )
+            }
+        }
+
+        Ok(serial)
+    }
+}
+
+/// Send the output of the guest's console to the log file. Consoles attached to stdio or a
+/// pseudo-terminal, and log files written by krunkit, are configured by prepare_serial's
+/// Console instead.
+impl KrunContextSet for SerialConfig {
+    fn krun_ctx_set(&self, backend: &dyn KrunBackend, id: u32) -> Result<(), anyhow::Error> {
+        let SerialMode::LogFile(log_file_path) = self.mode()? else {
+            return Ok(());
+        };
+        if self.log_options()?.is_some() {
+            return Ok(());
+        }
+
+        // libkrun only opens the file when the VM starts, so ensure it can be written to now.
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_file_path)
+            .context(format!(
+                "unable to create virtio-serial log file {}",
+                log_file_path.display()
+            ))?;
+
+        let path_cstr = path_to_cstring(log_file_path)?;
+        if backend.set_console_output(id, &path_cstr) < 0 {
+            return Err(anyhow!(
+                "unable to set virtio-serial log file {}",
+                log_file_path.display()
+            ));
+        }
+
+        Ok(())
     }
 }
 
 /// Configuration of a virtio-vsock device.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub struct VsockConfig {
     /// Port to connect to on VM.
     port: u32,
 
     /// Path of underlying socket.
+    #[serde(rename = "socketURL")]
     socket_url: PathBuf,
 
     /// Action of socket.
+    #[serde(default)]
     action: VsockAction,
 }
 
@@ -153,13 +778,22 @@ impl FromStr for VsockConfig {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let args = args_parse(s.to_string(), "virtio-vsock", Some(3))?;
+        let args = args_parse(s.to_string(), "virtio-vsock", None)?;
+        if !(2..=3).contains(&args.len()) {
+            return Err(anyhow!(
+                "expected --virtio-vsock argument to have 2 or 3 comma-separated sub-arguments, found {}",
+                args.len()
+            ));
+        }
 
         let port =
             u32::from_str(&val_parse(args[0].clone(), "port")?).context("port argument invalid")?;
         let socket_url = PathBuf::from_str(&val_parse(args[1].clone(), "socketURL")?)
             .context("socketURL argument not a valid path")?;
-        let action = VsockAction::from_str(&args[2])?;
+        let action = match args.get(2) {
+            Some(action) => VsockAction::from_str(action)?,
+            None => VsockAction::default(),
+        };
 
         Ok(Self {
             port,
@@ -169,13 +803,85 @@ impl FromStr for VsockConfig {
     }
 }
 
+impl VsockConfig {
+    /// Ensure that the socket can be used: a listen socket must be served by a host process,
+    /// and a connect socket must not be. Stale connect sockets are accepted, they are removed
+    /// when the device is set up.
+    fn check_socket(&self) -> Result<()> {
+        let path = &self.socket_url;
+        let metadata = fs::symlink_metadata(path);
+
+        match self.action {
+            VsockAction::Listen => {
+                let metadata =
+                    metadata.context(format!("vsock socket {} not found", path.display()))?;
+                if !metadata.file_type().is_socket() {
+                    return Err(anyhow!("vsock path {} is not a socket", path.display()));
+                }
+            }
+            VsockAction::Connect => {
+                let Ok(metadata) = metadata else {
+                    return Ok(());
+                };
+                if !metadata.file_type().is_socket() {
+                    return Err(anyhow!(
+                        "vsock path {} exists and is not a socket",
+                        path.display()
+                    ));
+                }
+
+                match UnixStream::connect(path) {
+                    Ok(_) => {
+                        return Err(anyhow!(
+                            "vsock socket {} is in use by another process",
+                            path.display()
+                        ))
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => (),
+                    Err(err) => {
+                        return Err(anyhow!(err)
+                            .context(format!("unable to check vsock socket {}", path.display())))
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remove a stale connect socket, left by a VM that is no longer running, so that libkrun
+    /// can bind the path again.
+    fn remove_stale_socket(&self) -> Result<()> {
+        let path = &self.socket_url;
+        if !matches!(self.action, VsockAction::Connect)
+            || !fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket())
+        {
+            return Ok(());
+        }
+
+        match UnixStream::connect(path) {
+            Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path)
+                .context(format!(
+                    "unable to remove stale vsock socket {}",
+                    path.display()
+                )),
+            _ => Ok(()),
+        }
+    }
+}
+
 /// Map the virtio-vsock's guest port and host path to enable the krun VM to communicate with the
-/// socket on the host.
+/// socket on the host. With listen, libkrun connects each guest connection on the port to the
+/// socket served by a host process. With connect, libkrun creates the socket and connects each
+/// host connection to the guest port.
 impl KrunContextSet for VsockConfig {
-    unsafe fn krun_ctx_set(&self, id: u32) -> Result<(), anyhow::Error> {
+    fn krun_ctx_set(&self, backend: &dyn KrunBackend, id: u32) -> Result<(), anyhow::Error> {
+        self.remove_stale_socket()?;
         let path_cstr = path_to_cstring(&self.socket_url)?;
 
-        if krun_add_vsock_port(id, self.port, path_cstr.as_ptr()) < 0 {
+        // libkrun's listen flag tells whether libkrun itself listens on the socket.
+        let listen = matches!(self.action, VsockAction::Connect);
+        if backend.add_vsock_port2(id, self.port, &path_cstr, listen) < 0 {
             return Err(anyhow!(format!(
                 "unable to add vsock port {} for path {}",
                 self.port,
@@ -187,10 +893,95 @@ impl KrunContextSet for VsockConfig {
     }
 }
 
-/// virtio-vsock action.
-#[derive(Clone, Debug)]
+/// Highest vsock port reserved for the hypervisor and well-known services (VMADDR_PORT 0 to 2).
+const VSOCK_RESERVED_PORT_MAX: u32 = 2;
+
+/// Wildcard vsock port, only valid when binding (VMADDR_PORT_ANY).
+const VMADDR_PORT_ANY: u32 = u32::MAX;
+
+/// Ensure that the virtio-vsock devices map distinct, usable guest ports to distinct host
+/// sockets, as libkrun keeps a single mapping per port and per socket.
+pub fn check_vsock(devices: &[VirtioDeviceConfig]) -> Result<()> {
+    let vsocks: Vec<&VsockConfig> = devices
+        .iter()
+        .filter_map(|d| match d {
+            VirtioDeviceConfig::Vsock(vsock) => Some(vsock),
+            _ => None,
+        })
+        .collect();
+
+    for (i, vsock) in vsocks.iter().enumerate() {
+        if vsock.port <= VSOCK_RESERVED_PORT_MAX || vsock.port == VMADDR_PORT_ANY {
+            return Err(anyhow!("vsock port {} is reserved", vsock.port));
+        }
+
+        for other in &vsocks[..i] {
+            if other.port == vsock.port {
+                return Err(anyhow!(
+                    "vsock port {} is used by more than one virtio-vsock device",
+                    vsock.port
+                ));
+            }
+
+            if normalize_path(&other.socket_url) == normalize_path(&vsock.socket_url) {
+                return Err(anyhow!(
+                    "vsock socket {} is used by more than one virtio-vsock device",
+                    vsock.socket_url.display()
+                ));
+            }
+        }
+    }
+
+    for vsock in vsocks {
+        vsock.check_socket()?;
+    }
+
+    Ok(())
+}
+
+/// Absolute form of a path, with its "." and ".." components resolved lexically, so that the
+/// same path given in different ways compares equal.
+fn normalize_path(path: &Path) -> PathBuf {
+    let absolute = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+
+    let mut normalized = PathBuf::new();
+    for component in absolute.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+
+    normalized
+}
+
+/// Path of the socket libkrun creates for a guest vsock port, None if no virtio-vsock device
+/// has the connect action on the port.
+pub fn vsock_connect_socket(devices: &[VirtioDeviceConfig], port: u32) -> Option<&Path> {
+    devices.iter().find_map(|d| match d {
+        VirtioDeviceConfig::Vsock(vsock)
+            if vsock.port == port && matches!(vsock.action, VsockAction::Connect) =>
+        {
+            Some(vsock.socket_url.as_path())
+        }
+        _ => None,
+    })
+}
+
+/// virtio-vsock action, how host processes use the socket.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum VsockAction {
+    /// A host process listens on the socket, and guest connections to the port are connected to
+    /// it.
+    #[default]
     Listen,
+
+    /// libkrun creates the socket, and host processes connect to it to reach the guest port.
+    Connect,
 }
 
 impl FromStr for VsockAction {
@@ -201,18 +992,21 @@ impl FromStr for VsockAction {
 
         match &s[..] {
             "listen" => Ok(Self::Listen),
+            "connect" => Ok(Self::Connect),
             _ => Err(anyhow!("invalid vsock action")),
         }
     }
 }
 
 /// Configuration of a virtio-net device.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub struct NetConfig {
     /// Path to underlying gvproxy socket.
     unix_socket_path: PathBuf,
 
     /// Network MAC address.
+    #[serde(rename = "mac")]
     mac_address: MacAddress,
 }
 
@@ -233,18 +1027,18 @@ impl FromStr for NetConfig {
 
 /// Set the gvproxy's path and network MAC address.
 impl KrunContextSet for NetConfig {
-    unsafe fn krun_ctx_set(&self, id: u32) -> Result<(), anyhow::Error> {
+    fn krun_ctx_set(&self, backend: &dyn KrunBackend, id: u32) -> Result<(), anyhow::Error> {
         let path_cstr = path_to_cstring(&self.unix_socket_path)?;
         let mac = self.mac_address.bytes();
 
-        if krun_set_gvproxy_path(id, path_cstr.as_ptr()) < 0 {
+        if backend.set_gvproxy_path(id, &path_cstr) < 0 {
             return Err(anyhow!(format!(
                 "unable to set gvproxy path {}",
                 &self.unix_socket_path.display()
             )));
         }
 
-        if krun_set_net_mac(id, mac.as_ptr()) < 0 {
+        if backend.set_net_mac(id, &mac) < 0 {
             return Err(anyhow!(format!(
                 "unable to set net MAC address {}",
                 self.mac_address
@@ -256,7 +1050,8 @@ impl KrunContextSet for NetConfig {
 }
 
 /// Configuration of a virtio-fs device.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub struct FsConfig {
     /// Shared directory with the host.
     shared_dir: PathBuf,
@@ -292,11 +1087,11 @@ impl FromStr for FsConfig {
 
 /// Set the shared directory with its guest mount tag.
 impl KrunContextSet for FsConfig {
-    unsafe fn krun_ctx_set(&self, id: u32) -> Result<(), anyhow::Error> {
+    fn krun_ctx_set(&self, backend: &dyn KrunBackend, id: u32) -> Result<(), anyhow::Error> {
         let shared_dir_cstr = path_to_cstring(&self.shared_dir)?;
         let mount_tag_cstr = path_to_cstring(&self.mount_tag)?;
 
-        if krun_add_virtiofs(id, mount_tag_cstr.as_ptr(), shared_dir_cstr.as_ptr()) < 0 {
+        if backend.add_virtiofs(id, &mount_tag_cstr, &shared_dir_cstr) < 0 {
             return Err(anyhow!(format!(
                 "unable to add virtiofs shared directory {} with mount tag {}",
                 &self.shared_dir.display(),
@@ -309,7 +1104,7 @@ impl KrunContextSet for FsConfig {
 }
 
 /// Construct a NULL-terminated C string from a Rust Path object.
-fn path_to_cstring(path: &Path) -> Result<CString, anyhow::Error> {
+pub fn path_to_cstring(path: &Path) -> Result<CString, anyhow::Error> {
     let cstring = CString::new(path.as_os_str().as_bytes()).context(format!(
         "unable to convert path {} into NULL-terminated C string",
         path.display()